@@ -1,6 +1,7 @@
 #![no_std]
+pub mod mcp23016;
 
-use core::ops::Range;
+use core::{fmt, ops::Range};
 
 use strum::{EnumCount, FromRepr, VariantArray};
 /// There are 8 GPIO pins for set A and set B
@@ -49,8 +50,17 @@ impl defmt::Format for AB {
     }
 }
 
+/// Formats a pin index like `B3`
 pub struct FormatPinIndex(pub usize);
 
+impl fmt::Display for FormatPinIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let letter = AB::from_index(self.0);
+        let index_within_letter = self.0 % N_GPIO_PINS_PER_SET;
+        write!(f, "{:?}{}", letter, index_within_letter)
+    }
+}
+
 #[cfg(feature = "defmt")]
 impl defmt::Format for FormatPinIndex {
     fn format(&self, fmt: defmt::Formatter) {
@@ -77,6 +87,10 @@ pub enum RegisterType {
     OLAT,
 }
 
+/// With `IOCON.BANK = 1`, the registers of port B start at this address, and the addresses
+/// between the ports aren't registers
+const BANK_B_ADDRESS: u8 = 0x10;
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Register {
@@ -89,16 +103,16 @@ impl Register {
     pub fn from_address(address: u8, bank_mode: bool) -> Option<Self> {
         Some({
             if bank_mode {
-                if address < RegisterType::COUNT as u8 {
-                    Self {
-                        ab: AB::A,
-                        _type: RegisterType::from_repr(address)?,
-                    }
+                let ab = if address < BANK_B_ADDRESS {
+                    AB::A
                 } else {
-                    Self {
-                        ab: AB::B,
-                        _type: RegisterType::from_repr(address - RegisterType::COUNT as u8)?,
-                    }
+                    AB::B
+                };
+                Self {
+                    ab,
+                    _type: RegisterType::from_repr(
+                        address - ab.set_index() as u8 * BANK_B_ADDRESS,
+                    )?,
                 }
             } else {
                 Self {
@@ -115,7 +129,7 @@ impl Register {
 
     pub fn address(&self, bank_mode: bool) -> u8 {
         if bank_mode {
-            self.ab.starting_index() as u8 + self._type as u8
+            self.ab.set_index() as u8 * BANK_B_ADDRESS + self._type as u8
         } else {
             (self._type as u8) * 2 + self.ab.set_index() as u8
         }
@@ -0,0 +1,72 @@
+//! The register map of the older MCP23016.
+//!
+//! Its registers come in pairs, one for each port, with port `0` at the even address. Unlike the
+//! MCP23017, it has no pull-ups and no interrupt configuration: the interrupt output activates when
+//! any input changes, and it is cleared by reading `GP` or `INTCAP`. There is no `INTF`, so the
+//! pins that changed have to be found by comparing `INTCAP` with the previous state.
+//! It also needs an external RC clock, and it can't be reset with a pin.
+//!
+//! The controller drives it with `Mcp23017::new_mcp23016`.
+
+use strum::{EnumCount, FromRepr};
+
+use crate::AB;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumCount, FromRepr)]
+#[repr(u8)]
+pub enum Mcp23016RegisterType {
+    GP,
+    OLAT,
+    IPOL,
+    IODIR,
+    /// Read only
+    INTCAP,
+    /// Only bit `0` (`IARES`) is used
+    IOCON,
+}
+
+/// With this bit set in `IOCON0`, the interrupt activity resolution (how long the chip takes to
+/// notice a change on an input) is 200 µs instead of 32 ms, at the cost of more current
+pub const IOCON_IARES: u8 = 1 << 0;
+
+/// How long the chip takes to notice a change on an input with [`IOCON_IARES`] set
+pub const FAST_INTERRUPT_ACTIVITY_RESOLUTION_US: u32 = 200;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mcp23016Register {
+    pub _type: Mcp23016RegisterType,
+    /// [`AB::A`] is port `0` and [`AB::B`] is port `1`
+    pub ab: AB,
+}
+
+impl Mcp23016Register {
+    /// If the address is invalid, returns `None`
+    pub fn from_address(address: u8) -> Option<Self> {
+        Some(Self {
+            _type: Mcp23016RegisterType::from_repr(address / 2)?,
+            ab: if address.is_multiple_of(2) {
+                AB::A
+            } else {
+                AB::B
+            },
+        })
+    }
+
+    pub fn address(&self) -> u8 {
+        (self._type as u8) * 2 + self.ab.set_index() as u8
+    }
+
+    /// Accessing one register of a pair and then continuing to read or write goes to the other
+    /// register of the pair, not the next address
+    pub fn pair(&self) -> Self {
+        Self {
+            _type: self._type,
+            ab: match self.ab {
+                AB::A => AB::B,
+                AB::B => AB::A,
+            },
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use crate::*;
+
+/// What the runner does between its I2C transactions. See [`Control::set_bus_sharing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BusSharing {
+    /// Transactions are back to back
+    #[default]
+    None,
+    /// Yields to the executor before every transaction after the first one, so that tasks which
+    /// are waiting for a shared bus get a chance to use it
+    Yield,
+    /// Waits before every transaction after the first one
+    #[cfg(feature = "embassy-time")]
+    Delay(embassy_time::Duration),
+}
+
+impl Control<'_> {
+    /// When the chip shares its I2C bus with time-sensitive devices, this keeps the runner from
+    /// hogging the bus, for example during interrupt storms.
+    ///
+    /// Every transaction is a separate bus arbitration point: the runner doesn't hold the bus
+    /// between transactions, and a shared bus (like `embassy-embedded-hal`'s `I2cDevice`) can
+    /// give it to another device in between. Updating a register (for example writing `OLAT`, or
+    /// reading `INTF` and `INTCAP`) is one transaction, and a runner pass is usually a few of them.
+    /// With [`BusSharing::None`], other tasks only get a chance to use the bus if the bus
+    /// implementation yields.
+    pub fn set_bus_sharing(&self, bus_sharing: BusSharing) {
+        self.immutable.control.bus_sharing.set(bus_sharing);
+    }
+}
+
+/// Called before every transaction. `first` is whether it's the first transaction since the runner
+/// started.
+pub(crate) async fn wait_for_turn(immutable: &Mcp23017Immutable, first: bool) {
+    if first {
+        return;
+    }
+    match immutable.control.bus_sharing.get() {
+        BusSharing::None => {}
+        BusSharing::Yield => embassy_futures::yield_now().await,
+        #[cfg(feature = "embassy-time")]
+        BusSharing::Delay(delay) => embassy_time::Timer::after(delay).await,
+    }
+}
@@ -0,0 +1,42 @@
+/// Configures pins from [`InitialPins`](crate::InitialPins) and puts them in a struct with named
+/// fields, so that board setup code doesn't mix up pins. Must be used in an `async` context.
+///
+/// The modes are `Output(Low)`, `Output(High)`, `Input(pull_up)`, `Input(floating)`,
+/// `Watch(pull_up)`, and `Watch(floating)`. Claiming the same pin twice doesn't compile.
+///
+/// ```ignore
+/// let board = mcp23017_controller::claim_pins!(pins => {
+///     led: A0 as Output(Low),
+///     button: B3 as Watch(pull_up),
+/// });
+/// board.led.set_high().await.unwrap();
+/// ```
+#[macro_export]
+macro_rules! claim_pins {
+    (@type $a:lifetime Output) => { $crate::Pin<$a, $crate::mode::Output> };
+    (@type $a:lifetime Input) => { $crate::Pin<$a, $crate::mode::Input> };
+    (@type $a:lifetime Watch) => { $crate::Pin<$a, $crate::mode::Watch> };
+    (@configure $pin:expr, Output(Low)) => {
+        $pin.into_output($crate::__private::PinState::Low).await
+    };
+    (@configure $pin:expr, Output(High)) => {
+        $pin.into_output($crate::__private::PinState::High).await
+    };
+    (@configure $pin:expr, Input(pull_up)) => { $pin.into_input(true).await };
+    (@configure $pin:expr, Input(floating)) => { $pin.into_input(false).await };
+    (@configure $pin:expr, Watch(pull_up)) => { $pin.into_watch(true).await };
+    (@configure $pin:expr, Watch(floating)) => { $pin.into_watch(false).await };
+    (
+        $pins:expr => {
+            $($name:ident : $pin:ident as $mode:ident ( $arg:ident )),* $(,)?
+        }
+    ) => {{
+        let pins: $crate::InitialPins<'_> = $pins;
+        struct ClaimedPins<'a> {
+            $($name: $crate::claim_pins!(@type 'a $mode),)*
+        }
+        ClaimedPins {
+            $($name: $crate::claim_pins!(@configure pins.$pin, $mode($arg)),)*
+        }
+    }};
+}
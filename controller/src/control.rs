@@ -0,0 +1,362 @@
+use core::{future::poll_fn, mem, task::Poll};
+
+#[cfg(feature = "embassy-time")]
+use crate::sampling::SampleBuffer;
+use crate::{
+    errors::{ErrorBuffer, RetryPolicy},
+    heartbeat::HeartbeatConfig,
+};
+use crate::{
+    sync::{Mutex, WakerSlot},
+    *,
+};
+
+/// How the chip drives its `INTA` and `INTB` pins (`IOCON.ODR` and `IOCON.INTPOL`).
+/// The runner waits for the active level on the interrupt pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InterruptOutput {
+    /// Active low, so the line needs a pull-up.
+    /// The line can be shared with other open-drain interrupt sources.
+    #[default]
+    OpenDrain,
+    /// Driven high, and driven low when there is an interrupt
+    ActiveLow,
+    /// Driven low, and driven high when there is an interrupt.
+    /// Useful with inverting level shifters.
+    ActiveHigh,
+}
+
+impl InterruptOutput {
+    pub(crate) fn active_level(self) -> PinState {
+        match self {
+            Self::OpenDrain | Self::ActiveLow => PinState::Low,
+            Self::ActiveHigh => PinState::High,
+        }
+    }
+
+    /// `IOCON` with interrupt mirroring enabled
+    pub(crate) fn iocon(self) -> u8 {
+        const MIRROR: u8 = 1 << 6;
+        const ODR: u8 = 1 << 2;
+        const INTPOL: u8 = 1 << 1;
+        MIRROR
+            | match self {
+                Self::OpenDrain => ODR,
+                Self::ActiveLow => 0,
+                Self::ActiveHigh => INTPOL,
+            }
+    }
+}
+
+/// A request that is for the whole chip instead of a pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChipRequest {
+    pub interrupt_output: InterruptOutput,
+    /// Set by [`Control::reinitialize`]
+    pub reinitialize: bool,
+    pub state: RequestState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OutputCheck {
+    /// Set by [`Control::check_outputs`] and cleared by the runner after checking
+    pub requested: bool,
+    pub interval_ms: Option<u32>,
+    /// The result of the last check
+    pub faults: u16,
+    /// Faults that [`Control::wait_for_output_faults`] didn't return yet
+    pub unreported_faults: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ConnectionCheck {
+    /// Set by [`Control::is_connected`] and cleared by the runner after checking
+    pub requested: bool,
+    pub connected: bool,
+}
+
+/// The shared state used by [`Control`].
+/// Each kind of request has its own waker, so different tasks can wait on different requests.
+pub(crate) struct ControlState {
+    pub request: Mutex<ChipRequest>,
+    pub request_waker: WakerSlot,
+    pub output_check: Mutex<OutputCheck>,
+    pub output_check_waker: WakerSlot,
+    pub output_faults_waker: WakerSlot,
+    pub connection_check: Mutex<ConnectionCheck>,
+    pub connection_check_waker: WakerSlot,
+    /// Set by [`Control::write_failsafe`] and cleared by the runner after writing
+    pub failsafe_requested: Mutex<bool>,
+    pub failsafe_waker: WakerSlot,
+    pub heartbeat: Mutex<Option<HeartbeatConfig>>,
+    pub soft_start: Mutex<Option<SoftStart>>,
+    pub retry_policy: Mutex<RetryPolicy>,
+    pub bus_sharing: Mutex<BusSharing>,
+    #[cfg(feature = "embassy-time")]
+    pub i2c_timeout: Mutex<Option<embassy_time::Duration>>,
+    pub errors: Mutex<ErrorBuffer>,
+    pub error_waker: WakerSlot,
+    /// Woken after every runner pass
+    pub flush_waker: WakerSlot,
+    #[cfg(feature = "embassy-time")]
+    pub debounce_window: Mutex<Option<embassy_time::Duration>>,
+    #[cfg(feature = "embassy-time")]
+    pub samples: Mutex<SampleBuffer>,
+    #[cfg(feature = "embassy-time")]
+    pub sample_waker: WakerSlot,
+}
+
+impl ControlState {
+    pub const fn new() -> Self {
+        Self {
+            request: Mutex::new(ChipRequest {
+                interrupt_output: InterruptOutput::OpenDrain,
+                reinitialize: false,
+                state: RequestState::Done,
+            }),
+            request_waker: WakerSlot::new(),
+            output_check: Mutex::new(OutputCheck {
+                requested: false,
+                interval_ms: None,
+                faults: 0,
+                unreported_faults: 0,
+            }),
+            output_check_waker: WakerSlot::new(),
+            output_faults_waker: WakerSlot::new(),
+            connection_check: Mutex::new(ConnectionCheck {
+                requested: false,
+                connected: false,
+            }),
+            connection_check_waker: WakerSlot::new(),
+            failsafe_requested: Mutex::new(false),
+            failsafe_waker: WakerSlot::new(),
+            heartbeat: Mutex::new(None),
+            soft_start: Mutex::new(None),
+            retry_policy: Mutex::new(RetryPolicy::new()),
+            bus_sharing: Mutex::new(BusSharing::None),
+            #[cfg(feature = "embassy-time")]
+            i2c_timeout: Mutex::new(None),
+            errors: Mutex::new(ErrorBuffer::new()),
+            error_waker: WakerSlot::new(),
+            flush_waker: WakerSlot::new(),
+            #[cfg(feature = "embassy-time")]
+            debounce_window: Mutex::new(None),
+            #[cfg(feature = "embassy-time")]
+            samples: Mutex::new(SampleBuffer::new()),
+            #[cfg(feature = "embassy-time")]
+            sample_waker: WakerSlot::new(),
+        }
+    }
+}
+
+impl ControlState {
+    /// Whether the runner needs to do a pass for a [`Control`]
+    pub fn has_request(&self) -> bool {
+        self.request.get().state == RequestState::Requested
+            || self.output_check.get().requested
+            || self.connection_check.get().requested
+            || self.failsafe_requested.get()
+    }
+}
+
+/// Changes chip-wide settings through the runner. Get one with [`Runner::control`].
+/// Settings go back to their defaults when the [`Mcp23017`] is split again.
+///
+/// Only one task at a time should wait on each kind of request,
+/// otherwise the waiting tasks will keep waking each other.
+#[derive(Clone, Copy)]
+pub struct Control<'a> {
+    pub(crate) immutable: &'a Mcp23017Immutable,
+}
+
+impl Control<'_> {
+    /// Changes `IOCON.ODR` and `IOCON.INTPOL`, and waits until the runner has written them.
+    /// Make sure that the interrupt pin passed to [`Mcp23017::new`] works with the new output,
+    /// for example by changing its pull.
+    pub async fn set_interrupt_output(&self, interrupt_output: InterruptOutput) {
+        self.request(|request| request.interrupt_output = interrupt_output)
+            .await;
+    }
+
+    /// Makes the runner assume that the chip was reset, and write every register again.
+    /// Waits until `IOCON` was written. The other registers are written in the same pass.
+    /// See [`reset_all_on_bus`].
+    pub async fn reinitialize(&self) {
+        self.request(|request| request.reinitialize = true).await;
+    }
+
+    /// Reads `IOCON` and compares it with what the runner wrote.
+    /// Returns `false` if the read fails or if the value is different, which means that the chip
+    /// is unplugged, hung, or was reset. Unlike other I2C errors, this doesn't stop the runner.
+    pub async fn is_connected(&self) -> bool {
+        self.immutable
+            .control
+            .connection_check
+            .lock(|connection_check| connection_check.requested = true);
+        self.immutable.runner_waker.wake();
+        poll_fn(|cx| {
+            self.immutable
+                .control
+                .connection_check_waker
+                .register(cx.waker());
+            let connection_check = self.immutable.control.connection_check.get();
+            if connection_check.requested {
+                Poll::Pending
+            } else {
+                Poll::Ready(connection_check.connected)
+            }
+        })
+        .await
+    }
+
+    /// Makes the runner set output pins to their failsafe state (see [`Pin::set_failsafe_state`])
+    /// and then return `Ok(())`, and waits until it has. Dropping the runner future can't write
+    /// anything, so call this before stopping the runner, for example before shutting down.
+    /// Like after an error, the runner can be run again, and then it sets the pins to their
+    /// requested states again.
+    ///
+    /// Only the runner writes the failsafe states, so this waits forever if the runner isn't
+    /// being polled, for example because it already returned an error or was never started.
+    /// If that can happen, wait with a timeout, like `embassy_time::with_timeout`, or `select` it
+    /// with the runner future.
+    pub async fn write_failsafe(&self) {
+        self.immutable.control.failsafe_requested.set(true);
+        self.immutable.runner_waker.wake();
+        poll_fn(|cx| {
+            self.immutable.control.failsafe_waker.register(cx.waker());
+            if self.immutable.control.failsafe_requested.get() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await
+    }
+
+    /// Waits until every pin request that wasn't done when this was called is done,
+    /// for example to make sure that all outputs are set before powering a peripheral.
+    /// Input requests that wait for the pin to change are not waited for,
+    /// because they can take forever.
+    pub async fn flush(&self) {
+        let outstanding = || {
+            pin_mask(|i| {
+                let request = self.immutable.pins[i].request.get();
+                request.state != RequestState::Done
+                    && !matches!(
+                        request.op,
+                        Op::Input {
+                            pull_up_enabled: _,
+                            op: Some(
+                                InputOp::WaitForState {
+                                    state: _,
+                                    int_control: _,
+                                } | InputOp::WaitForAnyEdge
+                                    | InputOp::WaitForSpecificEdge { after_state: _ }
+                            ),
+                        }
+                    )
+            })
+        };
+        let pins = outstanding();
+        poll_fn(|cx| {
+            self.immutable.control.flush_waker.register(cx.waker());
+            if outstanding() & pins == 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn request(&self, f: impl FnOnce(&mut ChipRequest)) {
+        self.immutable.control.request.lock(|request| {
+            f(request);
+            request.state = RequestState::Requested;
+        });
+        self.immutable.runner_waker.wake();
+        poll_fn(|cx| {
+            self.immutable.control.request_waker.register(cx.waker());
+            if self.immutable.control.request.get().state == RequestState::Done {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Reads `GPIO` for the output pins and compares it with `OLAT`.
+    /// Returns a mask where bit `i` is set if output pin `i` is not at the level it is set to,
+    /// which means that it is probably shorted or overloaded.
+    pub async fn check_outputs(&self) -> u16 {
+        self.immutable
+            .control
+            .output_check
+            .lock(|output_check| output_check.requested = true);
+        self.immutable.runner_waker.wake();
+        poll_fn(|cx| {
+            self.immutable
+                .control
+                .output_check_waker
+                .register(cx.waker());
+            let output_check = self.immutable.control.output_check.get();
+            if output_check.requested {
+                Poll::Pending
+            } else {
+                Poll::Ready(output_check.faults)
+            }
+        })
+        .await
+    }
+
+    /// Makes the runner check the outputs whenever it has been idle for `interval_ms`.
+    /// Use [`Self::wait_for_output_faults`] to find out about faults. `None` disables this.
+    /// This uses the `Delay` passed to [`Mcp23017::new`].
+    pub fn set_output_check_interval(&self, interval_ms: Option<u32>) {
+        self.immutable
+            .control
+            .output_check
+            .lock(|output_check| output_check.interval_ms = interval_ms);
+        self.immutable.runner_waker.wake();
+    }
+
+    /// Waits until a check finds faulted outputs, see [`Self::check_outputs`].
+    /// Returns every pin that was found to be faulted since the last time this returned.
+    pub async fn wait_for_output_faults(&self) -> u16 {
+        poll_fn(|cx| {
+            self.immutable
+                .control
+                .output_faults_waker
+                .register(cx.waker());
+            match self
+                .immutable
+                .control
+                .output_check
+                .lock(|output_check| mem::take(&mut output_check.unreported_faults))
+            {
+                0 => Poll::Pending,
+                faults => Poll::Ready(faults),
+            }
+        })
+        .await
+    }
+}
+
+/// Resets every chip on a shared `RESET` line, and makes their runners write every register again.
+/// The MCP23017 doesn't support the I2C general call reset, so a `RESET` line is needed.
+/// `controls` should include every chip connected to `reset_pin`.
+pub async fn reset_all_on_bus<ResetPin: OutputPin>(
+    reset_pin: &mut ResetPin,
+    delay: &mut impl DelayNs,
+    controls: &[Control<'_>],
+) -> Result<(), ResetPin::Error> {
+    reset_pin.set_low().await?;
+    // The minimum reset pulse width is 1 µs
+    delay.delay_us(1).await;
+    reset_pin.set_high().await?;
+    for control in controls {
+        control.reinitialize().await;
+    }
+    Ok(())
+}
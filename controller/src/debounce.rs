@@ -0,0 +1,64 @@
+//! Limiting how often the runner services interrupts. This needs the `embassy-time` feature.
+
+#[cfg(feature = "embassy-time")]
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(feature = "embassy-time")]
+use mcp23017_common::mcp23016::FAST_INTERRUPT_ACTIVITY_RESOLUTION_US;
+
+use crate::*;
+
+#[cfg(feature = "embassy-time")]
+impl Control<'_> {
+    /// After servicing an interrupt, the runner ignores the interrupt pin for `window`.
+    /// Interrupts that happen during the window are serviced together when it is over,
+    /// so one noisy input can't keep the I2C bus busy. `None` disables this.
+    ///
+    /// This applies to every pin, including high priority pins.
+    /// Passes for pin requests still happen during the window, and they service pending interrupts
+    /// too. The new window is used the next time the runner is idle.
+    pub fn set_interrupt_debounce_window(&self, window: Option<Duration>) {
+        self.immutable.control.debounce_window.set(window);
+    }
+}
+
+/// Used by the runner to know when to wait for the interrupt pin again
+#[derive(Default)]
+pub(crate) struct Debouncer {
+    #[cfg(feature = "embassy-time")]
+    last_interrupt_at: Option<Instant>,
+}
+
+impl Debouncer {
+    /// Waits until the window after the last interrupt is over. The MCP23016 only notices a
+    /// change once per interrupt activity resolution, so its window is at least that long.
+    pub async fn wait(&self, immutable: &Mcp23017Immutable, device: Device) {
+        #[cfg(feature = "embassy-time")]
+        {
+            let window = match device {
+                Device::Mcp23017 => immutable.control.debounce_window.get(),
+                Device::Mcp23016 => Some(
+                    immutable
+                        .control
+                        .debounce_window
+                        .get()
+                        .unwrap_or_default()
+                        .max(Duration::from_micros(
+                            FAST_INTERRUPT_ACTIVITY_RESOLUTION_US.into(),
+                        )),
+                ),
+            };
+            if let (Some(window), Some(last_interrupt_at)) = (window, self.last_interrupt_at) {
+                Timer::at(last_interrupt_at + window).await;
+            }
+        }
+        #[cfg(not(feature = "embassy-time"))]
+        let _ = (immutable, device);
+    }
+
+    pub fn interrupt_serviced(&mut self) {
+        #[cfg(feature = "embassy-time")]
+        {
+            self.last_interrupt_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use mcp23017_common::mcp23016::{IOCON_IARES, Mcp23016Register, Mcp23016RegisterType};
+
+use crate::*;
+
+/// The chip that the runner drives. See [`Mcp23017::new_mcp23016`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Device {
+    #[default]
+    Mcp23017,
+    /// The older MCP23016. It has no pull-ups, so pull-ups that pins request are ignored. It has
+    /// no interrupt configuration either: it interrupts whenever an input changes, and the runner
+    /// finds the pins that changed by comparing `INTCAP` with the states it saw last.
+    /// Its interrupt output is always active low, so [`Control::set_interrupt_output`] has no
+    /// effect on it.
+    Mcp23016,
+}
+
+impl Device {
+    /// The address of a register. Panics if the MCP23016 doesn't have the register.
+    pub(crate) fn register_address(self, register: Register) -> u8 {
+        match self {
+            Self::Mcp23017 => register.address(false),
+            Self::Mcp23016 => Mcp23016Register {
+                _type: match register._type {
+                    RegisterType::GPIO => Mcp23016RegisterType::GP,
+                    RegisterType::OLAT => Mcp23016RegisterType::OLAT,
+                    RegisterType::IPOL => Mcp23016RegisterType::IPOL,
+                    RegisterType::IODIR => Mcp23016RegisterType::IODIR,
+                    RegisterType::INTCAP => Mcp23016RegisterType::INTCAP,
+                    RegisterType::IOCON => Mcp23016RegisterType::IOCON,
+                    _type => panic!("the MCP23016 doesn't have {:?}", _type),
+                },
+                ab: register.ab,
+            }
+            .address(),
+        }
+    }
+
+    /// Whether the chip has `GPPU`, `GPINTEN`, `DEFVAL`, `INTCON` and `INTF`
+    pub(crate) fn has_mcp23017_registers(self) -> bool {
+        self == Self::Mcp23017
+    }
+
+    /// What the runner writes to `IOCON`. The MCP23016 gets the fast interrupt activity
+    /// resolution, so it notices changes within 200 µs instead of 32 ms.
+    pub(crate) fn iocon(self, interrupt_output: InterruptOutput) -> u8 {
+        match self {
+            Self::Mcp23017 => interrupt_output.iocon(),
+            Self::Mcp23016 => IOCON_IARES,
+        }
+    }
+
+    /// The level of the interrupt pin when there is an interrupt
+    pub(crate) fn interrupt_active_level(self, interrupt_output: InterruptOutput) -> PinState {
+        match self {
+            Self::Mcp23017 => interrupt_output.active_level(),
+            Self::Mcp23016 => PinState::Low,
+        }
+    }
+}
+
+/// Finds the pins that changed on an MCP23016, which has no `INTF`
+#[derive(Default)]
+pub(crate) struct ChangeTracker {
+    /// The states that the runner last read from `INTCAP` or `GP`
+    last_states: u16,
+    /// Pins that were waiting for an interrupt and changed in a `GP` read. Reading `GP` clears the
+    /// interrupt, so these are flagged in the next pass instead.
+    carried: u16,
+    /// The states of the `carried` pins in that `GP` read
+    carried_captured: u16,
+}
+
+impl ChangeTracker {
+    /// Returns what `INTF` and `INTCAP` would be, given `INTCAP` if it was read
+    pub fn interrupt_registers(&mut self, captured: Option<u16>) -> (u16, u16) {
+        let captured = captured.unwrap_or(self.last_states);
+        let int_flags = (captured ^ self.last_states) | self.carried;
+        let captured = (captured & !self.carried) | (self.carried_captured & self.carried);
+        self.last_states = captured;
+        self.carried = 0;
+        (int_flags, captured)
+    }
+
+    /// Call after reading `GP` of both ports. `waiting` are the pins that are waiting for an
+    /// interrupt and aren't done with this read.
+    pub fn gpio_read(&mut self, gpio: u16, waiting: u16) {
+        let changed = (gpio ^ self.last_states) & waiting & !self.carried;
+        self.carried |= changed;
+        self.carried_captured = (self.carried_captured & !changed) | (gpio & changed);
+        self.last_states = gpio;
+    }
+
+    /// Whether the next pass has to flag pins, even if there is no interrupt
+    pub fn has_carried(&self) -> bool {
+        self.carried != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tracker that last saw `states`
+    fn tracker(states: u16) -> ChangeTracker {
+        let mut tracker = ChangeTracker::default();
+        tracker.gpio_read(states, 0);
+        tracker
+    }
+
+    #[test]
+    fn flags_are_the_pins_that_intcap_changed() {
+        let mut tracker = tracker(0b0000_0101);
+        assert_eq!(
+            tracker.interrupt_registers(Some(0b0000_0110)),
+            (0b0000_0011, 0b0000_0110)
+        );
+        // The captured states are the new last states
+        assert_eq!(
+            tracker.interrupt_registers(Some(0b0000_0110)),
+            (0, 0b0000_0110)
+        );
+    }
+
+    #[test]
+    fn no_flags_without_an_interrupt() {
+        let mut tracker = tracker(0x1234);
+        assert_eq!(tracker.interrupt_registers(None), (0, 0x1234));
+        assert!(!tracker.has_carried());
+    }
+
+    #[test]
+    fn gpio_read_carries_changes_of_waiting_pins() {
+        let mut tracker = tracker(0);
+        // Pin 0 is waiting and changed, pin 1 changed but isn't waiting
+        tracker.gpio_read(0b11, 0b01);
+        assert!(tracker.has_carried());
+        assert_eq!(tracker.interrupt_registers(None), (0b01, 0b11));
+        assert!(!tracker.has_carried());
+        assert_eq!(tracker.interrupt_registers(None), (0, 0b11));
+    }
+
+    #[test]
+    fn carried_pins_keep_the_state_they_changed_to_first() {
+        let mut tracker = tracker(0);
+        tracker.gpio_read(0b1, 0b1);
+        tracker.gpio_read(0b0, 0b1);
+        // INTCAP has the newer state, but the flag is for the first change
+        assert_eq!(tracker.interrupt_registers(Some(0b0)), (0b1, 0b1));
+    }
+
+    #[test]
+    fn read_after_writing_outputs_flags_nothing() {
+        // The runner wrote OLAT, so outputs 8 to 15 changed, and only input 0 is waiting
+        let mut tracker = tracker(0x0000);
+        tracker.gpio_read(0xFF00, 0x0001);
+        assert!(!tracker.has_carried());
+        assert_eq!(tracker.interrupt_registers(None), (0, 0xFF00));
+    }
+
+    #[test]
+    fn mcp23016_register_addresses() {
+        let address = |_type, ab| Device::Mcp23016.register_address(Register { _type, ab });
+        assert_eq!(address(RegisterType::GPIO, AB::A), 0x00);
+        assert_eq!(address(RegisterType::OLAT, AB::B), 0x03);
+        assert_eq!(address(RegisterType::IODIR, AB::A), 0x06);
+        assert_eq!(address(RegisterType::INTCAP, AB::B), 0x09);
+        assert_eq!(address(RegisterType::IOCON, AB::A), 0x0A);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::*;
+
+/// What the runner shares for [`Control::dump_state`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Diagnostics {
+    pub registers: Registers,
+    pub int_flags: u16,
+    pub runner_idle: bool,
+}
+
+impl Diagnostics {
+    pub const fn new() -> Self {
+        Self {
+            registers: Registers::new(),
+            int_flags: 0,
+            runner_idle: false,
+        }
+    }
+}
+
+/// A snapshot of the runner's state, for debugging things like a pin future that never finishes.
+/// Use the [`Debug`] impl (or the `defmt::Format` impl) to see everything, including the request of
+/// every pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateDump {
+    /// The register values that the runner last wrote
+    pub registers: Registers,
+    /// Bit `i` is set if the request of pin `i` is not done
+    pub pending_requests: u16,
+    /// Bit `i` is set if pin `i` has a new request that the runner didn't see yet
+    pub new_requests: u16,
+    /// `INTF` from the last time the runner read it, only including pins that were waiting for
+    /// an interrupt
+    pub int_flags: u16,
+    /// `true` if the runner is waiting for something to do, `false` if it's in the middle of a
+    /// pass (or not running)
+    pub runner_idle: bool,
+    requests: [Request; N_TOTAL_GPIO_PINS],
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for StateDump {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Debug2Format(self))
+    }
+}
+
+impl Control<'_> {
+    /// This doesn't wait for the runner, so it works even if the runner is stuck.
+    pub fn dump_state(&self) -> StateDump {
+        let diagnostics = self.immutable.diagnostics.get();
+        let requests = array::from_fn(|i| self.immutable.pins[i].request.get());
+        StateDump {
+            registers: diagnostics.registers,
+            pending_requests: pin_mask(|i| requests[i].state != RequestState::Done),
+            new_requests: self.immutable.new_requests.get(),
+            int_flags: diagnostics.int_flags,
+            runner_idle: diagnostics.runner_idle,
+            requests,
+        }
+    }
+}
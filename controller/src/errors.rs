@@ -0,0 +1,247 @@
+use core::{future::poll_fn, task::Poll};
+
+use embedded_hal::i2c::ErrorKind;
+
+use crate::*;
+
+/// The number of non-fatal errors that are kept until they are received
+pub const ERROR_BUFFER_LEN: usize = 4;
+
+/// Something that went wrong without stopping the runner. See [`Control::receive_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonFatalError {
+    /// An I2C transaction failed. The runner waits for `backoff_ms` and then starts over,
+    /// writing `IOCON` again and continuing with the requests that were not done.
+    I2c {
+        kind: ErrorKind,
+        /// The number of times in a row that the runner failed, starting at `1`
+        attempt: u32,
+        backoff_ms: u32,
+    },
+    /// `IOCON` was read back for [`Control::is_connected`], but it was different from what the
+    /// runner wrote, which means that the chip was probably reset
+    ReadbackMismatch,
+    /// The runner was woken by the interrupt pin, but no pin that has interrupts enabled had a
+    /// flag in `INTF`
+    SpuriousInterrupt,
+    /// Watched pins (bit `i` is pin `i`) changed more than once between interrupts, so the runner
+    /// missed edges. Their last known states were resynced from `GPIO`, and they were notified
+    /// even if their states are the same. See [`PinStats::changes`].
+    InterruptOverflow { pins: u16 },
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for NonFatalError {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Debug2Format(self))
+    }
+}
+
+/// How the runner retries after an I2C error. See [`Control::set_retry_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times in a row that the runner retries before returning [`RunError::I2c`].
+    /// `0` means that every I2C error stops the runner.
+    pub max_retries: u32,
+    /// The delay before the first retry. It doubles with every retry in a row.
+    pub initial_backoff_ms: u32,
+    pub max_backoff_ms: u32,
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `attempt`, starting at `1`
+    fn backoff_ms(&self, attempt: u32) -> u32 {
+        self.initial_backoff_ms
+            .saturating_mul(1 << (attempt - 1).min(31))
+            .min(self.max_backoff_ms)
+    }
+}
+
+impl RetryPolicy {
+    /// Retries 5 times in a row, with a backoff from 1 ms to 1 s
+    pub const fn new() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1000,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ErrorBuffer {
+    errors: [Option<NonFatalError>; ERROR_BUFFER_LEN],
+    start: u8,
+    len: u8,
+    /// Errors that were dropped because the buffer was full
+    dropped: u32,
+}
+
+impl ErrorBuffer {
+    pub const fn new() -> Self {
+        Self {
+            errors: [None; ERROR_BUFFER_LEN],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// If the buffer is full, the oldest error is dropped
+    fn push(&mut self, error: NonFatalError) {
+        let end = (self.start as usize + self.len as usize) % ERROR_BUFFER_LEN;
+        self.errors[end] = Some(error);
+        if self.len as usize == ERROR_BUFFER_LEN {
+            self.start = ((self.start as usize + 1) % ERROR_BUFFER_LEN) as u8;
+            self.dropped = self.dropped.saturating_add(1);
+        } else {
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<NonFatalError> {
+        if self.len == 0 {
+            return None;
+        }
+        let error = self.errors[self.start as usize].take();
+        self.start = ((self.start as usize + 1) % ERROR_BUFFER_LEN) as u8;
+        self.len -= 1;
+        error
+    }
+}
+
+impl Control<'_> {
+    /// Changes how the runner retries after an I2C error.
+    /// By default, it retries 5 times in a row, with a backoff from 1 ms to 1 s.
+    /// Errors from the interrupt pin always stop the runner.
+    pub fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+        self.immutable.control.retry_policy.set(retry_policy);
+    }
+
+    /// Waits for the oldest non-fatal error that wasn't received yet, for logging and counting
+    /// faults. Only the newest [`ERROR_BUFFER_LEN`] errors are kept.
+    pub async fn receive_error(&self) -> NonFatalError {
+        poll_fn(|cx| {
+            self.immutable.control.error_waker.register(cx.waker());
+            match self.immutable.control.errors.lock(ErrorBuffer::pop) {
+                Some(error) => Poll::Ready(error),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// The number of non-fatal errors that were dropped because they weren't received in time
+    pub fn dropped_errors(&self) -> u32 {
+        self.immutable.control.errors.get().dropped
+    }
+}
+
+pub(crate) fn report_error(immutable: &Mcp23017Immutable, error: NonFatalError) {
+    #[cfg(feature = "defmt")]
+    defmt::warn!("non-fatal error: {}", error);
+    immutable.control.errors.lock(|errors| errors.push(error));
+    immutable.control.error_waker.wake();
+}
+
+/// The delay before retrying, or `None` if the runner should stop
+pub(crate) fn retry_backoff_ms(immutable: &Mcp23017Immutable, attempt: u32) -> Option<u32> {
+    let retry_policy = immutable.control.retry_policy.get();
+    (attempt <= retry_policy.max_retries).then(|| retry_policy.backoff_ms(attempt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overflow(pins: u16) -> NonFatalError {
+        NonFatalError::InterruptOverflow { pins }
+    }
+
+    #[test]
+    fn errors_are_popped_in_order() {
+        let mut errors = ErrorBuffer::new();
+        errors.push(overflow(0));
+        errors.push(overflow(1));
+        assert_eq!(errors.pop(), Some(overflow(0)));
+        assert_eq!(errors.pop(), Some(overflow(1)));
+        assert_eq!(errors.pop(), None);
+        assert_eq!(errors.dropped, 0);
+    }
+
+    #[test]
+    fn error_buffer_wraps_around() {
+        let mut errors = ErrorBuffer::new();
+        for round in 0..3 {
+            for i in 0..ERROR_BUFFER_LEN as u16 - 1 {
+                errors.push(overflow(round * 10 + i));
+            }
+            for i in 0..ERROR_BUFFER_LEN as u16 - 1 {
+                assert_eq!(errors.pop(), Some(overflow(round * 10 + i)));
+            }
+        }
+        assert_eq!(errors.pop(), None);
+        assert_eq!(errors.dropped, 0);
+    }
+
+    #[test]
+    fn full_error_buffer_drops_the_oldest() {
+        let mut errors = ErrorBuffer::new();
+        for i in 0..ERROR_BUFFER_LEN as u16 + 2 {
+            errors.push(overflow(i));
+        }
+        assert_eq!(errors.dropped, 2);
+        for i in 2..ERROR_BUFFER_LEN as u16 + 2 {
+            assert_eq!(errors.pop(), Some(overflow(i)));
+        }
+        assert_eq!(errors.pop(), None);
+    }
+
+    #[test]
+    fn dropped_errors_saturate() {
+        let mut errors = ErrorBuffer::new();
+        errors.dropped = u32::MAX;
+        for i in 0..ERROR_BUFFER_LEN as u16 + 1 {
+            errors.push(overflow(i));
+        }
+        assert_eq!(errors.dropped, u32::MAX);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let retry_policy = RetryPolicy {
+            max_retries: 20,
+            initial_backoff_ms: 3,
+            max_backoff_ms: 100,
+        };
+        let backoffs = (1..=7).map(|attempt| retry_policy.backoff_ms(attempt));
+        assert!(backoffs.eq([3, 6, 12, 24, 48, 96, 100]));
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let retry_policy = RetryPolicy {
+            max_retries: u32::MAX,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: u32::MAX,
+        };
+        assert_eq!(retry_policy.backoff_ms(23), 1000 << 22);
+        assert_eq!(retry_policy.backoff_ms(24), u32::MAX);
+        assert_eq!(retry_policy.backoff_ms(32), u32::MAX);
+        assert_eq!(retry_policy.backoff_ms(u32::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn default_backoff_is_1_ms_to_1_s() {
+        let retry_policy = RetryPolicy::new();
+        assert_eq!(retry_policy.backoff_ms(1), 1);
+        assert_eq!(retry_policy.backoff_ms(10), 512);
+        assert_eq!(retry_policy.backoff_ms(11), 1000);
+    }
+}
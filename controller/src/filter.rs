@@ -0,0 +1,153 @@
+use crate::*;
+
+/// Filters noisy inputs, like industrial inputs through long cables, with an N-of-M majority vote.
+/// A pin only changes state once at least `n` of the last `M` samples agree on the new state.
+/// Feed it samples from [`Control::receive_sample`], or from anything else.
+pub struct MajorityFilter<const M: usize> {
+    mask: u16,
+    n: usize,
+    history: [u16; M],
+    next: usize,
+    states: Option<u16>,
+}
+
+impl<const M: usize> MajorityFilter<M> {
+    /// Only pins in `mask` are filtered. The other pins follow the newest sample.
+    /// `n` must be more than half of `M`, so that a pin can't have a majority for both states.
+    pub const fn new(mask: u16, n: usize) -> Self {
+        const {
+            assert!(M > 0, "a filter needs at least 1 sample");
+        }
+        assert!(
+            n > M / 2 && n <= M,
+            "n must be more than M / 2 and at most M"
+        );
+        Self {
+            mask,
+            n,
+            history: [0; M],
+            next: 0,
+            states: None,
+        }
+    }
+
+    /// Adds a sample and returns the filtered states. Bit `i` is the state of pin `i`.
+    /// The first sample is used as the initial state.
+    pub fn push(&mut self, sample: u16) -> u16 {
+        let states = match self.states {
+            None => {
+                self.history = [sample; M];
+                sample
+            }
+            Some(states) => {
+                self.history[self.next] = sample;
+                self.next = (self.next + 1) % M;
+                let mut new_states = (states & self.mask) | (sample & !self.mask);
+                for i in (0..N_TOTAL_GPIO_PINS).filter(|&i| bit(self.mask, i)) {
+                    let highs = self
+                        .history
+                        .iter()
+                        .filter(|&&sample| bit(sample, i))
+                        .count();
+                    if highs >= self.n {
+                        new_states |= 1 << i;
+                    } else if M - highs >= self.n {
+                        new_states &= !(1 << i);
+                    }
+                }
+                new_states
+            }
+        };
+        self.states = Some(states);
+        states
+    }
+
+    /// The filtered states, or `None` if there were no samples yet
+    pub fn states(&self) -> Option<u16> {
+        self.states
+    }
+
+    /// The filtered state of a pin, or `None` if there were no samples yet or there is no pin
+    /// `pin_index`
+    pub fn state(&self, pin_index: usize) -> Option<PinState> {
+        if pin_index >= N_TOTAL_GPIO_PINS {
+            return None;
+        }
+        self.states.map(|states| bit(states, pin_index).into())
+    }
+
+    /// Waits for the next sample from [`Control::receive_sample`] and returns the filtered states
+    #[cfg(feature = "embassy-time")]
+    pub async fn receive(&mut self, control: &Control<'_>) -> u16 {
+        let sample = control.receive_sample().await;
+        self.push(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_sample_fills_the_window() {
+        let mut filter = MajorityFilter::<5>::new(0xFFFF, 3);
+        assert_eq!(filter.states(), None);
+        assert_eq!(filter.push(0b1), 0b1);
+        // The window is 5 copies of the first sample, so 2 new samples can't outvote it
+        assert_eq!(filter.push(0b0), 0b1);
+        assert_eq!(filter.push(0b0), 0b1);
+    }
+
+    #[test]
+    fn changes_once_n_samples_agree() {
+        let mut filter = MajorityFilter::<5>::new(0xFFFF, 4);
+        filter.push(0b0);
+        for _ in 0..3 {
+            assert_eq!(filter.push(0b1), 0b0);
+        }
+        assert_eq!(filter.push(0b1), 0b1);
+        // 3 lows out of 5 aren't enough to change back
+        for _ in 0..3 {
+            assert_eq!(filter.push(0b0), 0b1);
+        }
+        assert_eq!(filter.push(0b0), 0b0);
+    }
+
+    #[test]
+    fn tie_keeps_the_state() {
+        let mut filter = MajorityFilter::<4>::new(0xFFFF, 3);
+        filter.push(0b0);
+        assert_eq!(filter.push(0b1), 0b0);
+        // 2 highs and 2 lows
+        assert_eq!(filter.push(0b1), 0b0);
+        assert_eq!(filter.push(0b1), 0b1);
+        // 2 highs and 2 lows again, after the window moved on
+        assert_eq!(filter.push(0b0), 0b1);
+        assert_eq!(filter.push(0b0), 0b1);
+        assert_eq!(filter.push(0b0), 0b0);
+    }
+
+    #[test]
+    fn pins_outside_the_mask_follow_the_newest_sample() {
+        let mut filter = MajorityFilter::<3>::new(0b01, 2);
+        assert_eq!(filter.push(0b00), 0b00);
+        assert_eq!(filter.push(0b11), 0b10);
+        assert_eq!(filter.push(0b01), 0b01);
+    }
+
+    #[test]
+    fn state_of_a_pin() {
+        let mut filter = MajorityFilter::<3>::new(0xFFFF, 2);
+        assert_eq!(filter.state(0), None);
+        filter.push(1 << 15);
+        assert_eq!(filter.state(0), Some(PinState::Low));
+        assert_eq!(filter.state(15), Some(PinState::High));
+        assert_eq!(filter.state(16), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn n_must_be_a_majority() {
+        MajorityFilter::<4>::new(0xFFFF, 2);
+    }
+}
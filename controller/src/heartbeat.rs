@@ -0,0 +1,44 @@
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HeartbeatConfig {
+    pub index: u8,
+    pub interval_ms: Option<u32>,
+}
+
+/// An output pin that the runner toggles on every pass.
+/// An external watchdog circuit can use it to detect a hung runner, and it also works as a
+/// liveness LED. Get one with [`Pin::into_heartbeat`].
+pub struct Heartbeat<'a> {
+    pin: Pin<'a, mode::Output>,
+}
+
+impl<'a> Pin<'a, mode::Output> {
+    /// Makes the runner toggle this pin on every pass.
+    /// With `interval_ms`, the runner also does a pass whenever it has been idle for
+    /// `interval_ms`, so that the pin keeps toggling while nothing else is happening.
+    /// This uses the `Delay` passed to [`Mcp23017::new`].
+    ///
+    /// Only one pin can be the heartbeat. Making a different pin the heartbeat stops toggling this
+    /// one.
+    pub fn into_heartbeat(self, interval_ms: Option<u32>) -> Heartbeat<'a> {
+        self.immutable.control.heartbeat.set(Some(HeartbeatConfig {
+            index: self.index,
+            interval_ms,
+        }));
+        self.immutable.runner_waker.wake();
+        Heartbeat { pin: self }
+    }
+}
+
+impl<'a> Heartbeat<'a> {
+    /// Stops toggling the pin. It stays at the state that it was toggled to last.
+    pub fn into_pin(self) -> Pin<'a, mode::Output> {
+        self.pin.immutable.control.heartbeat.lock(|heartbeat| {
+            if heartbeat.is_some_and(|heartbeat| heartbeat.index == self.pin.index) {
+                *heartbeat = None;
+            }
+        });
+        self.pin
+    }
+}
@@ -1,53 +1,105 @@
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
+
 use crate::*;
 
-impl Pin<'_, mode::Input> {
-    async fn op(&self, _op: InputOp) -> InputOp {
-        // self.s.sender().send_if_modified(|request| {
-        //     let request = request.as_mut().unwrap();
-        //     let pull_up_enabled = match request.op {
-        //         Op::Input {
-        //             pull_up_enabled,
-        //             op: _,
-        //         } => pull_up_enabled,
-        //         _ => unreachable!(),
-        //     };
-        //     let op = Op::Input {
-        //         pull_up_enabled,
-        //         op: Some(op),
-        //     };
-        //     if request.op != op {
-        //         *request = Request {
-        //             op,
-        //             state: RequestState::Requested,
-        //         };
-        //         true
-        //     } else if request.state == RequestState::Done {
-        //         request.state = RequestState::Requested;
-        //         true
-        //     } else {
-        //         false
-        //     }
-        // });
-        // match self
-        //     .s
-        //     .receiver()
-        //     .unwrap()
-        //     .changed_and(|request| request.state == RequestState::Done)
-        //     .await
-        //     .op
-        // {
-        //     Op::Input {
-        //         pull_up_enabled: _,
-        //         op,
-        //     } => op.unwrap(),
-        //     _ => unreachable!(),
-        // }
-        todo!()
+impl<'a, Mode> Pin<'a, Mode> {
+    /// Same as [`Pin::into_input`], but also returns the state of the pin.
+    /// The runner changes the mode and reads `GPIO` in the same pass, so the state is never from
+    /// before the pin was an input, and there is no second round trip.
+    pub async fn into_input_and_read(
+        self,
+        pull_up_enabled: bool,
+    ) -> (Pin<'a, mode::Input>, PinState) {
+        let pin = self.request_input(pull_up_enabled);
+        pin.request_input_op(InputOp::Read { response: None });
+        pin.wait_for_input_op().await;
+        let state = pin.read_response();
+        (pin, state)
+    }
+}
+
+impl<'a> Pin<'a, mode::Input> {
+    async fn op(&self, op: InputOp) -> InputOp {
+        self.request_input_op(op);
+        self.wait_for_input_op().await
+    }
+
+    /// Requests the op without waiting for it to be done.
+    /// Requesting multiple pins before waiting lets the runner process them in the same pass.
+    fn request_input_op(&self, op: InputOp) {
+        let queued = self.s().request.lock(|request| {
+            let (pull_up_enabled, current_op) = match request.op {
+                Op::Input {
+                    pull_up_enabled,
+                    op,
+                } => (pull_up_enabled, op),
+                _ => unreachable!(),
+            };
+            // Let the runner finish a read that it is already doing instead of throwing away its
+            // transaction, and request the op when the read is done
+            if request.state == RequestState::ProcessingRequest
+                && matches!(current_op, Some(InputOp::Read { response: _ }))
+            {
+                self.s().queued_input_op.set(Some(op));
+                return true;
+            }
+            self.s().queued_input_op.set(None);
+            // Always request, even if the op is the same as the last op, because the state
+            // of the pin could have changed since then
+            *request = Request {
+                op: Op::Input {
+                    pull_up_enabled,
+                    op: Some(op),
+                },
+                state: RequestState::Requested,
+            };
+            #[cfg(feature = "defmt")]
+            defmt::trace!("pin signaling request: {}", defmt::Debug2Format(&request));
+            false
+        });
+        if !queued {
+            self.notify_runner();
+        }
+    }
+
+    /// Returns the op if it's done. If an op was queued, it is requested now and this returns
+    /// `None` until it's done.
+    fn done_input_op(&self) -> Option<InputOp> {
+        match self.s().request.get() {
+            Request {
+                op:
+                    Op::Input {
+                        pull_up_enabled: _,
+                        op,
+                    },
+                state: RequestState::Done,
+            } => match self.s().queued_input_op.lock(Option::take) {
+                Some(queued_op) => {
+                    self.request_input_op(queued_op);
+                    None
+                }
+                None => Some(op.unwrap()),
+            },
+            _ => None,
+        }
+    }
+
+    async fn wait_for_input_op(&self) -> InputOp {
+        self.wait_for_request(|_| self.done_input_op()).await
     }
 
     async fn state(&self) -> PinState {
-        match self.op(InputOp::Read { response: None }).await {
-            InputOp::Read { response } => response.unwrap(),
+        self.op(InputOp::Read { response: None }).await;
+        self.read_response()
+    }
+
+    /// The response of a done read
+    fn read_response(&self) -> PinState {
+        match self.done_input_op() {
+            Some(InputOp::Read { response }) => response.unwrap(),
             _ => unreachable!(),
         }
     }
@@ -56,8 +108,57 @@ impl Pin<'_, mode::Input> {
         self.op(InputOp::WaitForSpecificEdge { after_state }).await;
     }
 
-    async fn wait_for_state(&self, state: PinState) {
-        self.op(InputOp::WaitForState(state)).await;
+    /// Wait until the pin is `level`, returning immediately if it already is.
+    ///
+    /// Unlike [`Wait::wait_for_high`] and [`Wait::wait_for_low`], which read `GPIO` and then
+    /// wait for the pin to change, this compares the pin with `DEFVAL`.
+    /// The chip keeps the interrupt active for as long as the pin is not `level`,
+    /// so a pulse to `level` that is over before the runner gets to it is not missed.
+    pub fn wait_while_not(&mut self, level: PinState) -> InputFuture<'_, 'a> {
+        self.wait_for_level_with(level, InterruptControl::CompareWithConfiguredValue)
+    }
+
+    /// Wait until the pin is `level`, choosing how the chip should generate the interrupt.
+    /// - [`InterruptControl::CompareWithConfiguredValue`] is the same as [`Self::wait_while_not`]
+    /// - [`InterruptControl::CompareWithPreviousValue`] is the same as [`Wait::wait_for_high`]
+    ///   and [`Wait::wait_for_low`]
+    pub fn wait_for_level_with(
+        &mut self,
+        level: PinState,
+        int_control: InterruptControl,
+    ) -> InputFuture<'_, 'a> {
+        InputFuture {
+            pin: self,
+            op: Some(InputOp::WaitForState {
+                state: level,
+                int_control,
+            }),
+        }
+    }
+}
+
+/// Returned by [`Pin::wait_while_not`] and [`Pin::wait_for_level_with`].
+/// Unlike an `async fn`, this can be named, so it can be stored in a struct or a `static`.
+/// The request is sent to the runner the first time this is polled.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct InputFuture<'p, 'a> {
+    pin: &'p Pin<'a, mode::Input>,
+    /// `None` after the request was sent
+    op: Option<InputOp>,
+}
+
+impl Future for InputFuture<'_, '_> {
+    type Output = ();
+
+    fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(op) = self.op.take() {
+            self.pin.request_input_op(op);
+        }
+        self.pin.s().waker.register(cx.waker());
+        match self.pin.done_input_op() {
+            Some(_) => Poll::Ready(()),
+            None => Poll::Pending,
+        }
     }
 }
 
@@ -73,12 +174,14 @@ impl InputPin for Pin<'_, mode::Input> {
 
 impl Wait for Pin<'_, mode::Input> {
     async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
-        self.wait_for_state(PinState::High).await;
+        self.wait_for_level_with(PinState::High, InterruptControl::CompareWithPreviousValue)
+            .await;
         Ok(())
     }
 
     async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
-        self.wait_for_state(PinState::Low).await;
+        self.wait_for_level_with(PinState::Low, InterruptControl::CompareWithPreviousValue)
+            .await;
         Ok(())
     }
 
@@ -97,3 +200,50 @@ impl Wait for Pin<'_, mode::Input> {
         Ok(())
     }
 }
+
+/// Waits until every pin is at its level at the same time, returning immediately if they already
+/// are.
+///
+/// All of the pins are read in one runner pass. Then every pin that is not at its level
+/// compares with `DEFVAL` in one runner pass, so a short pulse to the level is not missed.
+/// When any of them gets to its level, all of the pins are read again.
+pub async fn wait_for_all(pins: &mut [(Pin<'_, mode::Input>, PinState)]) {
+    loop {
+        for (pin, _) in pins.iter() {
+            pin.request_input_op(InputOp::Read { response: None });
+        }
+        let mut not_at_level = Vec::<usize, N_TOTAL_GPIO_PINS>::new();
+        for (i, (pin, state)) in pins.iter().enumerate() {
+            match pin.wait_for_input_op().await {
+                InputOp::Read { response } if response == Some(*state) => {}
+                InputOp::Read { response: _ } => not_at_level.push(i).unwrap(),
+                _ => unreachable!(),
+            }
+        }
+        if not_at_level.is_empty() {
+            return;
+        }
+
+        for &i in &not_at_level {
+            let (pin, state) = &pins[i];
+            pin.request_input_op(InputOp::WaitForState {
+                state: *state,
+                int_control: InterruptControl::CompareWithConfiguredValue,
+            });
+        }
+        poll_fn(|cx| {
+            let mut any_done = false;
+            for &i in &not_at_level {
+                let (pin, _) = &pins[i];
+                pin.s().waker.register(cx.waker());
+                any_done |= pin.done_input_op().is_some();
+            }
+            if any_done {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{sync::Mutex, *};
+
+/// The pixels of a [`LedMatrix`]. It can be changed from any task while the matrix is refreshed,
+/// for example by putting it in a `static`.
+pub struct Framebuffer {
+    /// Bit `x` of row `y` is the pixel at (`x`, `y`)
+    rows: Mutex<[u8; 8]>,
+}
+
+impl Framebuffer {
+    pub const fn new() -> Self {
+        Self {
+            rows: Mutex::new([0; 8]),
+        }
+    }
+
+    pub fn set_pixel(&self, x: usize, y: usize, on: bool) {
+        self.rows.lock(|rows| {
+            if on {
+                rows[y] |= 1 << x;
+            } else {
+                rows[y] &= !(1 << x);
+            }
+        });
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.rows.get()[y] & (1 << x) != 0
+    }
+
+    /// Bit `x` is the pixel at (`x`, `y`)
+    pub fn set_row(&self, y: usize, pixels: u8) {
+        self.rows.lock(|rows| rows[y] = pixels);
+    }
+
+    /// Changes every pixel at once, so that a refresh never shows half of the change
+    pub fn set_rows(&self, rows: [u8; 8]) {
+        self.rows.set(rows);
+    }
+
+    pub fn clear(&self) {
+        self.set_rows([0; 8]);
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drives a multiplexed LED matrix of up to 8x8, with one pin for each row and each column.
+/// One row is on at a time, and the rows are scanned fast enough that they all look on.
+///
+/// Rows are active high, and columns are active low (they sink the current of the LEDs in the
+/// row). The rows usually need transistors, because a whole row draws more current than a pin can
+/// source.
+///
+/// Every row is written in a single runner pass. With the rows on one port and the columns on
+/// the other, that's a single I2C write. The previous row is switched off in the same write, so
+/// there is no ghosting from showing the columns of one row on another row.
+pub struct LedMatrix<'a, const ROWS: usize, const COLUMNS: usize, Delay> {
+    rows: [Pin<'a, mode::Output>; ROWS],
+    columns: [Pin<'a, mode::Output>; COLUMNS],
+    delay: Delay,
+    row_time_us: u32,
+}
+
+impl<'a, const ROWS: usize, const COLUMNS: usize, Delay: DelayNs>
+    LedMatrix<'a, ROWS, COLUMNS, Delay>
+{
+    /// Refreshes the whole matrix 100 times per second, see [`Self::set_refresh_rate`].
+    /// Nothing is shown until the first refresh.
+    pub fn new(
+        rows: [Pin<'a, mode::Output>; ROWS],
+        columns: [Pin<'a, mode::Output>; COLUMNS],
+        delay: Delay,
+    ) -> Self {
+        const {
+            assert!(
+                ROWS > 0 && ROWS <= 8 && COLUMNS > 0 && COLUMNS <= 8,
+                "a LED matrix can be at most 8x8"
+            );
+        }
+        let mut led_matrix = Self {
+            rows,
+            columns,
+            delay,
+            row_time_us: 0,
+        };
+        led_matrix.set_refresh_rate(100);
+        led_matrix
+    }
+
+    pub fn into_pins(
+        self,
+    ) -> (
+        [Pin<'a, mode::Output>; ROWS],
+        [Pin<'a, mode::Output>; COLUMNS],
+    ) {
+        (self.rows, self.columns)
+    }
+
+    /// How many times per second every row is shown.
+    /// If the runner can't keep up, the actual rate is lower.
+    pub fn set_refresh_rate(&mut self, hz: u32) {
+        self.row_time_us = 1_000_000 / (hz.max(1) * ROWS as u32);
+    }
+
+    /// Turns on row `y` (if it's `Some`) with the columns in `pixels`, and turns off every other row
+    async fn show_row(&mut self, y: Option<usize>, pixels: u8) {
+        for (i, pin) in self.rows.iter().enumerate() {
+            pin.request_op(Op::Output {
+                latch: (y == Some(i)).into(),
+            });
+        }
+        for (x, pin) in self.columns.iter().enumerate() {
+            pin.request_op(Op::Output {
+                latch: (pixels & (1 << x) == 0).into(),
+            });
+        }
+        for pin in self.rows.iter().chain(&self.columns) {
+            pin.wait_until_done().await;
+        }
+    }
+
+    /// Shows every row once
+    pub async fn refresh(&mut self, framebuffer: &Framebuffer) {
+        for y in 0..ROWS {
+            let pixels = framebuffer.rows.get()[y];
+            self.show_row(Some(y), pixels).await;
+            self.delay.delay_us(self.row_time_us).await;
+        }
+    }
+
+    /// Keeps refreshing. This never returns, but it is safe to cancel.
+    /// Call [`Self::blank`] after cancelling it, otherwise the last row stays on.
+    pub async fn run(&mut self, framebuffer: &Framebuffer) -> ! {
+        loop {
+            self.refresh(framebuffer).await;
+        }
+    }
+
+    /// Turns off every LED
+    pub async fn blank(&mut self) {
+        self.show_row(None, 0).await;
+    }
+}
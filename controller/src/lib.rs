@@ -1,32 +1,90 @@
 #![no_std]
+mod bus_sharing;
+mod claim;
+mod control;
+mod debounce;
+mod device;
+mod diagnostics;
+mod errors;
+mod filter;
+mod heartbeat;
 mod input;
+mod led_matrix;
+mod ll;
 pub mod mode;
 mod output;
 mod pin;
+mod polled_interrupt;
+mod port;
+mod recovery;
 mod register;
 mod runner;
+mod sampling;
+mod sequencer;
+mod seven_segment;
+mod shared;
+mod soft_start;
+mod stepper;
+mod sync;
+#[cfg(feature = "embassy-executor")]
+mod task;
+mod timeout;
+mod transaction;
 mod util;
 mod watch;
 
 use core::{array, convert::Infallible};
 
-use embassy_futures::select::select;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, rwlock::RwLock, signal::Signal};
 use embedded_hal::digital::{ErrorType, PinState};
 use embedded_hal_async::{
     delay::DelayNs,
     digital::{InputPin, OutputPin, StatefulOutputPin, Wait},
 };
 use heapless::Vec;
-use mcp23017_common::{
-    AB, InterruptControl, IoDirection, N_TOTAL_GPIO_PINS, Register, RegisterType,
-};
+pub use ll::*;
+use mcp23017_common::N_TOTAL_GPIO_PINS;
+pub use mcp23017_common::{AB, InterruptControl, IoDirection, Register, RegisterType};
 pub use pin::*;
+pub use polled_interrupt::PolledInterruptPin;
+pub use port::OutputPort;
 use util::*;
 
-use crate::{mode::Input, runner::run};
+pub use bus_sharing::BusSharing;
+pub use control::*;
+pub use device::Device;
+pub use diagnostics::StateDump;
+pub use errors::{ERROR_BUFFER_LEN, NonFatalError, RetryPolicy};
+pub use filter::*;
+pub use heartbeat::Heartbeat;
+pub use input::{InputFuture, wait_for_all};
+pub use led_matrix::{Framebuffer, LedMatrix};
+pub use recovery::{RecoverBusError, recover_bus};
+pub use runner::{RunFuture, Runner};
+pub use sampling::SAMPLE_BUFFER_LEN;
+pub use sequencer::*;
+pub use seven_segment::{CommonPin, SevenSegment, SevenSegmentDigits};
+pub use shared::SharedPin;
+pub use soft_start::SoftStart;
+pub use stepper::*;
+pub use transaction::{Transaction, TransactionPins};
+pub use watch::{PinStats, WatchFuture};
+
+/// Used by the macros
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "embassy-executor")]
+    pub use embassy_executor;
+    pub use embedded_hal::digital::PinState;
+    #[cfg(feature = "embassy-executor")]
+    pub use static_cell;
+}
 
-type M = CriticalSectionRawMutex;
+use crate::{
+    diagnostics::Diagnostics,
+    mode::Input,
+    shared::SHARED_WAKERS,
+    sync::{MultiWakerSlot, Mutex, WakerSlot, atomically},
+};
 
 const BASE_ADDRESS: u8 = 0x20;
 
@@ -40,43 +98,82 @@ fn address(address_lower_bits: [bool; 3]) -> u8 {
     address
 }
 
+/// What [`Runner::run`] returns
+pub type RunResult<I2c, ResetPin, InterruptPin> = Result<
+    (),
+    RunError<
+        <ResetPin as ErrorType>::Error,
+        <InterruptPin as ErrorType>::Error,
+        <I2c as embedded_hal::i2c::ErrorType>::Error,
+    >,
+>;
+
 #[derive(Debug)]
 pub enum RunError<ResetPinError, InterruptPinError, I2cError> {
     ResetPin(ResetPinError),
     InterruptPin(InterruptPinError),
     I2c(I2cError),
+    /// An I2C transaction took longer than the timeout set with `Control::set_i2c_timeout`
+    Timeout,
 }
 
+/// The values the runner last wrote. Bit `i` is for pin `i`.
+/// Right after starting or reinitializing, these are the chip's power-on values.
+/// With [`Device::Mcp23016`], `pull_up_enabled` stays `0`, and `int_enabled`, `int_control` and
+/// `int_compare` are what the runner would write to an MCP23017, because the MCP23016 doesn't have
+/// those registers.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct PinRegisters {
-    io_dir: IoDirection,
-    pull_up_enabled: bool,
-    latch: PinState,
-    int_enabled: bool,
-    int_control: InterruptControl,
-    int_compare: PinState,
+pub struct Registers {
+    /// `IODIR`, where `1` is input
+    pub io_dir: u16,
+    /// `GPPU`
+    pub pull_up_enabled: u16,
+    /// `OLAT`
+    pub latch: u16,
+    /// `GPINTEN`
+    pub int_enabled: u16,
+    /// `INTCON`, where `1` is [`InterruptControl::CompareWithConfiguredValue`]
+    pub int_control: u16,
+    /// `DEFVAL`
+    pub int_compare: u16,
+    /// `IPOL`, where `1` inverts what is read from the pin
+    pub polarity_inverted: u16,
 }
 
-impl Default for PinRegisters {
-    fn default() -> Self {
+impl Registers {
+    /// The chip's power-on values
+    pub const fn new() -> Self {
         Self {
-            io_dir: IoDirection::Input,
-            pull_up_enabled: false,
-            int_enabled: false,
-            latch: PinState::Low,
-            int_control: InterruptControl::CompareWithPreviousValue,
-            int_compare: PinState::Low,
+            io_dir: u16::MAX,
+            pull_up_enabled: 0,
+            latch: 0,
+            int_enabled: 0,
+            int_control: 0,
+            int_compare: 0,
+            polarity_inverted: 0,
         }
     }
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum InputOp {
     Read {
         response: Option<PinState>,
     },
-    /// The runner sets the compare value to the opposite state and waits for an interrupt.
-    WaitForState(PinState),
+    /// With [`InterruptControl::CompareWithConfiguredValue`], the runner sets the compare value
+    /// to the opposite state and waits for an interrupt.
+    /// With [`InterruptControl::CompareWithPreviousValue`], the runner reads the GPIO reg,
+    /// and reads it again on every interrupt until it is the specified state.
+    WaitForState {
+        state: PinState,
+        int_control: InterruptControl,
+    },
     /// The runner reads the GPIO reg, sets the compare value to compare with previous,
     /// and waits for an interrupt.
     WaitForAnyEdge,
@@ -116,29 +213,75 @@ enum RequestState {
 }
 
 struct Mcp23017ImmutablePin {
-    request: RwLock<M, Request>,
-    request_signal: Signal<M, ()>,
-    response_signal: Signal<M, ()>,
+    request: Mutex<Request>,
+    /// The task waiting for the request to be done, or for a watched pin to change
+    waker: WakerSlot,
+    /// Only used if the pin is an output
+    failsafe_state: Mutex<Option<PinState>>,
+    /// Only used if the pin is watched
+    stats: Mutex<PinStats>,
+    /// An input op that was requested while the runner was doing a read, which is requested
+    /// after the read is done
+    queued_input_op: Mutex<Option<InputOp>>,
 }
 
-impl Default for Mcp23017ImmutablePin {
-    fn default() -> Self {
+impl Mcp23017ImmutablePin {
+    const fn new() -> Self {
         Self {
-            request: RwLock::new(Request {
+            request: Mutex::new(Request {
                 op: Op::Input {
                     pull_up_enabled: false,
                     op: None,
                 },
                 state: RequestState::Done,
             }),
-            request_signal: Signal::new(),
-            response_signal: Signal::new(),
+            waker: WakerSlot::new(),
+            failsafe_state: Mutex::new(None),
+            stats: Mutex::new(PinStats::new()),
+            queued_input_op: Mutex::new(None),
         }
     }
 }
 
 struct Mcp23017Immutable {
     pins: [Mcp23017ImmutablePin; N_TOTAL_GPIO_PINS],
+    /// Bit `i` is set when pin `i` has a new request for the runner
+    new_requests: Mutex<u16>,
+    runner_waker: WakerSlot,
+    /// Bit `i` is set when the last known value of watched pin `i` changes
+    watch_changes: Mutex<u16>,
+    /// Bit `i` is set if pin `i` is high priority
+    high_priority: Mutex<u16>,
+    /// Bit `i` is set if reads of pin `i` should be inverted (`IPOL`)
+    polarity_inverted: Mutex<u16>,
+    /// Tasks using a [`SharedPin`] of any pin. Woken together with the waker of the pin.
+    shared_waker: MultiWakerSlot<SHARED_WAKERS>,
+    control: ControlState,
+    diagnostics: Mutex<Diagnostics>,
+}
+
+impl Mcp23017Immutable {
+    const fn new() -> Self {
+        Self {
+            pins: [const { Mcp23017ImmutablePin::new() }; N_TOTAL_GPIO_PINS],
+            new_requests: Mutex::new(0),
+            runner_waker: WakerSlot::new(),
+            watch_changes: Mutex::new(0),
+            high_priority: Mutex::new(0),
+            polarity_inverted: Mutex::new(0),
+            shared_waker: MultiWakerSlot::new(),
+            control: ControlState::new(),
+            diagnostics: Mutex::new(Diagnostics::new()),
+        }
+    }
+}
+
+impl Mcp23017Immutable {
+    /// Wakes the task waiting on pin `i`, and any task using a [`SharedPin`]
+    fn wake_pin(&self, i: usize) {
+        self.pins[i].waker.wake();
+        self.shared_waker.wake();
+    }
 }
 
 struct Mcp23017Mutable<I2c, ResetPin, InterruptPin, Delay> {
@@ -147,6 +290,30 @@ struct Mcp23017Mutable<I2c, ResetPin, InterruptPin, Delay> {
     reset_pin: ResetPin,
     interrupt_pin: InterruptPin,
     delay: Delay,
+    device: Device,
+    /// What the runner wrote to the chip, kept when the runner is run again after an error
+    registers: Registers,
+    /// Set by [`Mcp23017::split`], so the runner writes the power-on values to the chip before
+    /// its first pass
+    reset_pending: bool,
+}
+
+/// A reset pin that does nothing. [`Mcp23017::new_mcp23016`] uses it, because the MCP23016
+/// doesn't have a reset pin.
+pub struct NoResetPin;
+
+impl ErrorType for NoResetPin {
+    type Error = Infallible;
+}
+
+impl OutputPin for NoResetPin {
+    async fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 pub struct Mcp23017<I2c, ResetPin, InterruptPin, Delay> {
@@ -219,45 +386,114 @@ impl<'a> InitialPins<'a> {
 impl<I2c: embedded_hal_async::i2c::I2c, ResetPin: OutputPin, InterruptPin: Wait, Delay: DelayNs>
     Mcp23017<I2c, ResetPin, InterruptPin, Delay>
 {
-    pub fn new(
+    /// This is a `const fn`, so the [`Mcp23017`] can be created at compile time,
+    /// for example in a `static`.
+    ///
+    /// The runner pulses `reset_pin` before its first pass after every [`Self::split`]. If the
+    /// `RESET` line is shared with other chips (see [`reset_all_on_bus`]), pass a pin that does
+    /// nothing, like [`NoResetPin`].
+    pub const fn new(
         i2c: I2c,
         address_lower_bits: [bool; 3],
         reset_pin: ResetPin,
         interrupt_pin: InterruptPin,
         delay: Delay,
+    ) -> Self {
+        Self::new_device(
+            i2c,
+            address_lower_bits,
+            reset_pin,
+            interrupt_pin,
+            delay,
+            Device::Mcp23017,
+        )
+    }
+
+    const fn new_device(
+        i2c: I2c,
+        address_lower_bits: [bool; 3],
+        reset_pin: ResetPin,
+        interrupt_pin: InterruptPin,
+        delay: Delay,
+        device: Device,
     ) -> Self {
         Self {
-            immutable: Mcp23017Immutable {
-                pins: array::from_fn(|_| Default::default()),
-            },
+            immutable: Mcp23017Immutable::new(),
             mutable: Mcp23017Mutable {
                 i2c,
                 address_lower_bits,
                 reset_pin,
                 interrupt_pin,
                 delay,
+                device,
+                registers: Registers::new(),
+                reset_pending: false,
             },
         }
     }
 
     /// Get a runner future and access to pins.
     /// The runner must be polled basically for the lifetime of the pins.
-    /// Currently, all errors will result in the error future being `Poll::Ready(Err(error)))`,
-    /// and the only way to recover from the error is to call `run` again.
+    /// I2C errors are retried with a backoff (see [`Control::set_retry_policy`]) and reported
+    /// with [`Control::receive_error`]. If the retries run out, or for any other error, the
+    /// future is `Poll::Ready(Err(error)))`, and the only way to recover is to call `run` again.
+    /// Before returning an error, the runner tries to set output pins to their failsafe state
+    /// (see [`Pin::set_failsafe_state`]). Dropping the future can't write them, so stop the runner
+    /// with [`Control::write_failsafe`] instead, which makes it return `Ok(())`.
     ///
     /// If you need to recover from errors and the API is too, inconvenient, create an issue.
     pub fn run(
         &mut self,
     ) -> (
-        impl Future<Output = Result<(), RunError<ResetPin::Error, InterruptPin::Error, I2c::Error>>>,
+        impl Future<Output = RunResult<I2c, ResetPin, InterruptPin>>,
+        InitialPins<'_>,
+    ) {
+        let (mut runner, pins) = self.split();
+        (async move { runner.run().await }, pins)
+    }
+
+    /// Same as [`Self::run`], but the runner is returned as a [`Runner`] instead of a future.
+    /// Unlike the future, [`Runner`] can be named, so it can be passed to a task.
+    ///
+    /// Every pin is an input again, so before its first pass, the runner writes the power-on
+    /// values to every register. The chip could still have what an older runner wrote, including
+    /// bytes of a write that failed.
+    pub fn split(
+        &mut self,
+    ) -> (
+        Runner<'_, I2c, ResetPin, InterruptPin, Delay>,
         InitialPins<'_>,
     ) {
-        self.immutable.pins = array::from_fn(|_| Default::default());
+        self.immutable = Mcp23017Immutable::new();
+        self.mutable.reset_pending = true;
         (
-            run(&mut self.mutable, &self.immutable),
-            InitialPins::new(array::from_fn(|index| {
-                Pin::new(&self.immutable.pins[index])
-            })),
+            Runner {
+                mutable: &mut self.mutable,
+                immutable: &self.immutable,
+            },
+            InitialPins::new(array::from_fn(|index| Pin::new(&self.immutable, index))),
+        )
+    }
+}
+
+impl<I2c: embedded_hal_async::i2c::I2c, InterruptPin: Wait, Delay: DelayNs>
+    Mcp23017<I2c, NoResetPin, InterruptPin, Delay>
+{
+    /// Same as [`Mcp23017::new`], but drives the older MCP23016 (see [`Device::Mcp23016`]),
+    /// which has no reset pin. Its I2C bus can't be faster than 400 kHz.
+    pub const fn new_mcp23016(
+        i2c: I2c,
+        address_lower_bits: [bool; 3],
+        interrupt_pin: InterruptPin,
+        delay: Delay,
+    ) -> Self {
+        Self::new_device(
+            i2c,
+            address_lower_bits,
+            NoResetPin,
+            interrupt_pin,
+            delay,
+            Device::Mcp23016,
         )
     }
 }
@@ -0,0 +1,156 @@
+use crate::*;
+use mcp23017_common::N_GPIO_PINS_PER_SET;
+
+const IOCON_BANK: u8 = 1 << 7;
+
+macro_rules! registers {
+    ($($_type:ident => $read:ident $(, $write:ident)?;)*) => {
+        $(
+            #[doc = concat!("Reads `", stringify!($_type), "` for set A or B.")]
+            pub async fn $read(&mut self, ab: AB) -> Result<u8, I2c::Error> {
+                self.read_register(Register {
+                    _type: RegisterType::$_type,
+                    ab,
+                })
+                .await
+            }
+
+            $(
+                #[doc = concat!("Writes `", stringify!($_type), "` for set A or B.")]
+                pub async fn $write(&mut self, ab: AB, value: u8) -> Result<(), I2c::Error> {
+                    self.write_register(
+                        Register {
+                            _type: RegisterType::$_type,
+                            ab,
+                        },
+                        value,
+                    )
+                    .await
+                }
+            )?
+        )*
+    };
+}
+
+/// A low level driver which directly reads and writes registers.
+/// There is no runner and no pins. Every method does its I2C transactions when it is called,
+/// and nothing else. Use this if you want full control over the I2C traffic
+/// and don't need to share the chip between tasks.
+pub struct Mcp23017Ll<I2c> {
+    i2c: I2c,
+    address: u8,
+    /// IOCON.BANK, which changes the register addresses
+    bank_mode: bool,
+}
+
+impl<I2c: embedded_hal_async::i2c::I2c> Mcp23017Ll<I2c> {
+    /// This does not do any I2C transactions.
+    /// The chip is assumed to be in its reset state (`IOCON.BANK = 0`).
+    pub fn new(i2c: I2c, address_lower_bits: [bool; 3]) -> Self {
+        Self {
+            i2c,
+            address: address(address_lower_bits),
+            bank_mode: false,
+        }
+    }
+
+    pub fn into_i2c(self) -> I2c {
+        self.i2c
+    }
+
+    pub async fn read_register(&mut self, register: Register) -> Result<u8, I2c::Error> {
+        let mut buffer = [Default::default(); 1];
+        self.i2c
+            .write_read(
+                self.address,
+                &[register.address(self.bank_mode)],
+                &mut buffer,
+            )
+            .await?;
+        Ok(buffer[0])
+    }
+
+    /// Writing to `IOCON` updates the bank mode used to calculate register addresses.
+    pub async fn write_register(
+        &mut self,
+        register: Register,
+        value: u8,
+    ) -> Result<(), I2c::Error> {
+        self.i2c
+            .write(self.address, &[register.address(self.bank_mode), value])
+            .await?;
+        if register._type == RegisterType::IOCON {
+            self.bank_mode = value & IOCON_BANK != 0;
+        }
+        Ok(())
+    }
+
+    registers! {
+        IODIR => read_iodir, write_iodir;
+        IPOL => read_ipol, write_ipol;
+        GPINTEN => read_gpinten, write_gpinten;
+        DEFVAL => read_defval, write_defval;
+        INTCON => read_intcon, write_intcon;
+        IOCON => read_iocon, write_iocon;
+        GPPU => read_gppu, write_gppu;
+        // These are read-only
+        INTF => read_intf;
+        INTCAP => read_intcap;
+        GPIO => read_gpio, write_gpio;
+        OLAT => read_olat, write_olat;
+    }
+
+    /// Read-modify-write a single bit of a register
+    async fn update_bit(
+        &mut self,
+        _type: RegisterType,
+        pin_index: usize,
+        value: bool,
+    ) -> Result<(), I2c::Error> {
+        let register = Register {
+            _type,
+            ab: AB::from_index(pin_index),
+        };
+        let mask = 1 << (pin_index % N_GPIO_PINS_PER_SET);
+        let previous_value = self.read_register(register).await?;
+        let new_value = if value {
+            previous_value | mask
+        } else {
+            previous_value & !mask
+        };
+        if new_value != previous_value {
+            self.write_register(register, new_value).await?;
+        }
+        Ok(())
+    }
+
+    /// Configures the direction and pull-up of a pin, without changing the other pins.
+    /// `pin_index` is `0..8` for `A0..A7` and `8..16` for `B0..B7`.
+    pub async fn configure_pin(
+        &mut self,
+        pin_index: usize,
+        io_direction: IoDirection,
+        pull_up_enabled: bool,
+    ) -> Result<(), I2c::Error> {
+        self.update_bit(RegisterType::GPPU, pin_index, pull_up_enabled)
+            .await?;
+        self.update_bit(RegisterType::IODIR, pin_index, io_direction.into())
+            .await
+    }
+
+    /// Sets the output latch of a pin, without changing the other pins.
+    pub async fn set_pin_state(
+        &mut self,
+        pin_index: usize,
+        state: PinState,
+    ) -> Result<(), I2c::Error> {
+        self.update_bit(RegisterType::OLAT, pin_index, state.into())
+            .await
+    }
+
+    /// Reads the current level of a pin.
+    pub async fn pin_state(&mut self, pin_index: usize) -> Result<PinState, I2c::Error> {
+        let value = self.read_gpio(AB::from_index(pin_index)).await?;
+        Ok((value & (1 << (pin_index % N_GPIO_PINS_PER_SET)) != 0).into())
+    }
+}
@@ -1,13 +1,21 @@
 /// Use this mode for **infrequently** reading the pin state.
 /// If you are using a `wait_` method in a loop, then you will have better performance
 /// (less i2c traffic) using [`Watch`] mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Input;
 
 /// Use this mode whenever you need to use the pin as an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Output;
 
 /// Use this mode if you need to constantly read the latest value,
 /// using interrupts to notify when the pin changes.
 /// The runner will keep interrupts always enabled for this pin, and keep
 /// internally updating the last known state of the pin.
+/// [`Wait`](embedded_hal_async::digital::Wait) is implemented with the last known state,
+/// so cascaded chips can use a watched pin as their interrupt pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Watch;
@@ -1,27 +1,49 @@
 use crate::*;
 
 impl Pin<'_, mode::Output> {
+    /// Set the state that the runner will try to set this pin to before returning an error,
+    /// so that things like heaters and motors don't stay on. `None` leaves the pin as it is.
+    ///
+    /// This is best-effort. If the I2C bus is what failed, the write will probably fail too.
+    /// Nothing is written if the runner future is dropped, so use [`Control::write_failsafe`] to
+    /// stop the runner.
+    /// The failsafe state is cleared when the pin is converted to a different mode.
+    pub fn set_failsafe_state(&mut self, state: Option<PinState>) {
+        self.s().failsafe_state.set(state);
+    }
+
     async fn set_state(&mut self, state: PinState) {
         self.update_op(Op::Output { latch: state }).await;
     }
 
     async fn is_set_state(&mut self, state: PinState) -> bool {
-        let set_state = loop {
-            {
-                let request = self.s.request.read().await;
-                if request.state == RequestState::Done {
-                    break match request.op {
-                        Op::Output { latch } => latch,
-                        _ => unreachable!(),
-                    };
-                }
-            }
-            self.s.response_signal.wait().await;
-        };
+        let set_state = self
+            .wait_for_request(|request| match request {
+                Request {
+                    op: Op::Output { latch },
+                    state: RequestState::Done,
+                } => Some(latch),
+                _ => None,
+            })
+            .await;
         set_state == state
     }
 }
 
+/// Sets the states of multiple output pins.
+/// All of the pins are requested before waiting, so the runner writes them in the same pass.
+pub(crate) async fn set_output_states(
+    pins: &[Pin<'_, mode::Output>],
+    state: impl Fn(usize) -> PinState,
+) {
+    for (i, pin) in pins.iter().enumerate() {
+        pin.request_op(Op::Output { latch: state(i) });
+    }
+    for pin in pins {
+        pin.wait_until_done().await;
+    }
+}
+
 impl OutputPin for Pin<'_, mode::Output> {
     async fn set_low(&mut self) -> Result<(), Self::Error> {
         self.set_state(PinState::Low).await;
@@ -1,43 +1,143 @@
+use core::{fmt, future::poll_fn, task::Poll};
+
+use mcp23017_common::N_GPIO_PINS_PER_SET;
+
 use crate::*;
 
+/// Which pin of the chip a [`Pin`] is. Formats like `B3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PinId {
+    pub port: AB,
+    /// `0` to `7`
+    pub number: u8,
+}
+
+impl PinId {
+    /// `0` to `15`, where `A0` is `0` and `B0` is `8`.
+    /// This is the bit of the pin in masks, like [`Registers`].
+    pub fn index(&self) -> usize {
+        self.port.starting_index() + self.number as usize
+    }
+}
+
+impl fmt::Display for PinId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}{}", self.port, self.number)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PinId {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}{}", self.port, self.number)
+    }
+}
+
+/// A pin of the [`Mcp23017`], in a [`mode`].
+///
+/// Each pin has a single request, and the methods that use it take `&mut self` or `self`,
+/// so a pin only has one request at a time. If a future is dropped before its request is done,
+/// for example in a `select`, the next request replaces it:
+/// - For output states and mode changes, the latest request wins. The runner writes the newest
+///   state, and the new future waits until it is written, even if the dropped request was the same.
+/// - For reads on input pins, a read that the runner is already doing is finished, and the new
+///   request is queued behind it. Only one request is queued, so a newer one replaces it. A read
+///   never returns a value that was read before it was requested.
+/// - For waits on input pins, the dropped request is discarded and the new one starts over,
+///   because a wait can take forever.
 pub struct Pin<'a, Mode> {
-    pub(crate) s: &'a Mcp23017ImmutablePin,
+    pub(crate) immutable: &'a Mcp23017Immutable,
+    pub(crate) index: u8,
     pub(crate) _mode: Mode,
 }
 
-impl<Mode> Pin<'_, Mode> {
+impl<'a, Mode> Pin<'a, Mode> {
+    pub fn id(&self) -> PinId {
+        PinId {
+            port: self.port(),
+            number: self.index % N_GPIO_PINS_PER_SET as u8,
+        }
+    }
+
+    pub fn port(&self) -> AB {
+        AB::from_index(self.index as usize)
+    }
+
+    pub(crate) fn s(&self) -> &'a Mcp23017ImmutablePin {
+        &self.immutable.pins[self.index as usize]
+    }
+
+    pub(crate) fn into_mode<NewMode>(self, mode: NewMode) -> Pin<'a, NewMode> {
+        Pin {
+            immutable: self.immutable,
+            index: self.index,
+            _mode: mode,
+        }
+    }
+
+    /// Also waits if the op didn't change, because the same op could have been requested by a
+    /// future that was dropped before it was done
     pub(crate) async fn update_op(&self, new_op: Op) {
-        {
-            let mut request = self.s.request.write().await;
-            if &request.op == &new_op {
-                return;
+        self.request_op(new_op);
+        self.wait_until_done().await;
+    }
+
+    /// Requests the op without waiting for it to be done.
+    /// Returns `false` if the op is already the current op.
+    /// Requesting multiple pins before waiting lets the runner process them in the same pass.
+    pub(crate) fn request_op(&self, new_op: Op) -> bool {
+        let changed = self.s().request.lock(|request| {
+            if request.op == new_op {
+                return false;
             }
             request.op = new_op;
             request.state = RequestState::Requested;
+            self.s().queued_input_op.set(None);
             #[cfg(feature = "defmt")]
             defmt::trace!("pin signaling request: {}", defmt::Debug2Format(&request));
-            self.s.request_signal.signal(());
+            true
+        });
+        if changed {
+            self.notify_runner();
         }
-        loop {
-            {
-                let request = self.s.request.read().await;
-                if request.state == RequestState::Done {
-                    break;
-                }
+        changed
+    }
+
+    /// Lets the runner know that this pin has a new request
+    pub(crate) fn notify_runner(&self) {
+        self.immutable
+            .new_requests
+            .lock(|new_requests| *new_requests |= 1 << self.index);
+        self.immutable.runner_waker.wake();
+    }
+
+    /// Waits until `f` returns `Some` for the current request
+    pub(crate) async fn wait_for_request<R>(&self, f: impl Fn(Request) -> Option<R>) -> R {
+        poll_fn(|cx| {
+            self.s().waker.register(cx.waker());
+            match f(self.s().request.get()) {
+                Some(r) => Poll::Ready(r),
+                None => Poll::Pending,
             }
-            #[cfg(feature = "defmt")]
-            defmt::trace!("pin waiting for response signal");
-            self.s.response_signal.wait().await;
-            #[cfg(feature = "defmt")]
-            defmt::trace!("pin received response signal");
-        }
+        })
+        .await
+    }
+
+    pub(crate) async fn wait_until_done(&self) {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("pin waiting for request to be done");
+        self.wait_for_request(|request| (request.state == RequestState::Done).then_some(()))
+            .await;
+        #[cfg(feature = "defmt")]
+        defmt::trace!("pin request done");
     }
 }
 
 impl<'a> Pin<'a, mode::Input> {
-    pub(crate) fn new(s: &'a Mcp23017ImmutablePin) -> Self {
+    pub(crate) fn new(immutable: &'a Mcp23017Immutable, index: usize) -> Self {
         Self {
-            s,
+            immutable,
+            index: index as u8,
             _mode: mode::Input,
         }
     }
@@ -45,65 +145,115 @@ impl<'a> Pin<'a, mode::Input> {
 
 impl<'a, Mode> Pin<'a, Mode> {
     pub async fn into_output(self, initial_value: PinState) -> Pin<'a, mode::Output> {
-        self.update_op(Op::Output {
-            latch: initial_value,
-        })
-        .await;
-        Pin {
-            s: self.s,
-            _mode: mode::Output,
-        }
+        let pin = self.request_output(initial_value);
+        pin.wait_until_done().await;
+        pin
     }
 
     pub async fn into_input(self, pull_up_enabled: bool) -> Pin<'a, mode::Input> {
-        self.update_op(Op::Input {
+        let pin = self.request_input(pull_up_enabled);
+        pin.wait_until_done().await;
+        pin
+    }
+
+    /// The runner services the interrupts of high priority pins at the start of a pass,
+    /// before writing any registers, so that their latency doesn't depend on what other pins
+    /// are doing. Use this for things like an emergency stop input.
+    /// This only matters for input and watched pins. It stays set when the mode changes.
+    ///
+    /// In the worst case, the interrupt happens just after the runner read `INTF`,
+    /// so the runner has to finish that pass first. That is at most about 56 bytes on the I2C bus
+    /// (around 1.3 ms at 400 kHz and 5 ms at 100 kHz), plus the time it takes for your executor
+    /// to poll the runner and the task waiting on the pin.
+    pub fn set_high_priority(&mut self, high_priority: bool) {
+        let mask = 1 << self.index;
+        self.immutable.high_priority.lock(|high_priority_pins| {
+            if high_priority {
+                *high_priority_pins |= mask;
+            } else {
+                *high_priority_pins &= !mask;
+            }
+        });
+    }
+
+    pub async fn into_watch(self, pull_up_enabled: bool) -> Pin<'a, mode::Watch> {
+        let pin = self.request_watch(pull_up_enabled);
+        pin.wait_until_done().await;
+        pin
+    }
+
+    /// Changes the mode without waiting for the runner
+    pub(crate) fn request_output(self, initial_value: PinState) -> Pin<'a, mode::Output> {
+        self.request_op(Op::Output {
+            latch: initial_value,
+        });
+        self.into_mode(mode::Output)
+    }
+
+    /// Changes the mode without waiting for the runner
+    pub(crate) fn request_input(self, pull_up_enabled: bool) -> Pin<'a, mode::Input> {
+        self.s().failsafe_state.set(None);
+        self.request_op(Op::Input {
             pull_up_enabled,
             op: None,
-        })
-        .await;
-        Pin {
-            s: self.s,
-            _mode: mode::Input,
-        }
+        });
+        self.into_mode(mode::Input)
     }
 
-    pub async fn into_watch(self, pull_up_enabled: bool) -> Pin<'a, mode::Watch> {
-        let new_op = Op::Watch {
+    /// Changes the mode without waiting for the runner
+    pub(crate) fn request_watch(self, pull_up_enabled: bool) -> Pin<'a, mode::Watch> {
+        self.s().failsafe_state.set(None);
+        self.s().stats.set(PinStats::new());
+        self.request_op(Op::Watch {
             pull_up_enabled,
             last_known_value: None,
-        };
-        {
-            let mut request = self.s.request.write().await;
-            if &request.op != &new_op {
-                request.op = new_op;
-                request.state = RequestState::Requested;
-                self.s.request_signal.signal(());
-            }
-        }
-        loop {
-            {
-                let request = self.s.request.read().await;
-                match request.op {
-                    Op::Watch {
-                        pull_up_enabled: _,
-                        last_known_value,
-                    } => {
-                        if last_known_value.is_some() {
-                            break;
-                        }
-                    }
-                    _ => {}
-                };
-            }
-            self.s.response_signal.wait().await;
-        }
-        Pin {
-            s: self.s,
-            _mode: mode::Watch,
+        });
+        self.into_mode(mode::Watch)
+    }
+}
+
+impl<'a, Mode> Pin<'a, Mode> {
+    /// Makes the runner put the pin in a safe state: an input without a pull-up and with
+    /// interrupts disabled. This doesn't wait for the runner.
+    /// Use this for pins that are only sometimes used, for example by a module that can be
+    /// unplugged. The pin is not high priority anymore.
+    pub fn release(mut self) -> ReleasedPin<'a> {
+        self.set_high_priority(false);
+        ReleasedPin {
+            pin: self.request_input(false),
         }
     }
 }
 
+/// A pin that was released with [`Pin::release`]. It can be kept, or passed to a different task,
+/// until the pin is needed again.
+pub struct ReleasedPin<'a> {
+    pin: Pin<'a, mode::Input>,
+}
+
+impl<'a> ReleasedPin<'a> {
+    /// Waits until the pin is in the safe state, and returns it so that it can be put in a new
+    /// mode
+    pub async fn reclaim(self) -> Pin<'a, mode::Input> {
+        self.pin.wait_until_done().await;
+        self.pin
+    }
+}
+
+/// Formats like `Pin(B3, Output)`
+impl<Mode: fmt::Debug> fmt::Debug for Pin<'_, Mode> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pin({}, {:?})", self.id(), self._mode)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<Mode: defmt::Format> defmt::Format for Pin<'_, Mode> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Pin({}, {})", self.id(), self._mode)
+    }
+}
+
 impl<Mode> ErrorType for Pin<'_, Mode> {
     type Error = Infallible;
 }
@@ -0,0 +1,76 @@
+use crate::*;
+
+/// Lets the runner use an interrupt pin that implements [`InputPin`] but not [`Wait`],
+/// for example a GPIO without an EXTI channel. The level is read every `interval_us`.
+/// Pass it to [`Mcp23017::new`] as the interrupt pin.
+///
+/// The MCP23017 keeps its interrupt output active until the runner reads `INTCAP`, so no interrupt
+/// is missed. The interval only adds up to `interval_us` of latency, in exchange for reading the
+/// pin that often while the runner is idle.
+/// The edge methods of [`Wait`] can miss pulses shorter than the interval, but the runner doesn't
+/// use them.
+pub struct PolledInterruptPin<Pin, Delay> {
+    pin: Pin,
+    delay: Delay,
+    interval_us: u32,
+}
+
+impl<Pin: InputPin, Delay: DelayNs> PolledInterruptPin<Pin, Delay> {
+    pub const fn new(pin: Pin, delay: Delay, interval_us: u32) -> Self {
+        Self {
+            pin,
+            delay,
+            interval_us,
+        }
+    }
+
+    pub fn set_interval(&mut self, interval_us: u32) {
+        self.interval_us = interval_us;
+    }
+
+    pub fn into_inner(self) -> (Pin, Delay) {
+        (self.pin, self.delay)
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) -> Result<(), Pin::Error> {
+        loop {
+            let is_level = match level {
+                PinState::Low => self.pin.is_low().await?,
+                PinState::High => self.pin.is_high().await?,
+            };
+            if is_level {
+                return Ok(());
+            }
+            self.delay.delay_us(self.interval_us).await;
+        }
+    }
+}
+
+impl<Pin: ErrorType, Delay> ErrorType for PolledInterruptPin<Pin, Delay> {
+    type Error = Pin::Error;
+}
+
+impl<Pin: InputPin, Delay: DelayNs> Wait for PolledInterruptPin<Pin, Delay> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(PinState::High).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(PinState::Low).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(PinState::Low).await?;
+        self.wait_for_level(PinState::High).await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(PinState::High).await?;
+        self.wait_for_level(PinState::Low).await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        let level = PinState::from(self.pin.is_high().await?);
+        self.wait_for_level(!level).await
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{output::set_output_states, *};
+
+/// Up to 16 output pins that are changed together with bit masks, for things like parallel buses
+/// where data and strobe pins have to change without disturbing each other.
+/// Bit `i` of a mask is the `i`th pin of the port.
+///
+/// Every change is written in a single runner pass. The runner keeps the value of `OLAT`,
+/// so this is one write of `OLAT` without reading it from the chip.
+pub struct OutputPort<'a, const N: usize> {
+    pins: [Pin<'a, mode::Output>; N],
+}
+
+impl<'a, const N: usize> OutputPort<'a, N> {
+    pub fn new(pins: [Pin<'a, mode::Output>; N]) -> Self {
+        const {
+            assert!(N <= u16::BITS as usize, "a port can have at most 16 pins");
+        }
+        Self { pins }
+    }
+
+    pub fn into_pins(self) -> [Pin<'a, mode::Output>; N] {
+        self.pins
+    }
+
+    /// The states that the pins are set to
+    pub fn states(&self) -> u16 {
+        pin_mask(|i| {
+            i < N
+                && matches!(
+                    self.pins[i].s().request.get().op,
+                    Op::Output {
+                        latch: PinState::High
+                    }
+                )
+        })
+    }
+
+    /// Sets the state of every pin
+    pub async fn write(&mut self, states: u16) {
+        set_output_states(&self.pins, |i| bit(states, i).into()).await;
+    }
+
+    /// Sets the pins in `mask` high, leaving the other pins as they are
+    pub async fn set_bits(&mut self, mask: u16) {
+        self.write(self.states() | mask).await;
+    }
+
+    /// Sets the pins in `mask` low, leaving the other pins as they are
+    pub async fn clear_bits(&mut self, mask: u16) {
+        self.write(self.states() & !mask).await;
+    }
+
+    /// Toggles the pins in `mask`, leaving the other pins as they are
+    pub async fn toggle_bits(&mut self, mask: u16) {
+        self.write(self.states() ^ mask).await;
+    }
+}
@@ -0,0 +1,72 @@
+use embedded_hal::digital::ErrorType;
+
+use crate::*;
+
+impl<I2c: embedded_hal_async::i2c::I2c, ResetPin: OutputPin, InterruptPin: Wait, Delay: DelayNs>
+    Runner<'_, I2c, ResetPin, InterruptPin, Delay>
+{
+    /// Same as [`Self::run`], but when the I2C retries run out (see [`Control::set_retry_policy`])
+    /// or a transaction times out (see `Control::set_i2c_timeout`), `recover` is called with the
+    /// I2C bus, and if it returns `true`, the runner starts again.
+    /// Starting again writes `IOCON` and then continues with the requests that were not done.
+    ///
+    /// `recover` can re-initialize the I2C peripheral, power-cycle a level shifter, or take the
+    /// pins from the peripheral and use [`recover_bus`]. Return `false` to give up and return the
+    /// error.
+    pub async fn run_with_recovery(
+        &mut self,
+        mut recover: impl AsyncFnMut(&mut I2c) -> bool,
+    ) -> Result<(), RunError<ResetPin::Error, InterruptPin::Error, I2c::Error>> {
+        loop {
+            match self.run().await {
+                Err(error @ (RunError::I2c(_) | RunError::Timeout)) => {
+                    #[cfg(feature = "defmt")]
+                    defmt::warn!("recovering I2C bus");
+                    if !recover(&mut self.mutable.i2c).await {
+                        return Err(error);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RecoverBusError<SclError, SdaError> {
+    Scl(SclError),
+    Sda(SdaError),
+}
+
+/// Unsticks an I2C bus where a target is holding `SDA` low because it was interrupted in the
+/// middle of a byte, with the standard sequence of up to 9 clock pulses and then a STOP condition.
+/// Returns whether `SDA` is released.
+///
+/// Both pins need pull-ups, and must be open drain or switch between driving low and floating.
+/// The I2C peripheral must not be using them. The bus is clocked at about 100 kHz.
+pub async fn recover_bus<Scl: OutputPin, Sda: InputPin + OutputPin>(
+    scl: &mut Scl,
+    sda: &mut Sda,
+    delay: &mut impl DelayNs,
+) -> Result<bool, RecoverBusError<Scl::Error, <Sda as ErrorType>::Error>> {
+    sda.set_high().await.map_err(RecoverBusError::Sda)?;
+    for _ in 0..9 {
+        if sda.is_high().await.map_err(RecoverBusError::Sda)? {
+            break;
+        }
+        scl.set_low().await.map_err(RecoverBusError::Scl)?;
+        delay.delay_us(5).await;
+        scl.set_high().await.map_err(RecoverBusError::Scl)?;
+        delay.delay_us(5).await;
+    }
+    // STOP is SDA going high while SCL is high
+    scl.set_low().await.map_err(RecoverBusError::Scl)?;
+    delay.delay_us(5).await;
+    sda.set_low().await.map_err(RecoverBusError::Sda)?;
+    delay.delay_us(5).await;
+    scl.set_high().await.map_err(RecoverBusError::Scl)?;
+    delay.delay_us(5).await;
+    sda.set_high().await.map_err(RecoverBusError::Sda)?;
+    delay.delay_us(5).await;
+    sda.is_high().await.map_err(RecoverBusError::Sda)
+}
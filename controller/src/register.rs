@@ -1,44 +1,43 @@
 use crate::*;
-use mcp23017_common::{AB::*, N_GPIO_PINS_PER_SET, N_TOTAL_GPIO_PINS, Register, RegisterType};
+use mcp23017_common::{AB::*, Register, RegisterType};
 
-/// Writes to A, B, both, or none,depending on the values that are `Some`.
+/// Writes to A, B, both, or none, depending on which bytes are different.
+/// Bits `0..8` are A and bits `8..16` are B. Both chips continue from A to B when writing more
+/// than one byte.
 pub async fn write_registers<I2c: embedded_hal_async::i2c::I2c>(
     i2c: &mut I2c,
     i2c_address: u8,
+    device: Device,
     register: RegisterType,
-    current_values: [bool; N_TOTAL_GPIO_PINS],
-    new_values: [bool; N_TOTAL_GPIO_PINS],
+    current_value: u16,
+    new_value: u16,
 ) -> Result<(), I2c::Error> {
-    let write_a = &current_values[A.range()] != &new_values[A.range()];
-    let write_b = &current_values[B.range()] != &new_values[B.range()];
-    let write_count = write_a as usize + write_b as usize;
+    let [current_a_byte, current_b_byte] = current_value.to_le_bytes();
+    let [new_a_byte, new_b_byte] = new_value.to_le_bytes();
+    let write_a = current_a_byte != new_a_byte;
+    let write_b = current_b_byte != new_b_byte;
 
-    if write_count > 0 {
-        let new_a_byte = u8::from_bits_le(new_values[A.range()].try_into().unwrap());
-        let new_b_byte = u8::from_bits_le(new_values[B.range()].try_into().unwrap());
+    if write_a || write_b {
         let buffer: Vec<_, 3> = match (write_a, write_b) {
             (true, false) => Vec::from_slice(&[
-                Register {
+                device.register_address(Register {
                     _type: register,
                     ab: A,
-                }
-                .address(false),
+                }),
                 new_a_byte,
             ]),
             (false, true) => Vec::from_slice(&[
-                Register {
+                device.register_address(Register {
                     _type: register,
                     ab: B,
-                }
-                .address(false),
+                }),
                 new_b_byte,
             ]),
             (true, true) => Vec::from_slice(&[
-                Register {
+                device.register_address(Register {
                     _type: register,
                     ab: A,
-                }
-                .address(false),
+                }),
                 new_a_byte,
                 new_b_byte,
             ]),
@@ -51,55 +50,65 @@ pub async fn write_registers<I2c: embedded_hal_async::i2c::I2c>(
     Ok(())
 }
 
-/// Writes all `Some` with the  read value.
+/// Reads A, B, both, or none, depending on which bytes of `mask` have bits set.
+/// Bits that are not in `mask` are `0`.
 pub async fn read_registers<I2c: embedded_hal_async::i2c::I2c>(
     i2c: &mut I2c,
     i2c_address: u8,
+    device: Device,
     register: RegisterType,
-    values: &mut [Option<bool>; N_TOTAL_GPIO_PINS],
-) -> Result<(), I2c::Error> {
-    let read_a = values[A.range()].iter().any(|value| value.is_some());
-    let read_b = values[B.range()].iter().any(|value| value.is_some());
+    mask: u16,
+) -> Result<u16, I2c::Error> {
+    let [mask_a, mask_b] = mask.to_le_bytes();
+    let read_a = mask_a != 0;
+    let read_b = mask_b != 0;
     let read_count = read_a as usize + read_b as usize;
 
-    if read_count > 0 {
-        let mut buffer = [Default::default(); 2];
-        i2c.write_read(
-            i2c_address,
-            &[Register {
-                _type: register,
-                ab: if read_a { A } else { B },
-            }
-            .address(false)],
-            &mut buffer[..read_count],
-        )
-        .await?;
-
-        let (a_byte, b_byte) = match (read_a, read_b) {
-            (true, false) => (Some(buffer[0]), None),
-            (false, true) => (None, Some(buffer[0])),
-            (true, true) => (Some(buffer[0]), Some(buffer[1])),
-            _ => unreachable!(),
-        };
-        if let Some(a_byte) = a_byte {
-            let a_values = a_byte.into_bits_le();
-            let values = &mut values[A.range()];
-            for i in 0..N_GPIO_PINS_PER_SET {
-                if let Some(value) = values[i].as_mut() {
-                    *value = a_values[i];
-                }
-            }
-        }
-        if let Some(b_byte) = b_byte {
-            let b_values = b_byte.into_bits_le();
-            let values = &mut values[B.range()];
-            for i in 0..N_GPIO_PINS_PER_SET {
-                if let Some(value) = values[i].as_mut() {
-                    *value = b_values[i];
-                }
-            }
-        }
+    if read_count == 0 {
+        return Ok(0);
     }
 
-    Ok(())
+    let mut buffer = [Default::default(); 2];
+    i2c.write_read(
+        i2c_address,
+        &[device.register_address(Register {
+            _type: register,
+            ab: if read_a { A } else { B },
+        })],
+        &mut buffer[..read_count],
+    )
+    .await?;
+
+    let bytes = match (read_a, read_b) {
+        (true, false) => [buffer[0], 0],
+        (false, true) => [0, buffer[0]],
+        (true, true) => buffer,
+        _ => unreachable!(),
+    };
+    Ok(u16::from_le_bytes(bytes) & mask)
+}
+
+/// Reads `INTF` and `INTCAP` for both ports of an MCP23017 in a single transaction.
+/// This relies on `IOCON.BANK = 0` and `IOCON.SEQOP = 0`, so that the address pointer goes from
+/// `INTFA` to `INTCAPB`. Reading `INTCAP` clears the interrupt.
+pub async fn read_interrupt_registers<I2c: embedded_hal_async::i2c::I2c>(
+    i2c: &mut I2c,
+    i2c_address: u8,
+) -> Result<(u16, u16), I2c::Error> {
+    let mut buffer = [Default::default(); 4];
+    i2c.write_read(
+        i2c_address,
+        &[Register {
+            _type: RegisterType::INTF,
+            ab: A,
+        }
+        .address(false)],
+        &mut buffer,
+    )
+    .await?;
+    let [intf_a, intf_b, intcap_a, intcap_b] = buffer;
+    Ok((
+        u16::from_le_bytes([intf_a, intf_b]),
+        u16::from_le_bytes([intcap_a, intcap_b]),
+    ))
 }
@@ -1,15 +1,92 @@
-use embassy_futures::{join::join_array, select::select_array};
+use core::{
+    future::{pending, poll_fn},
+    mem,
+    pin::Pin as FuturePin,
+    task::{Context, Poll},
+};
+
+use embassy_futures::select::{Either4, select4};
+use embedded_hal::i2c::Error as _;
 use embedded_hal_async::{
     delay::DelayNs,
     digital::{OutputPin, Wait},
+    i2c::I2c as _,
 };
 
 use crate::{
-    register::{read_registers, write_registers},
+    debounce::Debouncer,
+    device::ChangeTracker,
+    errors::{report_error, retry_backoff_ms},
+    register::{read_interrupt_registers, read_registers, write_registers},
+    sampling::Sampler,
+    soft_start::write_latches,
+    timeout::{TimeoutError, TimeoutI2c},
     *,
 };
 
-pub async fn run<
+/// Runs the [`Mcp23017`]. Get one with [`Mcp23017::split`].
+/// The future returned by [`Self::run`] can't be named without type alias `impl Trait`,
+/// which isn't stable yet, so store the `Runner` instead and call `run` where it is polled,
+/// or pin the future and wrap it in a [`RunFuture`].
+pub struct Runner<'a, I2c, ResetPin, InterruptPin, Delay> {
+    pub(crate) mutable: &'a mut Mcp23017Mutable<I2c, ResetPin, InterruptPin, Delay>,
+    pub(crate) immutable: &'a Mcp23017Immutable,
+}
+
+impl<'a, I2c: embedded_hal_async::i2c::I2c, ResetPin: OutputPin, InterruptPin: Wait, Delay: DelayNs>
+    Runner<'a, I2c, ResetPin, InterruptPin, Delay>
+{
+    /// Get a [`Control`] to change chip-wide settings while the runner is running
+    pub fn control(&self) -> Control<'a> {
+        Control {
+            immutable: self.immutable,
+        }
+    }
+
+    /// The runner must be polled basically for the lifetime of the pins.
+    /// See [`Mcp23017::run`].
+    ///
+    /// After an error, this can be called again without changing the pins, for example after
+    /// recovering the bus with [`Self::replace_i2c`]. The runner continues with the requests that
+    /// were not done. If the chip was reset too, call [`Control::reinitialize`].
+    pub async fn run(&mut self) -> RunResult<I2c, ResetPin, InterruptPin> {
+        run(self.mutable, self.immutable).await
+    }
+
+    /// Replaces the I2C bus, returning the old one.
+    /// Use this to give the runner a new bus instance while it isn't running,
+    /// for example after re-initializing the I2C peripheral to recover from a stuck bus.
+    pub fn replace_i2c(&mut self, i2c: I2c) -> I2c {
+        mem::replace(&mut self.mutable.i2c, i2c)
+    }
+}
+
+/// The future of [`Runner::run`], with a type that can be named, so it can be stored in a struct
+/// or passed to code that needs a concrete future type, without `Box`.
+/// `E` is the [`RunError`] of the runner.
+///
+/// The future itself still has to be stored somewhere, so pin it first, for example with
+/// `RunFuture::new(core::pin::pin!(runner.run()))`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RunFuture<'f, E> {
+    future: FuturePin<&'f mut (dyn Future<Output = Result<(), E>> + 'f)>,
+}
+
+impl<'f, E> RunFuture<'f, E> {
+    pub fn new(future: FuturePin<&'f mut (impl Future<Output = Result<(), E>> + 'f)>) -> Self {
+        Self { future }
+    }
+}
+
+impl<E> Future for RunFuture<'_, E> {
+    type Output = Result<(), E>;
+
+    fn poll(mut self: FuturePin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+async fn run<
     I2c: embedded_hal_async::i2c::I2c,
     ResetPin: OutputPin,
     InterruptPin: Wait,
@@ -18,247 +95,965 @@ pub async fn run<
     mutable: &mut Mcp23017Mutable<I2c, ResetPin, InterruptPin, Delay>,
     immutable: &Mcp23017Immutable,
 ) -> Result<(), RunError<ResetPin::Error, InterruptPin::Error, I2c::Error>> {
-    // mutable
-    //     .reset_pin
-    //     .set_low()
-    //     .await
-    //     .map_err(RunError::ResetPin)?;
-    // mutable.delay.delay_us(1).await;
-    // mutable
-    //     .reset_pin
-    //     .set_high()
-    //     .await
-    //     .map_err(RunError::ResetPin)?;
+    // The number of I2C errors in a row
+    let mut attempt = 0;
+    let result = loop {
+        let result = run_with_registers(mutable, immutable, &mut attempt)
+            .await
+            .map_err(|error| match error {
+                RunError::ResetPin(error) => RunError::ResetPin(error),
+                RunError::InterruptPin(error) => RunError::InterruptPin(error),
+                RunError::I2c(TimeoutError::I2c(error)) => RunError::I2c(error),
+                RunError::I2c(TimeoutError::Timeout) | RunError::Timeout => RunError::Timeout,
+            });
+        // The register cache is only updated after a successful write, so it's still correct
+        // for the bytes that the next pass compares, and requests that were not done are still
+        // processing, so starting over continues where the error happened
+        if let Err(RunError::I2c(error)) = &result {
+            attempt += 1;
+            if let Some(backoff_ms) = retry_backoff_ms(immutable, attempt) {
+                report_error(
+                    immutable,
+                    NonFatalError::I2c {
+                        kind: error.kind(),
+                        attempt,
+                        backoff_ms,
+                    },
+                );
+                mutable.delay.delay_ms(backoff_ms).await;
+                continue;
+            }
+        }
+        break result;
+    };
+    if result.is_err() {
+        write_failsafe_states(
+            &mut TimeoutI2c::new(&mut mutable.i2c, immutable),
+            address(mutable.address_lower_bits),
+            mutable.device,
+            immutable,
+            &mut mutable.registers,
+        )
+        .await;
+    }
+    result
+}
 
-    // Configure IOCON
-    let address = address(mutable.address_lower_bits);
-    mutable
-        .i2c
-        .write(
-            address,
+/// Best-effort attempt to set output pins that have a failsafe state to that state
+async fn write_failsafe_states<I2c: embedded_hal_async::i2c::I2c>(
+    i2c: &mut I2c,
+    i2c_address: u8,
+    device: Device,
+    immutable: &Mcp23017Immutable,
+    registers: &mut Registers,
+) {
+    let failsafe_states = array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| {
+        immutable.pins[i]
+            .failsafe_state
+            .get()
+            .filter(|_| !bit(registers.io_dir, i))
+    });
+    let has_failsafe_state = pin_mask(|i| failsafe_states[i].is_some());
+    if has_failsafe_state != 0 {
+        let failsafe_latches = pin_mask(|i| failsafe_states[i] == Some(PinState::High));
+        let new_latches = (registers.latch & !has_failsafe_state) | failsafe_latches;
+        // After an error, we can't be sure that the cached values are what's on the chip,
+        // so pretend that every bit is different to write both ports
+        let result = write_registers(
+            i2c,
+            i2c_address,
+            device,
+            RegisterType::OLAT,
+            !new_latches,
+            new_latches,
+        )
+        .await;
+        if result.is_ok() {
+            // So that the next run writes the requested states again
+            registers.latch = new_latches;
+        } else {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("failed to write failsafe states");
+        }
+    }
+}
+
+/// Writes the power-on value to both ports of every register that the runner configures.
+/// Interrupts are disabled first, and pins are inputs before `OLAT` is written, so outputs don't
+/// glitch.
+async fn write_power_on_values<I2c: embedded_hal_async::i2c::I2c>(
+    i2c: &mut I2c,
+    i2c_address: u8,
+    device: Device,
+) -> Result<(), I2c::Error> {
+    let power_on = Registers::new();
+    let values = [
+        (RegisterType::GPINTEN, power_on.int_enabled),
+        (RegisterType::IODIR, power_on.io_dir),
+        (RegisterType::OLAT, power_on.latch),
+        (RegisterType::GPPU, power_on.pull_up_enabled),
+        (RegisterType::IPOL, power_on.polarity_inverted),
+        (RegisterType::DEFVAL, power_on.int_compare),
+        (RegisterType::INTCON, power_on.int_control),
+    ];
+    for (register, value) in values.into_iter().filter(|&(register, _)| {
+        device.has_mcp23017_registers()
+            || matches!(
+                register,
+                RegisterType::IODIR | RegisterType::OLAT | RegisterType::IPOL
+            )
+    }) {
+        // Pretend that every bit is different to write both ports
+        write_registers(i2c, i2c_address, device, register, !value, value).await?;
+    }
+    Ok(())
+}
+
+/// Writes `IOCON` if it was not written yet or if a [`Control`] requested a change.
+/// If a [`Control`] requested reinitialization, the register cache is reset to the chip's
+/// power-on values, so that the rest of the pass writes every register that needs to be written.
+/// Returns the interrupt output that is configured now.
+async fn process_chip_request<I2c: embedded_hal_async::i2c::I2c>(
+    i2c: &mut I2c,
+    i2c_address: u8,
+    device: Device,
+    immutable: &Mcp23017Immutable,
+    registers: &mut Registers,
+    mut current_interrupt_output: Option<InterruptOutput>,
+) -> Result<InterruptOutput, I2c::Error> {
+    let request = immutable.control.request.lock(|request| {
+        if request.state == RequestState::Requested {
+            request.state = RequestState::ProcessingRequest;
+        }
+        *request
+    });
+    if request.state == RequestState::ProcessingRequest && request.reinitialize {
+        #[cfg(feature = "defmt")]
+        defmt::debug!("reinitializing");
+        *registers = Registers::default();
+        current_interrupt_output = None;
+    }
+    if current_interrupt_output != Some(request.interrupt_output) {
+        i2c.write(
+            i2c_address,
             &[
-                Register {
+                device.register_address(Register {
                     _type: RegisterType::IOCON,
                     ab: AB::A,
-                }
-                .address(false),
-                // Enable interrupt mirroring and set interrupts to open-drain
-                0b01000100,
+                }),
+                device.iocon(request.interrupt_output),
             ],
         )
-        .await
-        .map_err(RunError::I2c)?;
+        .await?;
+    }
+    if request.state == RequestState::ProcessingRequest {
+        // Only set the request to done if it was not modified since we read it
+        immutable.control.request.lock(|new_request| {
+            if *new_request == request {
+                new_request.state = RequestState::Done;
+                new_request.reinitialize = false;
+            }
+        });
+        immutable.control.request_waker.wake();
+    }
+    Ok(request.interrupt_output)
+}
+
+async fn run_with_registers<
+    I2c: embedded_hal_async::i2c::I2c,
+    ResetPin: OutputPin,
+    InterruptPin: Wait,
+    Delay: DelayNs,
+>(
+    mutable: &mut Mcp23017Mutable<I2c, ResetPin, InterruptPin, Delay>,
+    immutable: &Mcp23017Immutable,
+    attempt: &mut u32,
+) -> Result<(), RunError<ResetPin::Error, InterruptPin::Error, TimeoutError<I2c::Error>>> {
+    // The register cache is borrowed from `mutable` instead of copied, so every write is kept
+    // even if this future is dropped
+    let Mcp23017Mutable {
+        i2c,
+        address_lower_bits,
+        reset_pin,
+        interrupt_pin,
+        delay,
+        device,
+        registers,
+        reset_pending,
+    } = mutable;
+    let device = *device;
+    let mut i2c = TimeoutI2c::new(i2c, immutable);
 
-    let mut registers = [PinRegisters::default(); N_TOTAL_GPIO_PINS];
+    let address = address(*address_lower_bits);
+    if *reset_pending {
+        // The MCP23016 has no reset pin. The power-on values are written anyway, in case the
+        // reset pin isn't connected.
+        if device == Device::Mcp23017 {
+            #[cfg(feature = "defmt")]
+            defmt::debug!("resetting");
+            reset_pin.set_low().await.map_err(RunError::ResetPin)?;
+            // The minimum reset pulse width is 1 µs
+            delay.delay_us(1).await;
+            reset_pin.set_high().await.map_err(RunError::ResetPin)?;
+        }
+        #[cfg(feature = "defmt")]
+        defmt::debug!("writing power-on values");
+        write_power_on_values(&mut i2c, address, device)
+            .await
+            .map_err(RunError::I2c)?;
+        *registers = Registers::new();
+        *reset_pending = false;
+    }
+
+    // Configure IOCON
+    let mut interrupt_output =
+        process_chip_request(&mut i2c, address, device, immutable, registers, None)
+            .await
+            .map_err(RunError::I2c)?;
+
+    // The MCP23016 has no INTF, so the pins that changed are found by comparing INTCAP with the
+    // states that were read last. Reading GP also clears any interrupt that is active now.
+    let mut change_tracker = ChangeTracker::default();
+    if device == Device::Mcp23016 {
+        let gpio = read_registers(&mut i2c, address, device, RegisterType::GPIO, u16::MAX)
+            .await
+            .map_err(RunError::I2c)?;
+        change_tracker.gpio_read(gpio, 0);
+    }
+
+    // Set when a pass leaves interrupts enabled for requests that were completed in that pass,
+    // so we immediately do another pass instead of waiting for a spurious interrupt.
+    // The first pass is done right away, in case there are requests from before an error.
+    let mut rerun = true;
+    // Set when the runner was idle for the output check interval
+    let mut periodic_output_check = false;
+    // How long the runner was idle since the last pass that wasn't only for the heartbeat
+    let mut idle_ms = 0;
+    let mut sampler = Sampler::default();
+    let mut debouncer = Debouncer::default();
 
     loop {
+        let mut woken_by_interrupt = false;
         // Make sure we have something to do
-        #[cfg(feature = "defmt")]
-        defmt::trace!("Runner is idle");
-        let wake_up_source = select(
-            select_array(array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(async |i| {
-                #[cfg(feature = "defmt")]
-                defmt::trace!("pin {} waiting for request signal", i);
-                immutable.pins[i].request_signal.wait().await;
-                #[cfg(feature = "defmt")]
-                defmt::trace!("pin {} received request signal", i);
-            })),
-            mutable.interrupt_pin.wait_for_low(),
-        )
-        .await;
-        #[cfg(feature = "defmt")]
-        defmt::trace!(
-            "Runner doing something because of {}",
-            defmt::Debug2Format(&wake_up_source)
-        );
-        #[cfg(not(feature = "defmt"))]
-        let _ = wake_up_source;
-
-        // Read requests and immediately set them to processing, or done if no action is needed
-        #[cfg(feature = "defmt")]
-        defmt::trace!("reading requests");
-        let requests = join_array(array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(async |i| {
+        if rerun {
             #[cfg(feature = "defmt")]
-            defmt::trace!("acquiring request lock {}", i);
-            let mut request = immutable.pins[i].request.write().await;
+            defmt::trace!("Runner is doing another pass to clean up");
+        } else {
             #[cfg(feature = "defmt")]
-            defmt::trace!("acquired request lock {}", i);
-            let request_before = *request;
-            match request_before {
-                Request {
-                    op: Op::Output { latch },
-                    state: RequestState::Requested,
-                } => {
-                    let change_dir = registers[i].io_dir != IoDirection::Output;
-                    let change_latch = registers[i].latch != latch;
-                    if change_dir || change_latch {
-                        request.state = RequestState::ProcessingRequest;
+            defmt::trace!("Runner is idle");
+            immutable
+                .diagnostics
+                .lock(|diagnostics| diagnostics.runner_idle = true);
+            let output_check_interval = immutable.control.output_check.get().interval_ms;
+            let heartbeat = immutable.control.heartbeat.get();
+            let timeout_ms = [
+                output_check_interval.map(|interval_ms| interval_ms.saturating_sub(idle_ms)),
+                heartbeat.and_then(|heartbeat| heartbeat.interval_ms),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            sampler.update(immutable);
+            let wake_up_source = select4(
+                poll_fn(|cx| {
+                    immutable.runner_waker.register(cx.waker());
+                    let new_requests = immutable.new_requests.lock(mem::take);
+                    if new_requests != 0
+                        || immutable.control.has_request()
+                        || immutable.control.output_check.get().interval_ms != output_check_interval
+                        || immutable.control.heartbeat.get() != heartbeat
+                        || sampler.interval_changed(immutable)
+                    {
+                        Poll::Ready(new_requests)
                     } else {
-                        request.state = RequestState::Done;
+                        Poll::Pending
+                    }
+                }),
+                async {
+                    debouncer.wait(immutable, device).await;
+                    match device.interrupt_active_level(interrupt_output) {
+                        PinState::Low => interrupt_pin.wait_for_low().await,
+                        PinState::High => interrupt_pin.wait_for_high().await,
+                    }
+                },
+                async {
+                    match timeout_ms {
+                        Some(timeout_ms) => delay.delay_ms(timeout_ms).await,
+                        None => pending().await,
                     }
-                    immutable.pins[i].response_signal.signal(());
+                },
+                sampler.wait(),
+            )
+            .await;
+            #[cfg(feature = "defmt")]
+            match wake_up_source {
+                Either4::First(new_requests) => {
+                    defmt::trace!("Runner woken by requests from pins {:016b}", new_requests)
                 }
-                Request {
-                    op:
-                        Op::Watch {
-                            pull_up_enabled: _,
-                            last_known_value: _,
-                        },
-                    state: RequestState::Requested,
-                } => {
-                    request.state = RequestState::ProcessingRequest;
-                    immutable.pins[i].response_signal.signal(());
+                Either4::Second(_) => defmt::trace!("Runner woken by interrupt pin"),
+                Either4::Third(_) => defmt::trace!("Runner woken by idle timeout"),
+                Either4::Fourth(_) => defmt::trace!("Runner woken to sample GPIO"),
+            }
+            immutable
+                .diagnostics
+                .lock(|diagnostics| diagnostics.runner_idle = false);
+            woken_by_interrupt = matches!(wake_up_source, Either4::Second(_));
+            match wake_up_source {
+                Either4::Second(result) => {
+                    idle_ms = 0;
+                    debouncer.interrupt_serviced();
+                    result.map_err(RunError::InterruptPin)?
                 }
-                _ => {}
-            };
-            request_before
-        }))
-        .await;
-        #[cfg(feature = "defmt")]
-        defmt::trace!("requests: {}", defmt::Debug2Format(&requests));
+                Either4::Third(()) => {
+                    idle_ms = idle_ms.saturating_add(timeout_ms.unwrap());
+                    if output_check_interval.is_some_and(|interval_ms| idle_ms >= interval_ms) {
+                        periodic_output_check = true;
+                        idle_ms = 0;
+                    }
+                }
+                _ => idle_ms = 0,
+            }
+        }
 
-        // Update IODIR
-        let new_io_dirs = requests.map(|request| match request.op {
-            Op::Output { latch: _ } => IoDirection::Output,
-            _ => IoDirection::Input,
+        // Toggle the heartbeat pin. This changes its request without making it a new request,
+        // so the pin's task isn't involved.
+        if let Some(heartbeat) = immutable.control.heartbeat.get() {
+            immutable.pins[heartbeat.index as usize]
+                .request
+                .lock(|request| {
+                    if let Op::Output { latch } = &mut request.op {
+                        *latch = !*latch;
+                    }
+                });
+        }
+
+        // Read requests and immediately set them to processing, or done if no action is needed
+        #[cfg(feature = "defmt")]
+        defmt::trace!("reading requests");
+        let polarity_inverted = immutable.polarity_inverted.get();
+        let requests = array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| {
+            immutable.pins[i].request.lock(|request| {
+                let is_new = request.state == RequestState::Requested;
+                if is_new {
+                    request.state = match request.op {
+                        Op::Output { latch }
+                            if !bit(registers.io_dir, i)
+                                && PinState::from(bit(registers.latch, i)) == latch
+                                && bit(registers.polarity_inverted, i)
+                                    == bit(polarity_inverted, i) =>
+                        {
+                            RequestState::Done
+                        }
+                        _ => RequestState::ProcessingRequest,
+                    };
+                    immutable.wake_pin(i);
+                }
+                (*request, is_new)
+            })
         });
+        let is_new = pin_mask(|i| requests[i].1);
+
+        // Update IPOL before reading anything, so every read in this pass is already inverted
         write_registers(
-            &mut mutable.i2c,
+            &mut i2c,
             address,
-            RegisterType::IODIR,
-            registers.map(|register| register.io_dir.into()),
-            new_io_dirs.map(|io_dir| io_dir.into()),
+            device,
+            RegisterType::IPOL,
+            registers.polarity_inverted,
+            polarity_inverted,
         )
         .await
         .map_err(RunError::I2c)?;
-        for i in 0..N_TOTAL_GPIO_PINS {
-            registers[i].io_dir = new_io_dirs[i];
+        registers.polarity_inverted = polarity_inverted;
+        let mut requests = requests.map(|(request, _)| request);
+        #[cfg(feature = "defmt")]
+        defmt::trace!("requests: {}", defmt::Debug2Format(&requests));
+        let input_ops = requests.map(|request| match request {
+            Request {
+                op:
+                    Op::Input {
+                        pull_up_enabled: _,
+                        op: Some(op),
+                    },
+                state: RequestState::ProcessingRequest,
+            } => Some(op),
+            _ => None,
+        });
+        let outputs = pin_mask(|i| matches!(requests[i].op, Op::Output { latch: _ }));
+        let watches = pin_mask(|i| {
+            matches!(
+                requests[i].op,
+                Op::Watch {
+                    pull_up_enabled: _,
+                    last_known_value: _,
+                }
+            )
+        });
+
+        // Read INTF and INTCAP in one transaction, so that we know which pins caused the interrupt
+        // and which state they changed to. This also clears the interrupt.
+        let previous_int_enabled = registers.int_enabled;
+        let (int_flags, captured) = match device {
+            Device::Mcp23017 if previous_int_enabled != 0 => {
+                read_interrupt_registers(&mut i2c, address)
+                    .await
+                    .map_err(RunError::I2c)?
+            }
+            Device::Mcp23017 => (0, 0),
+            // The MCP23016 interrupts when any input changes, so INTCAP is read after every
+            // interrupt to clear it. Without an interrupt, INTCAP is from an older interrupt.
+            Device::Mcp23016 => {
+                let captured = if woken_by_interrupt {
+                    Some(
+                        read_registers(&mut i2c, address, device, RegisterType::INTCAP, u16::MAX)
+                            .await
+                            .map_err(RunError::I2c)?,
+                    )
+                } else {
+                    None
+                };
+                change_tracker.interrupt_registers(captured)
+            }
+        };
+        // Every input of the MCP23016 can interrupt
+        let can_interrupt = match device {
+            Device::Mcp23017 => previous_int_enabled,
+            Device::Mcp23016 => registers.io_dir,
+        };
+        if woken_by_interrupt && int_flags & can_interrupt == 0 {
+            report_error(immutable, NonFatalError::SpuriousInterrupt);
         }
+        // Interrupts from the previous configuration of a pin don't count for new requests
+        let int_flags = int_flags & previous_int_enabled & !is_new;
+        immutable
+            .diagnostics
+            .lock(|diagnostics| diagnostics.int_flags = int_flags);
 
-        // Update OLAT
-        let new_latches = array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| match requests[i].op {
-            Op::Output { latch } => latch,
-            _ => registers[i].latch,
-        });
-        write_registers(
-            &mut mutable.i2c,
+        // Requests which are done because of an interrupt
+        let mut done_by_interrupt = int_flags
+            & pin_mask(|i| match input_ops[i] {
+                // The MCP23016 has no DEFVAL, so the captured state has to be checked
+                Some(InputOp::WaitForState {
+                    state,
+                    int_control: InterruptControl::CompareWithConfiguredValue,
+                }) => match device {
+                    Device::Mcp23017 => true,
+                    Device::Mcp23016 => PinState::from(bit(captured, i)) == state,
+                },
+                Some(InputOp::WaitForAnyEdge) => true,
+                Some(InputOp::WaitForSpecificEdge { after_state }) => {
+                    PinState::from(bit(captured, i)) == after_state
+                }
+                _ => false,
+            });
+        // Service high priority pins before writing any registers, so that their latency is bounded
+        let high_priority = immutable.high_priority.get() & int_flags & !outputs;
+        let priority_gpio_reads = high_priority
+            & (watches
+                | pin_mask(|i| {
+                    matches!(
+                        input_ops[i],
+                        Some(InputOp::WaitForState {
+                            state: _,
+                            int_control: InterruptControl::CompareWithPreviousValue,
+                        })
+                    )
+                }));
+        let priority_gpio = read_gpio(
+            &mut i2c,
             address,
-            RegisterType::OLAT,
-            registers.map(|register| register.latch.into()),
-            new_latches.map(|latch| latch.into()),
+            device,
+            &mut change_tracker,
+            priority_gpio_reads,
+            previous_int_enabled & !priority_gpio_reads,
         )
         .await
         .map_err(RunError::I2c)?;
-        for i in 0..N_TOTAL_GPIO_PINS {
-            registers[i].latch = new_latches[i];
+        let mut done = 0;
+        // Watched pins that had edges which the runner didn't see
+        let mut overflowed = 0;
+        for i in (0..N_TOTAL_GPIO_PINS).filter(|&i| bit(high_priority, i)) {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("servicing high priority pin {}", i);
+            let gpio_state =
+                bit(priority_gpio_reads, i).then_some(PinState::from(bit(priority_gpio, i)));
+            let missed_edges = watch_missed_edges(&requests[i], bit(captured, i), gpio_state);
+            if missed_edges {
+                overflowed |= 1 << i;
+            }
+            if complete_request(
+                immutable,
+                i,
+                &mut requests[i],
+                gpio_state,
+                bit(done_by_interrupt, i),
+                missed_edges,
+            ) {
+                done |= 1 << i;
+            }
         }
 
-        // Update GPPU
-        let new_pull_ups_enabled =
-            array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| match requests[i].op {
-                Op::Watch {
-                    pull_up_enabled,
-                    last_known_value: _,
-                } => pull_up_enabled,
-                _ => registers[i].pull_up_enabled,
-            });
+        interrupt_output = process_chip_request(
+            &mut i2c,
+            address,
+            device,
+            immutable,
+            registers,
+            Some(interrupt_output),
+        )
+        .await
+        .map_err(RunError::I2c)?;
+        // Reinitializing resets the register cache after IPOL was written, so write it again in
+        // this pass instead of leaving the chip with the power-on polarity until the next one
         write_registers(
-            &mut mutable.i2c,
+            &mut i2c,
             address,
-            RegisterType::GPPU,
-            registers.map(|register| register.pull_up_enabled),
-            new_pull_ups_enabled,
+            device,
+            RegisterType::IPOL,
+            registers.polarity_inverted,
+            polarity_inverted,
         )
         .await
         .map_err(RunError::I2c)?;
-        for i in 0..N_TOTAL_GPIO_PINS {
-            registers[i].pull_up_enabled = new_pull_ups_enabled[i];
+        registers.polarity_inverted = polarity_inverted;
+
+        if immutable.control.connection_check.get().requested {
+            let mut iocon = [Default::default(); 1];
+            let result = i2c
+                .write_read(
+                    address,
+                    &[device.register_address(Register {
+                        _type: RegisterType::IOCON,
+                        ab: AB::A,
+                    })],
+                    &mut iocon,
+                )
+                .await;
+            let connected = result.is_ok() && iocon[0] == device.iocon(interrupt_output);
+            if result.is_ok() && !connected {
+                report_error(immutable, NonFatalError::ReadbackMismatch);
+            }
+            #[cfg(feature = "defmt")]
+            if !connected {
+                defmt::warn!("connection check failed");
+            }
+            immutable.control.connection_check.set(ConnectionCheck {
+                requested: false,
+                connected,
+            });
+            immutable.control.connection_check_waker.wake();
         }
 
-        // Update GPINTEN
-        let new_int_enabled = array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| match requests[i].op {
-            Op::Watch {
-                pull_up_enabled: _,
-                last_known_value: _,
-            } => true,
-            _ => false,
+        if immutable.control.failsafe_requested.get() {
+            write_failsafe_states(&mut i2c, address, device, immutable, registers).await;
+            immutable.control.failsafe_requested.set(false);
+            immutable.control.failsafe_waker.wake();
+            #[cfg(feature = "defmt")]
+            defmt::info!("stopping the runner after writing failsafe states");
+            return Ok(());
+        }
+
+        // Requests which are still waiting for an interrupt, by the interrupt control they need
+        let configured_value_waits = !done_by_interrupt
+            & pin_mask(|i| {
+                matches!(
+                    input_ops[i],
+                    Some(InputOp::WaitForState {
+                        state: _,
+                        int_control: InterruptControl::CompareWithConfiguredValue,
+                    })
+                )
+            });
+        let previous_value_waits = !done_by_interrupt
+            & pin_mask(|i| {
+                matches!(
+                    input_ops[i],
+                    Some(InputOp::WaitForState {
+                        state: _,
+                        int_control: InterruptControl::CompareWithPreviousValue,
+                    }) | Some(InputOp::WaitForAnyEdge)
+                        | Some(InputOp::WaitForSpecificEdge { after_state: _ })
+                )
+            });
+        // The compare value is the opposite of the state we are waiting for
+        let configured_values = pin_mask(|i| {
+            matches!(
+                input_ops[i],
+                Some(InputOp::WaitForState {
+                    state: PinState::Low,
+                    int_control: _,
+                })
+            )
         });
-        let previous_int_enabled = registers.map(|register| register.int_enabled);
+
+        // Update IODIR
+        let new_io_dirs = !outputs;
         write_registers(
-            &mut mutable.i2c,
+            &mut i2c,
             address,
-            RegisterType::GPINTEN,
-            previous_int_enabled,
-            new_int_enabled,
+            device,
+            RegisterType::IODIR,
+            registers.io_dir,
+            new_io_dirs,
         )
         .await
         .map_err(RunError::I2c)?;
-        for i in 0..N_TOTAL_GPIO_PINS {
-            registers[i].int_enabled = new_int_enabled[i];
-        }
+        registers.io_dir = new_io_dirs;
 
-        // Read GPIO
-        // Read GPIO if disabling interrupts to clear any pending interrupts
-        let mut gpio_buffer = array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| {
-            if previous_int_enabled[i] && !new_int_enabled[i]
-                || match requests[i].op {
-                    Op::Watch {
-                        pull_up_enabled: _,
-                        last_known_value: _,
-                    } => {
-                        // TODO: Maybe don't read this unless we know an interrupt happened?
-                        true
-                    }
-                    _ => false,
+        // Update OLAT
+        let high_outputs = pin_mask(|i| {
+            matches!(
+                requests[i].op,
+                Op::Output {
+                    latch: PinState::High
                 }
-            {
-                Some(Default::default())
-            } else {
-                None
+            )
+        });
+        let new_latches = (registers.latch & !outputs) | high_outputs;
+        write_latches(
+            &mut i2c,
+            address,
+            device,
+            delay,
+            immutable.control.soft_start.get(),
+            &mut registers.latch,
+            new_latches,
+        )
+        .await
+        .map_err(RunError::I2c)?;
+
+        // Update GPPU
+        let pull_ups = pin_mask(|i| match requests[i].op {
+            Op::Input {
+                pull_up_enabled,
+                op: _,
             }
+            | Op::Watch {
+                pull_up_enabled,
+                last_known_value: _,
+            } => pull_up_enabled,
+            _ => false,
         });
-        read_registers(
-            &mut mutable.i2c,
+        let new_pull_ups_enabled = (registers.pull_up_enabled & outputs) | pull_ups;
+        // The MCP23016 has no pull-ups
+        if device.has_mcp23017_registers() {
+            write_registers(
+                &mut i2c,
+                address,
+                device,
+                RegisterType::GPPU,
+                registers.pull_up_enabled,
+                new_pull_ups_enabled,
+            )
+            .await
+            .map_err(RunError::I2c)?;
+            registers.pull_up_enabled = new_pull_ups_enabled;
+        } else {
+            #[cfg(feature = "defmt")]
+            if pull_ups & is_new != 0 {
+                defmt::warn!(
+                    "the MCP23016 has no pull-ups, so pins {:016b} are floating",
+                    pull_ups & is_new
+                );
+            }
+        }
+
+        // Update DEFVAL. The MCP23016 interrupts for every input, so the runner only keeps track of
+        // which pins are waiting, and doesn't write DEFVAL, INTCON and GPINTEN.
+        let new_int_compares = (registers.int_compare & !configured_value_waits)
+            | (configured_values & configured_value_waits);
+        if device.has_mcp23017_registers() {
+            write_registers(
+                &mut i2c,
+                address,
+                device,
+                RegisterType::DEFVAL,
+                registers.int_compare,
+                new_int_compares,
+            )
+            .await
+            .map_err(RunError::I2c)?;
+        }
+        registers.int_compare = new_int_compares;
+
+        // Update INTCON
+        let new_int_controls =
+            (registers.int_control & !(previous_value_waits | watches)) | configured_value_waits;
+        if device.has_mcp23017_registers() {
+            write_registers(
+                &mut i2c,
+                address,
+                device,
+                RegisterType::INTCON,
+                registers.int_control,
+                new_int_controls,
+            )
+            .await
+            .map_err(RunError::I2c)?;
+        }
+        registers.int_control = new_int_controls;
+
+        // Update GPINTEN
+        let new_int_enabled = configured_value_waits | previous_value_waits | watches;
+        if device.has_mcp23017_registers() {
+            write_registers(
+                &mut i2c,
+                address,
+                device,
+                RegisterType::GPINTEN,
+                registers.int_enabled,
+                new_int_enabled,
+            )
+            .await
+            .map_err(RunError::I2c)?;
+        }
+        registers.int_enabled = new_int_enabled;
+
+        // Read GPIO
+        // Pins that still have interrupts enabled will have any interrupt that happened after
+        // reading INTCAP handled in the next pass. For pins that just had interrupts disabled,
+        // read GPIO to clear it so that the interrupt line doesn't stay active.
+        // Every watched pin is read in every pass, in the same transaction, so their last known
+        // states are resynced even if edges were collapsed into one interrupt or missed.
+        let output_check_requested = immutable.control.output_check.get().requested;
+        let output_check_reads = if output_check_requested || periodic_output_check {
+            !registers.io_dir
+        } else {
+            0
+        };
+        sampler.update(immutable);
+        let sample_due = sampler.is_due();
+        // The MCP23016 can't compare with DEFVAL, so those pins are also read to know if they
+        // already are at their level. Pins that start waiting are read so that changes from
+        // before they were waiting aren't flagged.
+        let mcp23016_reads = match device {
+            Device::Mcp23017 => 0,
+            Device::Mcp23016 => configured_value_waits | (new_int_enabled & is_new),
+        };
+        let gpio_reads = (previous_int_enabled & !new_int_enabled)
+            | if sample_due { u16::MAX } else { 0 }
+            | output_check_reads
+            | watches
+            | mcp23016_reads
+            | pin_mask(|i| {
+                matches!(
+                    input_ops[i],
+                    Some(InputOp::Read { response: _ })
+                        | Some(InputOp::WaitForState {
+                            state: _,
+                            int_control: InterruptControl::CompareWithPreviousValue,
+                        })
+                )
+            });
+        let gpio = read_gpio(
+            &mut i2c,
             address,
-            RegisterType::GPIO,
-            &mut gpio_buffer,
+            device,
+            &mut change_tracker,
+            gpio_reads,
+            new_int_enabled & !gpio_reads,
         )
         .await
         .map_err(RunError::I2c)?;
-        let read_gpio_states = gpio_buffer.map(|option| option.map(|value| PinState::from(value)));
+        done_by_interrupt |= mcp23016_reads & configured_value_waits & (gpio ^ configured_values);
+        let gpio_states = gpio.into_bits_le();
+        let read_gpio_states = array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(|i| {
+            bit(gpio_reads, i).then_some(gpio_states[i].into())
+        });
         #[cfg(feature = "defmt")]
         defmt::trace!(
             "read gpio states: {}",
             defmt::Debug2Format(&read_gpio_states)
         );
 
-        // TODO: Handle Input mode
+        if sample_due {
+            sampler.push(immutable, gpio);
+        }
+
+        if output_check_requested || periodic_output_check {
+            let faults =
+                (gpio ^ registers.polarity_inverted ^ registers.latch) & output_check_reads;
+            #[cfg(feature = "defmt")]
+            if faults != 0 {
+                defmt::warn!("output pins {:016b} are faulted", faults);
+            }
+            immutable.control.output_check.lock(|output_check| {
+                if output_check_requested {
+                    output_check.requested = false;
+                    output_check.faults = faults;
+                }
+                output_check.unreported_faults |= faults;
+            });
+            if output_check_requested {
+                immutable.control.output_check_waker.wake();
+            }
+            if faults != 0 {
+                immutable.control.output_faults_waker.wake();
+            }
+            periodic_output_check = false;
+        }
 
         // Set requests to done if applicable
-        // Only set requests to done if they were not modified since we read them
-        join_array(array::from_fn::<_, N_TOTAL_GPIO_PINS, _>(async |i| {
-            let mut request = immutable.pins[i].request.write().await;
+        for i in 0..N_TOTAL_GPIO_PINS {
+            // The interrupts of high priority pins were already checked for missed edges
+            let missed_edges = bit(int_flags & !high_priority, i)
+                && watch_missed_edges(&requests[i], bit(captured, i), read_gpio_states[i]);
+            if missed_edges {
+                overflowed |= 1 << i;
+            }
+            if complete_request(
+                immutable,
+                i,
+                &mut requests[i],
+                read_gpio_states[i],
+                bit(done_by_interrupt, i),
+                missed_edges,
+            ) {
+                done |= 1 << i;
+            }
+        }
+        if overflowed != 0 {
             #[cfg(feature = "defmt")]
-            defmt::trace!("request: {}", defmt::Debug2Format(&request));
-            if requests[i].op == request.op && request.state == RequestState::ProcessingRequest {
-                match &mut request.op {
-                    Op::Output { latch: _ } => {
-                        request.state = RequestState::Done;
-                        immutable.pins[i].response_signal.signal(());
-                    }
-                    Op::Watch {
-                        pull_up_enabled: _,
-                        last_known_value,
-                    } => {
-                        if read_gpio_states[i] != *last_known_value {
-                            *last_known_value = read_gpio_states[i];
-                            immutable.pins[i].response_signal.signal(());
-                        }
+            defmt::warn!("watched pins {:016b} missed edges", overflowed);
+            report_error(
+                immutable,
+                NonFatalError::InterruptOverflow { pins: overflowed },
+            );
+        }
+        rerun = done & registers.int_enabled != 0 || change_tracker.has_carried();
+        *attempt = 0;
+        immutable.control.flush_waker.wake();
+        immutable
+            .diagnostics
+            .lock(|diagnostics| diagnostics.registers = *registers);
+    }
+}
+
+/// Reads `GPIO`. The MCP23016 clears its interrupt when `GP` is read, so both ports are read and
+/// `change_tracker` gets the pins in `waiting` that changed, to flag them in the next pass.
+async fn read_gpio<I2c: embedded_hal_async::i2c::I2c>(
+    i2c: &mut I2c,
+    i2c_address: u8,
+    device: Device,
+    change_tracker: &mut ChangeTracker,
+    mask: u16,
+    waiting: u16,
+) -> Result<u16, I2c::Error> {
+    match device {
+        Device::Mcp23017 => {
+            read_registers(i2c, i2c_address, device, RegisterType::GPIO, mask).await
+        }
+        Device::Mcp23016 if mask != 0 => {
+            let gpio =
+                read_registers(i2c, i2c_address, device, RegisterType::GPIO, u16::MAX).await?;
+            change_tracker.gpio_read(gpio, waiting);
+            Ok(gpio & mask)
+        }
+        Device::Mcp23016 => Ok(0),
+    }
+}
+
+/// Whether a watched pin that has a flag in `INTF` had edges that the runner didn't see.
+/// `INTCAP` is the state right after the first edge since the last read. If that is the last known
+/// state, the last known state was stale, and if the pin changed again after it, the edges were
+/// collapsed into one interrupt. Either way, there were 2 edges that the last known state missed.
+fn watch_missed_edges(request: &Request, captured: bool, gpio_state: Option<PinState>) -> bool {
+    match (request.op, gpio_state) {
+        (
+            Op::Watch {
+                pull_up_enabled: _,
+                last_known_value: Some(last_known_value),
+            },
+            Some(gpio_state),
+        ) => {
+            let captured = PinState::from(captured);
+            captured == last_known_value || captured != gpio_state
+        }
+        _ => false,
+    }
+}
+
+/// Sets the request of pin `i` to done if applicable, and updates the last known value of a
+/// watched pin. Only changes the request if it was not modified since `request` was read,
+/// and then updates `request` to match. Returns `true` if the request was set to done.
+/// If `missed_edges`, a watched pin counts 2 more changes and is notified even if its state is the
+/// same.
+fn complete_request(
+    immutable: &Mcp23017Immutable,
+    i: usize,
+    request: &mut Request,
+    gpio_state: Option<PinState>,
+    done_by_interrupt: bool,
+    missed_edges: bool,
+) -> bool {
+    immutable.pins[i].request.lock(|current_request| {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("request: {}", defmt::Debug2Format(&current_request));
+        // Watch requests keep being processed after they are done
+        let is_watch = matches!(
+            current_request.op,
+            Op::Watch {
+                pull_up_enabled: _,
+                last_known_value: _,
+            }
+        );
+        if request.op != current_request.op
+            || !(current_request.state == RequestState::ProcessingRequest
+                || is_watch && current_request.state == RequestState::Done)
+        {
+            return false;
+        }
+        let mut watch_read = false;
+        let done = match &mut current_request.op {
+            Op::Output { latch: _ } => true,
+            Op::Input {
+                pull_up_enabled: _,
+                op,
+            } => match op {
+                None => true,
+                Some(InputOp::Read { response }) => {
+                    *response = gpio_state;
+                    true
+                }
+                Some(InputOp::WaitForState {
+                    state,
+                    int_control: InterruptControl::CompareWithPreviousValue,
+                }) => gpio_state == Some(*state),
+                Some(_) => done_by_interrupt,
+            },
+            Op::Watch {
+                pull_up_enabled: _,
+                last_known_value,
+            } => {
+                let changed = gpio_state.is_some() && gpio_state != *last_known_value;
+                if changed || missed_edges {
+                    // The first read isn't a change
+                    if last_known_value.is_some() {
+                        let changes = u32::from(changed) + if missed_edges { 2 } else { 0 };
+                        immutable.pins[i].stats.lock(|stats| {
+                            stats.changes = stats.changes.wrapping_add(changes);
+                            #[cfg(feature = "embassy-time")]
+                            {
+                                stats.last_change = Some(embassy_time::Instant::now());
+                            }
+                        });
                     }
-                    _ => {}
+                    *last_known_value = gpio_state;
+                    immutable
+                        .watch_changes
+                        .lock(|watch_changes| *watch_changes |= 1 << i);
+                    immutable.wake_pin(i);
                 }
+                watch_read = gpio_state.is_some();
+                // The interrupts stay enabled, so this doesn't count as done for the runner
+                false
             }
-        }))
-        .await;
-    }
+        };
+        if done || watch_read && current_request.state == RequestState::ProcessingRequest {
+            current_request.state = RequestState::Done;
+            immutable.wake_pin(i);
+        }
+        *request = *current_request;
+        done
+    })
 }
@@ -0,0 +1,205 @@
+//! Reading `GPIO` at a fixed rate. This needs the `embassy-time` feature.
+
+#[cfg(feature = "embassy-time")]
+use core::{future::poll_fn, task::Poll};
+
+#[cfg(feature = "embassy-time")]
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::*;
+
+/// The number of samples that are kept until they are received
+pub const SAMPLE_BUFFER_LEN: usize = 16;
+
+#[cfg(feature = "embassy-time")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SampleBuffer {
+    pub interval: Option<Duration>,
+    samples: [u16; SAMPLE_BUFFER_LEN],
+    start: u8,
+    len: u8,
+}
+
+#[cfg(feature = "embassy-time")]
+impl SampleBuffer {
+    pub const fn new() -> Self {
+        Self {
+            interval: None,
+            samples: [0; SAMPLE_BUFFER_LEN],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// If the buffer is full, the oldest sample is dropped
+    fn push(&mut self, sample: u16) {
+        let end = (self.start as usize + self.len as usize) % SAMPLE_BUFFER_LEN;
+        self.samples[end] = sample;
+        if self.len as usize == SAMPLE_BUFFER_LEN {
+            self.start = ((self.start as usize + 1) % SAMPLE_BUFFER_LEN) as u8;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.samples[self.start as usize];
+        self.start = ((self.start as usize + 1) % SAMPLE_BUFFER_LEN) as u8;
+        self.len -= 1;
+        Some(sample)
+    }
+}
+
+#[cfg(feature = "embassy-time")]
+impl Control<'_> {
+    /// Makes the runner read `GPIO` for both ports in a single transaction every `interval`,
+    /// and keep the samples for [`Self::receive_sample`]. `None` stops sampling.
+    /// This is better than interrupts for scanning things like jog wheels and DIP switches.
+    ///
+    /// Reading `GPIO` clears pending interrupts, so an interrupt for a different pin that happens
+    /// while the runner is doing a pass can be missed.
+    pub fn set_sample_interval(&self, interval: Option<Duration>) {
+        self.immutable.control.samples.lock(|samples| {
+            samples.interval = interval;
+        });
+        self.immutable.runner_waker.wake();
+    }
+
+    /// Waits for the oldest sample that wasn't received yet. Bit `i` is the state of pin `i`.
+    /// Only the newest [`SAMPLE_BUFFER_LEN`] samples are kept.
+    pub async fn receive_sample(&self) -> u16 {
+        poll_fn(|cx| {
+            self.immutable.control.sample_waker.register(cx.waker());
+            match self.immutable.control.samples.lock(SampleBuffer::pop) {
+                Some(sample) => Poll::Ready(sample),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+/// Used by the runner to know when to sample
+#[derive(Default)]
+pub(crate) struct Sampler {
+    #[cfg(feature = "embassy-time")]
+    interval: Option<Duration>,
+    #[cfg(feature = "embassy-time")]
+    next_sample_at: Option<Instant>,
+}
+
+impl Sampler {
+    /// Whether the interval was changed with [`Control::set_sample_interval`]
+    pub fn interval_changed(&self, immutable: &Mcp23017Immutable) -> bool {
+        #[cfg(feature = "embassy-time")]
+        {
+            immutable.control.samples.get().interval != self.interval
+        }
+        #[cfg(not(feature = "embassy-time"))]
+        {
+            let _ = immutable;
+            false
+        }
+    }
+
+    /// Starts over if the interval was changed
+    pub fn update(&mut self, immutable: &Mcp23017Immutable) {
+        #[cfg(feature = "embassy-time")]
+        if self.interval_changed(immutable) {
+            self.interval = immutable.control.samples.get().interval;
+            self.next_sample_at = self.interval.map(|interval| Instant::now() + interval);
+        }
+        #[cfg(not(feature = "embassy-time"))]
+        let _ = immutable;
+    }
+
+    /// Waits until it's time to sample. If sampling is disabled, this never finishes.
+    pub async fn wait(&self) {
+        #[cfg(feature = "embassy-time")]
+        if let Some(next_sample_at) = self.next_sample_at {
+            Timer::at(next_sample_at).await;
+            return;
+        }
+        core::future::pending().await
+    }
+
+    pub fn is_due(&self) -> bool {
+        #[cfg(feature = "embassy-time")]
+        {
+            self.next_sample_at
+                .is_some_and(|next_sample_at| Instant::now() >= next_sample_at)
+        }
+        #[cfg(not(feature = "embassy-time"))]
+        {
+            false
+        }
+    }
+
+    /// Keeps the sample and schedules the next one.
+    /// If the runner fell behind, samples are skipped instead of read all at once.
+    pub fn push(&mut self, immutable: &Mcp23017Immutable, sample: u16) {
+        #[cfg(feature = "embassy-time")]
+        if let (Some(interval), Some(next_sample_at)) = (self.interval, self.next_sample_at) {
+            immutable
+                .control
+                .samples
+                .lock(|samples| samples.push(sample));
+            immutable.control.sample_waker.wake();
+            let now = Instant::now();
+            self.next_sample_at = Some(if next_sample_at + interval > now {
+                next_sample_at + interval
+            } else {
+                now + interval
+            });
+        }
+        #[cfg(not(feature = "embassy-time"))]
+        let _ = (immutable, sample);
+    }
+}
+
+#[cfg(all(test, feature = "embassy-time"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_popped_in_order() {
+        let mut samples = SampleBuffer::new();
+        assert_eq!(samples.pop(), None);
+        samples.push(1);
+        samples.push(2);
+        assert_eq!(samples.pop(), Some(1));
+        assert_eq!(samples.pop(), Some(2));
+        assert_eq!(samples.pop(), None);
+    }
+
+    #[test]
+    fn sample_buffer_wraps_around() {
+        let mut samples = SampleBuffer::new();
+        let mut next = 0;
+        for _ in 0..3 {
+            for i in 0..SAMPLE_BUFFER_LEN as u16 - 1 {
+                samples.push(next + i);
+            }
+            for _ in 0..SAMPLE_BUFFER_LEN - 1 {
+                assert_eq!(samples.pop(), Some(next));
+                next += 1;
+            }
+        }
+        assert_eq!(samples.pop(), None);
+    }
+
+    #[test]
+    fn overrun_drops_the_oldest() {
+        let mut samples = SampleBuffer::new();
+        for i in 0..SAMPLE_BUFFER_LEN as u16 + 3 {
+            samples.push(i);
+        }
+        for i in 3..SAMPLE_BUFFER_LEN as u16 + 3 {
+            assert_eq!(samples.pop(), Some(i));
+        }
+        assert_eq!(samples.pop(), None);
+    }
+}
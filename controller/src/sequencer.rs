@@ -0,0 +1,71 @@
+use crate::{output::set_output_states, *};
+
+/// One step of a [`Pattern`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// Bit `i` is the state of the `i`th pin of the [`Sequencer`]
+    pub mask: u16,
+    pub duration_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    Times(u32),
+    Forever,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern<'a> {
+    pub steps: &'a [Step],
+    pub repeat: Repeat,
+}
+
+/// Plays [`Pattern`]s on up to 16 output pins.
+/// Every step is written to the chip in a single runner pass, so all of the pins change together.
+pub struct Sequencer<'a, const N: usize, Delay> {
+    pins: [Pin<'a, mode::Output>; N],
+    delay: Delay,
+}
+
+impl<'a, const N: usize, Delay: DelayNs> Sequencer<'a, N, Delay> {
+    pub fn new(pins: [Pin<'a, mode::Output>; N], delay: Delay) -> Self {
+        const {
+            assert!(
+                N <= u16::BITS as usize,
+                "a sequencer can have at most 16 pins"
+            );
+        }
+        Self { pins, delay }
+    }
+
+    pub fn into_pins(self) -> [Pin<'a, mode::Output>; N] {
+        self.pins
+    }
+
+    /// Set the state of all pins. Bit `i` is the state of the `i`th pin.
+    pub async fn set(&mut self, mask: u16) {
+        set_output_states(&self.pins, |i| (mask & (1 << i) != 0).into()).await;
+    }
+
+    /// Plays the pattern, returning after the last repetition.
+    /// With [`Repeat::Forever`], this never returns, but it is safe to cancel (for example to
+    /// play a different pattern).
+    pub async fn play(&mut self, pattern: &Pattern<'_>) {
+        if pattern.steps.is_empty() {
+            return;
+        }
+        let mut repetitions = 0;
+        loop {
+            if let Repeat::Times(times) = pattern.repeat
+                && repetitions >= times
+            {
+                break;
+            }
+            for step in pattern.steps {
+                self.set(step.mask).await;
+                self.delay.delay_ms(step.duration_ms).await;
+            }
+            repetitions += 1;
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{sync::Mutex, *};
+
+/// Segments of the digits `0` to `9`. Bits `0..7` are segments `a` to `g`.
+const DIGIT_SEGMENTS: [u8; 10] = [0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F];
+/// Segment `g`
+const MINUS: u8 = 0x40;
+
+/// What the digits of a [`SevenSegment`] show. It can be changed from any task while the display
+/// is refreshed, for example by putting it in a `static`.
+/// Digit `0` is the leftmost digit.
+pub struct SevenSegmentDigits<const DIGITS: usize> {
+    digits: Mutex<[u8; DIGITS]>,
+}
+
+impl<const DIGITS: usize> SevenSegmentDigits<DIGITS> {
+    /// All digits are blank
+    pub const fn new() -> Self {
+        Self {
+            digits: Mutex::new([0; DIGITS]),
+        }
+    }
+
+    /// Shows a number aligned to the right, without leading zeros.
+    /// If it doesn't fit, every digit shows `-`.
+    pub fn display(&self, number: i32) {
+        let mut digits = [0; DIGITS];
+        let mut remaining = number.unsigned_abs();
+        let mut fits = true;
+        for i in (0..DIGITS).rev() {
+            digits[i] = DIGIT_SEGMENTS[(remaining % 10) as usize];
+            remaining /= 10;
+            if remaining == 0 {
+                if number < 0 {
+                    match i.checked_sub(1) {
+                        Some(i) => digits[i] = MINUS,
+                        None => fits = false,
+                    }
+                }
+                break;
+            }
+        }
+        if remaining != 0 || !fits {
+            digits = [MINUS; DIGITS];
+        }
+        self.display_raw(digits);
+    }
+
+    /// Bits `0..7` of each digit are segments `a` to `g`, and bit `7` is the decimal point
+    pub fn display_raw(&self, digits: [u8; DIGITS]) {
+        self.digits.set(digits);
+    }
+}
+
+impl<const DIGITS: usize> Default for SevenSegmentDigits<DIGITS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The type of display, which decides the active levels of the pins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommonPin {
+    /// Segments are active high, and digit selects are active low
+    Cathode,
+    /// Segments are active low, and digit selects are active high
+    Anode,
+}
+
+/// Drives multiplexed 7-segment displays, with 8 pins for the segments (`a` to `g` and the decimal
+/// point) and a pin to select each digit. One digit is on at a time, and the digits are scanned
+/// fast enough that they all look on.
+///
+/// Before switching to the next digit, every digit is switched off in its own runner pass,
+/// so the segments of one digit never show up on another digit.
+/// Then the segments and the digit select are written in a single runner pass.
+pub struct SevenSegment<'a, const DIGITS: usize, Delay> {
+    segments: [Pin<'a, mode::Output>; 8],
+    digits: [Pin<'a, mode::Output>; DIGITS],
+    common_pin: CommonPin,
+    delay: Delay,
+    digit_time_us: u32,
+}
+
+impl<'a, const DIGITS: usize, Delay: DelayNs> SevenSegment<'a, DIGITS, Delay> {
+    /// Refreshes the whole display 100 times per second, see [`Self::set_refresh_rate`].
+    /// Nothing is shown until the first refresh.
+    pub fn new(
+        segments: [Pin<'a, mode::Output>; 8],
+        digits: [Pin<'a, mode::Output>; DIGITS],
+        common_pin: CommonPin,
+        delay: Delay,
+    ) -> Self {
+        const {
+            assert!(DIGITS > 0, "a display needs at least 1 digit");
+        }
+        let mut seven_segment = Self {
+            segments,
+            digits,
+            common_pin,
+            delay,
+            digit_time_us: 0,
+        };
+        seven_segment.set_refresh_rate(100);
+        seven_segment
+    }
+
+    pub fn into_pins(self) -> ([Pin<'a, mode::Output>; 8], [Pin<'a, mode::Output>; DIGITS]) {
+        (self.segments, self.digits)
+    }
+
+    /// How many times per second every digit is shown.
+    /// If the runner can't keep up, the actual rate is lower.
+    pub fn set_refresh_rate(&mut self, hz: u32) {
+        self.digit_time_us = 1_000_000 / (hz.max(1) * DIGITS as u32);
+    }
+
+    /// Selects digit `i` (if it's `Some`) and shows `segments` on it.
+    /// If `segments` is `None`, the segments are left as they are.
+    async fn show_digit(&mut self, i: Option<usize>, segments: Option<u8>) {
+        let (segment_on, digit_on) = match self.common_pin {
+            CommonPin::Cathode => (PinState::High, PinState::Low),
+            CommonPin::Anode => (PinState::Low, PinState::High),
+        };
+        if let Some(segments) = segments {
+            for (j, pin) in self.segments.iter().enumerate() {
+                pin.request_op(Op::Output {
+                    latch: if segments & (1 << j) != 0 {
+                        segment_on
+                    } else {
+                        !segment_on
+                    },
+                });
+            }
+        }
+        for (j, pin) in self.digits.iter().enumerate() {
+            pin.request_op(Op::Output {
+                latch: if i == Some(j) { digit_on } else { !digit_on },
+            });
+        }
+        for pin in self.segments.iter().chain(&self.digits) {
+            pin.wait_until_done().await;
+        }
+    }
+
+    /// Shows every digit once
+    pub async fn refresh(&mut self, digits: &SevenSegmentDigits<DIGITS>) {
+        for i in 0..DIGITS {
+            let segments = digits.digits.get()[i];
+            self.blank().await;
+            self.show_digit(Some(i), Some(segments)).await;
+            self.delay.delay_us(self.digit_time_us).await;
+        }
+    }
+
+    /// Keeps refreshing. Run this in its own task. This never returns, but it is safe to cancel.
+    /// Call [`Self::blank`] after cancelling it, otherwise the last digit stays on.
+    pub async fn run(&mut self, digits: &SevenSegmentDigits<DIGITS>) -> ! {
+        loop {
+            self.refresh(digits).await;
+        }
+    }
+
+    /// Switches off every digit
+    pub async fn blank(&mut self) {
+        self.show_digit(None, None).await;
+    }
+}
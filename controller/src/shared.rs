@@ -0,0 +1,182 @@
+use core::{future::poll_fn, task::Poll};
+
+use crate::*;
+
+/// The number of tasks that can wait on [`SharedPin`]s of the same chip without waking each other
+pub(crate) const SHARED_WAKERS: usize = 4;
+
+/// A handle to a pin that can be cloned, so that multiple tasks can use the same pin.
+/// Get one with [`Pin::share`]. The pin can't change modes while it is shared.
+///
+/// Up to 4 tasks can wait on the shared pins of a chip at the same time.
+/// If more tasks wait, they still work, but they keep waking each other.
+pub struct SharedPin<'p, 'a, Mode> {
+    pin: &'p Pin<'a, Mode>,
+    /// [`PinStats::changes`] the last time this handle saw a change. Only used if the pin is watched.
+    seen_changes: u32,
+}
+
+impl<Mode> Clone for SharedPin<'_, '_, Mode> {
+    fn clone(&self) -> Self {
+        Self {
+            pin: self.pin,
+            seen_changes: self.seen_changes,
+        }
+    }
+}
+
+impl<'a> Pin<'a, mode::Watch> {
+    /// Get a handle that can observe this pin from multiple tasks
+    pub fn share(&self) -> SharedPin<'_, 'a, mode::Watch> {
+        SharedPin {
+            pin: self,
+            seen_changes: self.s().stats.get().changes,
+        }
+    }
+}
+
+impl<'a> Pin<'a, mode::Output> {
+    /// Get a handle that can set this pin from multiple tasks
+    pub fn share(&self) -> SharedPin<'_, 'a, mode::Output> {
+        SharedPin {
+            pin: self,
+            seen_changes: 0,
+        }
+    }
+}
+
+impl<Mode> SharedPin<'_, '_, Mode> {
+    /// Waits until `f` returns `Some` for the current request
+    async fn wait_for_request<R>(&self, f: impl Fn(Request) -> Option<R>) -> R {
+        poll_fn(|cx| {
+            self.pin.immutable.shared_waker.register(cx.waker());
+            match f(self.pin.s().request.get()) {
+                Some(r) => Poll::Ready(r),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl SharedPin<'_, '_, mode::Watch> {
+    /// See [`Pin::state`]
+    pub fn state(&self) -> PinState {
+        self.pin.state()
+    }
+
+    /// See [`Pin::stats`]
+    pub fn stats(&self) -> PinStats {
+        self.pin.stats()
+    }
+
+    /// Waits until the watched value changes. Each handle sees every change once, so this doesn't
+    /// affect [`Pin::watch`] or other handles.
+    pub async fn watch(&mut self) {
+        self.seen_changes = self
+            .wait_for_request(|_| {
+                let changes = self.pin.s().stats.get().changes;
+                (changes != self.seen_changes).then_some(changes)
+            })
+            .await;
+    }
+
+    async fn wait_for_state(&mut self, state: PinState) {
+        while self.state() != state {
+            self.watch().await;
+        }
+    }
+
+    /// `None` means any edge
+    async fn wait_for_edge(&mut self, after_state: Option<PinState>) {
+        // Changes from before this function was called don't count
+        self.seen_changes = self.stats().changes;
+        let mut previous_state = self.state();
+        loop {
+            self.watch().await;
+            let state = self.state();
+            if state != previous_state && after_state.is_none_or(|after_state| after_state == state)
+            {
+                break;
+            }
+            previous_state = state;
+        }
+    }
+}
+
+/// Works like the [`Wait`] impl of [`Pin<mode::Watch>`]
+impl Wait for SharedPin<'_, '_, mode::Watch> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_state(PinState::High).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_state(PinState::Low).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Some(PinState::High)).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Some(PinState::Low)).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(None).await;
+        Ok(())
+    }
+}
+
+impl SharedPin<'_, '_, mode::Output> {
+    /// If multiple handles set the state at the same time, the latest state wins,
+    /// and all of them wait until it is written
+    async fn set_state(&mut self, state: PinState) {
+        self.pin.request_op(Op::Output { latch: state });
+        self.wait_for_request(|request| (request.state == RequestState::Done).then_some(()))
+            .await;
+    }
+
+    async fn is_set_state(&mut self, state: PinState) -> bool {
+        let set_state = self
+            .wait_for_request(|request| match request {
+                Request {
+                    op: Op::Output { latch },
+                    state: RequestState::Done,
+                } => Some(latch),
+                _ => None,
+            })
+            .await;
+        set_state == state
+    }
+}
+
+impl OutputPin for SharedPin<'_, '_, mode::Output> {
+    async fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_state(PinState::Low).await;
+        Ok(())
+    }
+
+    async fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_state(PinState::High).await;
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for SharedPin<'_, '_, mode::Output> {
+    async fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.is_set_state(PinState::High).await)
+    }
+
+    async fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.is_set_state(PinState::Low).await)
+    }
+}
+
+impl<Mode> ErrorType for SharedPin<'_, '_, Mode> {
+    type Error = Infallible;
+}
@@ -0,0 +1,242 @@
+use crate::{register::write_registers, *};
+
+/// Staggers output pins that are switched on in the same runner pass, to limit inrush current.
+/// See [`Control::set_soft_start`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftStart {
+    /// The level that switches a load on. Use [`PinState::Low`] for active low loads.
+    pub on_level: PinState,
+    /// The delay between switching on one pin and the next
+    pub delay_us: u32,
+}
+
+impl Control<'_> {
+    /// When the runner switches on more than one output pin in the same pass, it switches them on
+    /// one at a time with a delay in between, starting with pin `0`. Everything else in that pass,
+    /// including switching off, is written together with the first pin.
+    /// Relay and LED strip boards can brown out when all of their channels switch on at once.
+    /// `None` switches all of the pins on together.
+    ///
+    /// The runner doesn't do anything else during the delays.
+    /// This uses the `Delay` passed to [`Mcp23017::new`].
+    pub fn set_soft_start(&self, soft_start: Option<SoftStart>) {
+        self.immutable.control.soft_start.set(soft_start);
+    }
+}
+
+/// Writes `OLAT`, staggering the pins that are switched on if soft start is enabled.
+/// `latches` is updated after every write, so it is correct even if a write fails.
+pub(crate) async fn write_latches<I2c: embedded_hal_async::i2c::I2c>(
+    i2c: &mut I2c,
+    i2c_address: u8,
+    device: Device,
+    delay: &mut impl DelayNs,
+    soft_start: Option<SoftStart>,
+    latches: &mut u16,
+    new_latches: u16,
+) -> Result<(), I2c::Error> {
+    let current_latches = *latches;
+    let switched_on = match soft_start {
+        Some(SoftStart {
+            on_level: PinState::High,
+            delay_us: _,
+        }) => !current_latches & new_latches,
+        Some(SoftStart {
+            on_level: PinState::Low,
+            delay_us: _,
+        }) => current_latches & !new_latches,
+        None => 0,
+    };
+    let Some(soft_start) = soft_start.filter(|_| switched_on.count_ones() > 1) else {
+        write_registers(
+            i2c,
+            i2c_address,
+            device,
+            RegisterType::OLAT,
+            current_latches,
+            new_latches,
+        )
+        .await?;
+        *latches = new_latches;
+        return Ok(());
+    };
+    let mut next_latches = new_latches ^ switched_on;
+    for i in (0..N_TOTAL_GPIO_PINS).filter(|&i| bit(switched_on, i)) {
+        if *latches != current_latches {
+            delay.delay_us(soft_start.delay_us).await;
+        }
+        next_latches ^= 1 << i;
+        write_registers(
+            i2c,
+            i2c_address,
+            device,
+            RegisterType::OLAT,
+            *latches,
+            next_latches,
+        )
+        .await?;
+        *latches = next_latches;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorType, I2c, Operation};
+    use heapless::Vec;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        /// `OLAT` after a write
+        Olat(u16),
+        Delay(u32),
+    }
+
+    /// An MCP23017 that records the writes to `OLAT` and the delays between them
+    struct Mock {
+        olat: u16,
+        events: Vec<Event, 32>,
+    }
+
+    impl ErrorType for Mock {
+        type Error = Infallible;
+    }
+
+    impl I2c for Mock {
+        async fn transaction(
+            &mut self,
+            _address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            for operation in operations {
+                let Operation::Write([address, bytes @ ..]) = operation else {
+                    panic!("unexpected {operation:?}");
+                };
+                let mut olat = self.olat.to_le_bytes();
+                let start = match *address {
+                    0x14 => 0,
+                    0x15 => 1,
+                    address => panic!("unexpected write to {address:#04x}"),
+                };
+                olat[start..start + bytes.len()].copy_from_slice(bytes);
+                self.olat = u16::from_le_bytes(olat);
+                self.events.push(Event::Olat(self.olat)).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    impl DelayNs for Mock {
+        async fn delay_ns(&mut self, _ns: u32) {
+            unreachable!()
+        }
+
+        async fn delay_us(&mut self, us: u32) {
+            self.events.push(Event::Delay(us)).unwrap();
+        }
+    }
+
+    /// Writes `new_latches` and returns what happened
+    fn write(soft_start: Option<SoftStart>, latches: u16, new_latches: u16) -> Vec<Event, 32> {
+        let mut i2c = Mock {
+            olat: latches,
+            events: Vec::new(),
+        };
+        let mut delay = Mock {
+            olat: 0,
+            events: Vec::new(),
+        };
+        let mut latches = latches;
+        block_on(write_latches(
+            &mut i2c,
+            0x20,
+            Device::Mcp23017,
+            &mut delay,
+            soft_start,
+            &mut latches,
+            new_latches,
+        ))
+        .unwrap();
+        assert_eq!(latches, new_latches);
+        assert_eq!(i2c.olat, new_latches);
+        // Merge the delays into the writes in the order they happened, which is one delay before
+        // every write except the first
+        let mut events = Vec::new();
+        let mut delays = delay.events.into_iter();
+        for (i, event) in i2c.events.into_iter().enumerate() {
+            if i > 0 {
+                events.push(delays.next().unwrap()).unwrap();
+            }
+            events.push(event).unwrap();
+        }
+        assert_eq!(delays.next(), None);
+        events
+    }
+
+    const fn soft_start(on_level: PinState) -> Option<SoftStart> {
+        Some(SoftStart {
+            on_level,
+            delay_us: 50,
+        })
+    }
+
+    #[test]
+    fn without_soft_start_everything_is_written_at_once() {
+        assert_eq!(write(None, 0x0000, 0x0107), [Event::Olat(0x0107)]);
+    }
+
+    #[test]
+    fn active_high_pins_switch_on_one_at_a_time() {
+        // Pin 1 switches off with the first pin that switches on
+        assert_eq!(
+            write(soft_start(PinState::High), 0x0002, 0x0105),
+            [
+                Event::Olat(0x0001),
+                Event::Delay(50),
+                Event::Olat(0x0005),
+                Event::Delay(50),
+                Event::Olat(0x0105),
+            ]
+        );
+    }
+
+    #[test]
+    fn active_low_pins_switch_on_one_at_a_time() {
+        // Pin 1 switches off with the first pin that switches on
+        assert_eq!(
+            write(soft_start(PinState::Low), 0xFFFD, 0xFEFA),
+            [
+                Event::Olat(0xFFFE),
+                Event::Delay(50),
+                Event::Olat(0xFFFA),
+                Event::Delay(50),
+                Event::Olat(0xFEFA),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_pin_switching_on_is_not_staggered() {
+        assert_eq!(
+            write(soft_start(PinState::High), 0x0003, 0x0006),
+            [Event::Olat(0x0006)]
+        );
+        assert_eq!(
+            write(soft_start(PinState::Low), 0x0003, 0x0006),
+            [Event::Olat(0x0006)]
+        );
+    }
+
+    #[test]
+    fn only_switching_off_is_not_staggered() {
+        assert_eq!(
+            write(soft_start(PinState::High), 0x0107, 0x0000),
+            [Event::Olat(0x0000)]
+        );
+    }
+}
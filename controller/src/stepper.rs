@@ -0,0 +1,94 @@
+use crate::{output::set_output_states, *};
+
+/// Coil patterns for half stepping. Bit `i` is the state of the `i`th pin.
+/// Full stepping uses the odd indices, which have two coils on for more torque.
+const HALF_STEPS: [u8; 8] = [
+    0b0001, 0b0011, 0b0010, 0b0110, 0b0100, 0b1100, 0b1000, 0b1001,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepMode {
+    FullStep,
+    HalfStep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepDirection {
+    Forward,
+    Backward,
+}
+
+/// Drives a stepper motor (through a driver like the ULN2003) with 4 output pins.
+/// The coils of each step are written to the chip in a single runner pass.
+pub struct Stepper<'a, Delay> {
+    pins: [Pin<'a, mode::Output>; 4],
+    delay: Delay,
+    mode: StepMode,
+    step_delay_us: u32,
+    /// Index into [`HALF_STEPS`]
+    phase: usize,
+}
+
+impl<'a, Delay: DelayNs> Stepper<'a, Delay> {
+    /// The pins should be in the order of the coils (A, B, A', B').
+    /// The coils are not energized until the first step.
+    pub fn new(
+        pins: [Pin<'a, mode::Output>; 4],
+        delay: Delay,
+        mode: StepMode,
+        step_delay_us: u32,
+    ) -> Self {
+        Self {
+            pins,
+            delay,
+            mode,
+            step_delay_us,
+            phase: 1,
+        }
+    }
+
+    pub fn into_pins(self) -> [Pin<'a, mode::Output>; 4] {
+        self.pins
+    }
+
+    pub fn set_mode(&mut self, mode: StepMode) {
+        self.mode = mode;
+    }
+
+    /// The time to wait after each step. This limits the speed of the motor.
+    pub fn set_step_delay_us(&mut self, step_delay_us: u32) {
+        self.step_delay_us = step_delay_us;
+    }
+
+    /// Do a single step and then wait for the step delay
+    pub async fn step(&mut self, direction: StepDirection) {
+        let phases = HALF_STEPS.len();
+        self.phase = match (self.mode, direction) {
+            (StepMode::HalfStep, StepDirection::Forward) => self.phase + 1,
+            (StepMode::HalfStep, StepDirection::Backward) => self.phase + phases - 1,
+            // If we were half stepping, snap to the next two-coil phase
+            (StepMode::FullStep, StepDirection::Forward) => (self.phase + 1) | 1,
+            (StepMode::FullStep, StepDirection::Backward) => (self.phase + phases - 2) | 1,
+        } % phases;
+        let coils = HALF_STEPS[self.phase];
+        set_output_states(&self.pins, |i| (coils & (1 << i) != 0).into()).await;
+        self.delay.delay_us(self.step_delay_us).await;
+    }
+
+    /// Do `steps` steps forward, or backward if negative
+    pub async fn move_steps(&mut self, steps: i32) {
+        let direction = if steps < 0 {
+            StepDirection::Backward
+        } else {
+            StepDirection::Forward
+        };
+        for _ in 0..steps.unsigned_abs() {
+            self.step(direction).await;
+        }
+    }
+
+    /// Turns off all coils, so the motor doesn't hold its position or use power
+    pub async fn release(&mut self) {
+        set_output_states(&self.pins, |_| PinState::Low).await;
+    }
+}
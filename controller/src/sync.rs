@@ -0,0 +1,158 @@
+//! Synchronization between the pins and the runner.
+//!
+//! Everything else in this crate only uses the types in this module, so the runner works on any
+//! async executor (embassy, RTIC, ...). With the `embassy-sync` feature (enabled by default), these
+//! are thin wrappers around `embassy-sync`. Without it, they are implemented with
+//! `critical-section` directly.
+//!
+//! To keep RAM usage low, there are no channels or signals. Each pin has a [`Mutex`] with its
+//! request and a [`WakerSlot`] for the task using the pin, and the runner has a single
+//! [`WakerSlot`] for all of the pins. Waiters register their waker and then check the shared state,
+//! so a wake can't be lost between checking and waiting.
+//!
+//! Either way, both [`Mutex`] backends lock a critical section, so a `critical-section`
+//! implementation must be provided by your platform. `embassy-futures` is always a dependency,
+//! even without `embassy-sync`, because the runner uses its `select4` and `yield_now`. Those are
+//! plain futures that don't depend on the embassy executor or on anything else from embassy.
+
+use core::{cell::Cell, task::Waker};
+
+/// A value that can be accessed from the pins and from the runner.
+/// The value is never borrowed across an `.await`, so this is a blocking mutex.
+pub(crate) struct Mutex<T> {
+    #[cfg(feature = "embassy-sync")]
+    inner: embassy_sync::blocking_mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
+        Cell<T>,
+    >,
+    #[cfg(not(feature = "embassy-sync"))]
+    inner: critical_section::Mutex<Cell<T>>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "embassy-sync")]
+            inner: embassy_sync::blocking_mutex::Mutex::new(Cell::new(value)),
+            #[cfg(not(feature = "embassy-sync"))]
+            inner: critical_section::Mutex::new(Cell::new(value)),
+        }
+    }
+
+    fn with_cell<R>(&self, f: impl FnOnce(&Cell<T>) -> R) -> R {
+        #[cfg(feature = "embassy-sync")]
+        {
+            self.inner.lock(f)
+        }
+        #[cfg(not(feature = "embassy-sync"))]
+        {
+            critical_section::with(|cs| f(self.inner.borrow(cs)))
+        }
+    }
+}
+
+impl<T: Copy> Mutex<T> {
+    pub fn get(&self) -> T {
+        self.with_cell(|cell| cell.get())
+    }
+
+    pub fn set(&self, value: T) {
+        self.with_cell(|cell| cell.set(value))
+    }
+
+    /// Modify the value while holding the lock
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.with_cell(|cell| {
+            let mut value = cell.get();
+            let r = f(&mut value);
+            cell.set(value);
+            r
+        })
+    }
+}
+
+/// Runs `f` in a single critical section, so the runner sees every change that `f` makes to
+/// [`Mutex`]es at once
+pub(crate) fn atomically<R>(f: impl FnOnce() -> R) -> R {
+    critical_section::with(|_| f())
+}
+
+/// Holds the waker of the one task that is waiting.
+/// If a different task registers, the previous one is woken so that it doesn't wait forever.
+pub(crate) struct WakerSlot {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> Self {
+        Self {
+            waker: Mutex::new(None),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let previous_waker = self.waker.with_cell(|cell| match cell.take() {
+            Some(previous_waker) if previous_waker.will_wake(waker) => {
+                cell.set(Some(previous_waker));
+                None
+            }
+            previous_waker => {
+                cell.set(Some(waker.clone()));
+                previous_waker
+            }
+        });
+        if let Some(previous_waker) = previous_waker {
+            previous_waker.wake();
+        }
+    }
+
+    pub fn wake(&self) {
+        if let Some(waker) = self.waker.with_cell(Cell::take) {
+            waker.wake();
+        }
+    }
+}
+
+/// Like [`WakerSlot`], but for up to `N` tasks.
+/// If more tasks register, every task is woken so that none of them wait forever,
+/// and they register again when they are polled.
+pub(crate) struct MultiWakerSlot<const N: usize> {
+    wakers: Mutex<[Option<Waker>; N]>,
+}
+
+impl<const N: usize> MultiWakerSlot<N> {
+    pub const fn new() -> Self {
+        Self {
+            wakers: Mutex::new([const { None }; N]),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let registered = self.wakers.with_cell(|cell| {
+            let mut wakers = cell.replace([const { None }; N]);
+            let registered = if wakers.iter().flatten().any(|w| w.will_wake(waker)) {
+                true
+            } else if let Some(slot) = wakers.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(waker.clone());
+                true
+            } else {
+                false
+            };
+            cell.set(wakers);
+            registered
+        });
+        if !registered {
+            self.wake();
+            waker.wake_by_ref();
+        }
+    }
+
+    pub fn wake(&self) {
+        let wakers = self
+            .wakers
+            .with_cell(|cell| cell.replace([const { None }; N]));
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
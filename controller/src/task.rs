@@ -0,0 +1,38 @@
+/// Puts a [`Mcp23017`](crate::Mcp23017) in a `static`, spawns an embassy task which polls its
+/// [`Runner`](crate::Runner), and evaluates to its [`InitialPins<'static>`](crate::InitialPins).
+///
+/// Tasks can't be generic, so the types of the `Mcp23017` have to be written out.
+/// Each invocation creates its own task and `static`, and can only be run once.
+/// If the runner returns an error, the task panics, because the pins can't be used anymore.
+///
+/// ```ignore
+/// let pins = mcp23017_controller::spawn_runner!(
+///     spawner,
+///     Mcp23017::new(i2c, [false; 3], reset_pin, interrupt_pin, Delay),
+///     Mcp23017<I2c<'static, Async>, Output<'static>, ExtiInput<'static>, Delay>
+/// );
+/// ```
+#[macro_export]
+macro_rules! spawn_runner {
+    (
+        $spawner:expr,
+        $mcp23017:expr,
+        Mcp23017<$I2c:ty, $ResetPin:ty, $InterruptPin:ty, $Delay:ty $(,)?>
+    ) => {{
+        use $crate::__private::embassy_executor;
+
+        #[embassy_executor::task(embassy_executor = embassy_executor)]
+        async fn mcp23017_runner(
+            mut runner: $crate::Runner<'static, $I2c, $ResetPin, $InterruptPin, $Delay>,
+        ) {
+            runner.run().await.unwrap();
+        }
+
+        static MCP23017: $crate::__private::static_cell::StaticCell<
+            $crate::Mcp23017<$I2c, $ResetPin, $InterruptPin, $Delay>,
+        > = $crate::__private::static_cell::StaticCell::new();
+        let (runner, pins) = MCP23017.init($mcp23017).split();
+        $spawner.must_spawn(mcp23017_runner(runner));
+        pins
+    }};
+}
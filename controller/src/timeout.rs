@@ -0,0 +1,110 @@
+//! Timeouts for I2C transactions. This needs the `embassy-time` feature.
+
+#[cfg(feature = "embassy-time")]
+use embassy_time::Duration;
+use embedded_hal::i2c::{ErrorKind, ErrorType, Operation};
+
+use crate::{bus_sharing::wait_for_turn, *};
+
+#[cfg(feature = "embassy-time")]
+impl Control<'_> {
+    /// Makes the runner return [`RunError::Timeout`] if an I2C transaction takes longer than
+    /// `timeout`, for example because something is holding `SCL` low and the I2C driver waits
+    /// forever. `None` disables this.
+    /// Timeouts are not retried (see [`Self::set_retry_policy`]), because they usually mean that
+    /// the bus needs to be recovered.
+    pub fn set_i2c_timeout(&self, timeout: Option<Duration>) {
+        self.immutable.control.i2c_timeout.set(timeout);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TimeoutError<E> {
+    I2c(E),
+    #[cfg_attr(not(feature = "embassy-time"), allow(dead_code))]
+    Timeout,
+}
+
+impl<E: embedded_hal::i2c::Error> embedded_hal::i2c::Error for TimeoutError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::I2c(error) => error.kind(),
+            Self::Timeout => ErrorKind::Other,
+        }
+    }
+}
+
+/// Forwards every transaction to the I2C bus, with the timeout from [`Control::set_i2c_timeout`]
+/// and the [`BusSharing`] from [`Control::set_bus_sharing`]
+pub(crate) struct TimeoutI2c<'a, I2c> {
+    i2c: &'a mut I2c,
+    immutable: &'a Mcp23017Immutable,
+    /// Whether there was a transaction yet
+    started: bool,
+}
+
+impl<'a, I2c> TimeoutI2c<'a, I2c> {
+    pub fn new(i2c: &'a mut I2c, immutable: &'a Mcp23017Immutable) -> Self {
+        Self {
+            i2c,
+            immutable,
+            started: false,
+        }
+    }
+
+    async fn wait_for_turn(&mut self) {
+        wait_for_turn(self.immutable, !self.started).await;
+        self.started = true;
+    }
+}
+
+async fn with_timeout<E>(
+    immutable: &Mcp23017Immutable,
+    future: impl Future<Output = Result<(), E>>,
+) -> Result<(), TimeoutError<E>> {
+    #[cfg(feature = "embassy-time")]
+    if let Some(timeout) = immutable.control.i2c_timeout.get() {
+        return match embassy_time::with_timeout(timeout, future).await {
+            Ok(result) => result.map_err(TimeoutError::I2c),
+            Err(embassy_time::TimeoutError) => Err(TimeoutError::Timeout),
+        };
+    }
+    #[cfg(not(feature = "embassy-time"))]
+    let _ = immutable;
+    future.await.map_err(TimeoutError::I2c)
+}
+
+impl<I2c: embedded_hal_async::i2c::I2c> ErrorType for TimeoutI2c<'_, I2c> {
+    type Error = TimeoutError<I2c::Error>;
+}
+
+impl<I2c: embedded_hal_async::i2c::I2c> embedded_hal_async::i2c::I2c for TimeoutI2c<'_, I2c> {
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.wait_for_turn().await;
+        with_timeout(self.immutable, self.i2c.read(address, read)).await
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.wait_for_turn().await;
+        with_timeout(self.immutable, self.i2c.write(address, write)).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.wait_for_turn().await;
+        with_timeout(self.immutable, self.i2c.write_read(address, write, read)).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.wait_for_turn().await;
+        with_timeout(self.immutable, self.i2c.transaction(address, operations)).await
+    }
+}
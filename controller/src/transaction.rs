@@ -0,0 +1,341 @@
+use core::{future::poll_fn, ptr, task::Poll};
+
+use crate::*;
+
+/// Changes multiple pins in a single runner pass, so the registers are written once for all of
+/// them instead of once per pin.
+///
+/// Each method only stages the change. Pins that change mode are moved into the transaction, and
+/// [`Self::commit`] hands every change to the runner at once, waits until it has done all of them,
+/// and returns those pins in their new modes, in the order they were staged. Dropping the
+/// transaction without committing throws the changes away, and the pins that were moved into it
+/// with them.
+///
+/// ```ignore
+/// let (a0, b2) = control
+///     .begin()
+///     .output(a0, PinState::High)
+///     .input(b2, true)
+///     .inverted(&b3, true)
+///     .set(&mut a1, PinState::Low)
+///     .commit()
+///     .await;
+/// ```
+#[must_use = "call `commit` to make the changes"]
+pub struct Transaction<'a, P = ()> {
+    immutable: Option<&'a Mcp23017Immutable>,
+    /// The pins in their new modes
+    pins: P,
+    /// The new op of each pin
+    ops: [Option<Op>; N_TOTAL_GPIO_PINS],
+    /// Bit `i` is set if the polarity of pin `i` is changed
+    polarity_changed: u16,
+    /// The new polarity of the pins in `polarity_changed`
+    polarity_inverted: u16,
+}
+
+/// The pins of a [`Transaction`], which is a tuple of up to 16 pins
+pub trait TransactionPins<T> {
+    type Output;
+
+    fn append(self, pin: T) -> Self::Output;
+}
+
+macro_rules! impl_transaction_pins {
+    ($($p:ident)*) => {
+        impl<$($p,)* T> TransactionPins<T> for ($($p,)*) {
+            type Output = ($($p,)* T,);
+
+            #[allow(non_snake_case)]
+            fn append(self, pin: T) -> Self::Output {
+                let ($($p,)*) = self;
+                ($($p,)* pin,)
+            }
+        }
+    };
+}
+
+impl_transaction_pins!();
+impl_transaction_pins!(P0);
+impl_transaction_pins!(P0 P1);
+impl_transaction_pins!(P0 P1 P2);
+impl_transaction_pins!(P0 P1 P2 P3);
+impl_transaction_pins!(P0 P1 P2 P3 P4);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8 P9);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8 P9 P10);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8 P9 P10 P11);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8 P9 P10 P11 P12);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8 P9 P10 P11 P12 P13);
+impl_transaction_pins!(P0 P1 P2 P3 P4 P5 P6 P7 P8 P9 P10 P11 P12 P13 P14);
+
+impl<'a> Transaction<'a> {
+    pub const fn new() -> Self {
+        Self {
+            immutable: None,
+            pins: (),
+            ops: [None; N_TOTAL_GPIO_PINS],
+            polarity_changed: 0,
+            polarity_inverted: 0,
+        }
+    }
+}
+
+impl<'a, P> Transaction<'a, P> {
+    fn add<Mode>(&mut self, pin: &Pin<'a, Mode>) {
+        let immutable = *self.immutable.get_or_insert(pin.immutable);
+        assert!(
+            ptr::eq(immutable, pin.immutable),
+            "all pins in a transaction must be on the same chip"
+        );
+    }
+
+    /// Moves the pin into the transaction in its new mode
+    fn stage<Mode, NewMode>(
+        mut self,
+        pin: Pin<'a, Mode>,
+        mode: NewMode,
+        op: Op,
+    ) -> Transaction<'a, P::Output>
+    where
+        P: TransactionPins<Pin<'a, NewMode>>,
+    {
+        self.add(&pin);
+        self.ops[pin.index as usize] = Some(op);
+        Transaction {
+            immutable: self.immutable,
+            pins: self.pins.append(pin.into_mode(mode)),
+            ops: self.ops,
+            polarity_changed: self.polarity_changed,
+            polarity_inverted: self.polarity_inverted,
+        }
+    }
+
+    /// See [`Pin::into_output`]
+    pub fn output<Mode>(
+        self,
+        pin: Pin<'a, Mode>,
+        initial_value: PinState,
+    ) -> Transaction<'a, P::Output>
+    where
+        P: TransactionPins<Pin<'a, mode::Output>>,
+    {
+        self.stage(
+            pin,
+            mode::Output,
+            Op::Output {
+                latch: initial_value,
+            },
+        )
+    }
+
+    /// See [`Pin::into_input`]
+    pub fn input<Mode>(
+        self,
+        pin: Pin<'a, Mode>,
+        pull_up_enabled: bool,
+    ) -> Transaction<'a, P::Output>
+    where
+        P: TransactionPins<Pin<'a, mode::Input>>,
+    {
+        self.stage(
+            pin,
+            mode::Input,
+            Op::Input {
+                pull_up_enabled,
+                op: None,
+            },
+        )
+    }
+
+    /// See [`Pin::into_watch`]
+    pub fn watch<Mode>(
+        self,
+        pin: Pin<'a, Mode>,
+        pull_up_enabled: bool,
+    ) -> Transaction<'a, P::Output>
+    where
+        P: TransactionPins<Pin<'a, mode::Watch>>,
+    {
+        self.stage(
+            pin,
+            mode::Watch,
+            Op::Watch {
+                pull_up_enabled,
+                last_known_value: None,
+            },
+        )
+    }
+
+    /// Sets the state of an output pin
+    pub fn set(mut self, pin: &mut Pin<'a, mode::Output>, state: PinState) -> Self {
+        self.add(pin);
+        self.ops[pin.index as usize] = Some(Op::Output { latch: state });
+        self
+    }
+
+    /// Sets whether reads of the pin are inverted (`IPOL`). This only changes what is read, so
+    /// an inverted input reads [`PinState::Low`] when the pin is high, and compares the inverted
+    /// level for interrupts. Output latches are not inverted.
+    pub fn inverted<Mode>(mut self, pin: &Pin<'a, Mode>, inverted: bool) -> Self {
+        self.add(pin);
+        self.polarity_changed |= 1 << pin.index;
+        self.polarity_inverted =
+            (self.polarity_inverted & !(1 << pin.index)) | ((inverted as u16) << pin.index);
+        self
+    }
+
+    /// Hands every change to the runner at once, and waits until the runner has done them.
+    /// Returns the pins that changed mode.
+    pub async fn commit(self) -> P {
+        let Some(immutable) = self.immutable else {
+            return self.pins;
+        };
+        let pins = atomically(|| {
+            let mut pins = 0;
+            let polarity_changed = immutable.polarity_inverted.lock(|polarity_inverted| {
+                let new = (*polarity_inverted & !self.polarity_changed) | self.polarity_inverted;
+                let changed = *polarity_inverted ^ new;
+                *polarity_inverted = new;
+                changed
+            });
+            for (i, pin) in immutable.pins.iter().enumerate() {
+                let changed = pin.request.lock(|request| match self.ops[i] {
+                    // Like `Pin::request_op`, a pin whose op is the same isn't requested again
+                    Some(op) if op != request.op => {
+                        match op {
+                            Op::Input { .. } => pin.failsafe_state.set(None),
+                            Op::Watch { .. } => {
+                                pin.failsafe_state.set(None);
+                                pin.stats.set(PinStats::new());
+                            }
+                            Op::Output { .. } => {}
+                        }
+                        *request = Request {
+                            op,
+                            state: RequestState::Requested,
+                        };
+                        pin.queued_input_op.set(None);
+                        true
+                    }
+                    // Only the polarity changed, so request the same op again to make `commit`
+                    // wait for the pass that writes `IPOL`
+                    _ if bit(polarity_changed, i) => {
+                        request.state = RequestState::Requested;
+                        true
+                    }
+                    _ => false,
+                });
+                if changed {
+                    pins |= 1 << i;
+                }
+            }
+            immutable
+                .new_requests
+                .lock(|new_requests| *new_requests |= pins);
+            pins
+        });
+        #[cfg(feature = "defmt")]
+        defmt::trace!("transaction committed pins {:016b}", pins);
+        immutable.runner_waker.wake();
+        let staged = pins | pin_mask(|i| self.ops[i].is_some());
+        poll_fn(|cx| {
+            let mut done = true;
+            for i in (0..N_TOTAL_GPIO_PINS).filter(|&i| bit(staged, i)) {
+                let pin = &immutable.pins[i];
+                pin.waker.register(cx.waker());
+                done &= pin.request.get().state == RequestState::Done;
+            }
+            if done { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await;
+        self.pins
+    }
+}
+
+impl Default for Transaction<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Control<'a> {
+    /// Starts a [`Transaction`] on this chip
+    pub fn begin(&self) -> Transaction<'a> {
+        Transaction {
+            immutable: Some(self.immutable),
+            ..Transaction::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use embassy_futures::poll_once;
+
+    use super::*;
+
+    fn request(immutable: &Mcp23017Immutable, i: usize) -> Request {
+        immutable.pins[i].request.get()
+    }
+
+    #[test]
+    fn dropping_changes_nothing() {
+        let immutable = Mcp23017Immutable::new();
+        let before = request(&immutable, 0);
+        let b3 = Pin::new(&immutable, 11);
+        let transaction = Transaction::new()
+            .output(Pin::new(&immutable, 0), PinState::High)
+            .inverted(&b3, true);
+        drop(transaction);
+        assert_eq!(request(&immutable, 0), before);
+        assert_eq!(immutable.new_requests.get(), 0);
+        assert_eq!(immutable.polarity_inverted.get(), 0);
+    }
+
+    #[test]
+    fn commit_requests_everything_at_once() {
+        let immutable = Mcp23017Immutable::new();
+        let control = Control {
+            immutable: &immutable,
+        };
+        let b3 = Pin::new(&immutable, 11);
+        let mut commit = pin!(
+            control
+                .begin()
+                .output(Pin::new(&immutable, 0), PinState::High)
+                .input(Pin::new(&immutable, 10), true)
+                .inverted(&b3, true)
+                .commit()
+        );
+        assert!(poll_once(commit.as_mut()).is_pending());
+        assert_eq!(immutable.new_requests.get(), 1 << 0 | 1 << 10 | 1 << 11);
+        assert_eq!(immutable.polarity_inverted.get(), 1 << 11);
+        assert_eq!(
+            request(&immutable, 0),
+            Request {
+                op: Op::Output {
+                    latch: PinState::High
+                },
+                state: RequestState::Requested,
+            }
+        );
+        assert_eq!(request(&immutable, 11).state, RequestState::Requested);
+
+        // The runner did all of them
+        for i in [0, 10, 11] {
+            immutable.pins[i]
+                .request
+                .lock(|request| request.state = RequestState::Done);
+        }
+        let Poll::Ready((a0, b2)) = poll_once(commit) else {
+            panic!("commit didn't return after every change was done");
+        };
+        assert_eq!((a0.index, b2.index), (0, 10));
+    }
+}
@@ -12,6 +12,14 @@ impl FromBits<{ u8::BITS as usize }> for u8 {
     }
 }
 
+impl FromBits<{ u16::BITS as usize }> for u16 {
+    fn from_bits_le(bits: [bool; u16::BITS as usize]) -> Self {
+        bits.iter()
+            .enumerate()
+            .fold(0u16, |acc, (i, &b)| acc | ((b as u16) << i))
+    }
+}
+
 pub trait IntoBits<const BIT_LEN: usize> {
     fn into_bits_le(self) -> [bool; BIT_LEN];
 }
@@ -21,3 +29,19 @@ impl IntoBits<{ u8::BITS as usize }> for u8 {
         array::from_fn(|i| (self & (1 << i)) != 0)
     }
 }
+
+impl IntoBits<{ u16::BITS as usize }> for u16 {
+    fn into_bits_le(self) -> [bool; u16::BITS as usize] {
+        array::from_fn(|i| (self & (1 << i)) != 0)
+    }
+}
+
+/// A mask where bit `i` is `f(i)`, for every pin
+pub fn pin_mask(f: impl FnMut(usize) -> bool) -> u16 {
+    u16::from_bits_le(array::from_fn(f))
+}
+
+/// Whether bit `i` of a mask is set
+pub fn bit(mask: u16, i: usize) -> bool {
+    mask & (1 << i) != 0
+}
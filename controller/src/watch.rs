@@ -1,10 +1,40 @@
+use core::task::{Context, Poll};
+
 use crate::*;
 
-impl Pin<'_, mode::Watch> {
-    /// Although this function is `async`, it is only `async` to access a mutex,
-    /// so it basically be sync every time.
-    pub async fn state(&mut self) -> PinState {
-        match self.s.request.read().await.op {
+/// What the runner observed about a watched pin since it was put into [`mode::Watch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinStats {
+    /// The number of times that the last known state changed.
+    /// Changes that are over before the runner reads `GPIO` are counted as 2 changes if the runner
+    /// notices them from `INTCAP` (see [`NonFatalError::InterruptOverflow`]), and otherwise not
+    /// counted.
+    pub changes: u32,
+    /// When the runner read the last change
+    #[cfg(feature = "embassy-time")]
+    pub last_change: Option<embassy_time::Instant>,
+}
+
+impl PinStats {
+    pub const fn new() -> Self {
+        Self {
+            changes: 0,
+            #[cfg(feature = "embassy-time")]
+            last_change: None,
+        }
+    }
+}
+
+impl Default for PinStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Pin<'a, mode::Watch> {
+    /// The last known state of the pin. This does not do any I2C transactions.
+    pub fn state(&self) -> PinState {
+        match self.s().request.get().op {
             Op::Watch {
                 pull_up_enabled: _,
                 last_known_value,
@@ -14,10 +44,133 @@ impl Pin<'_, mode::Watch> {
         .unwrap()
     }
 
+    /// The last known state of the pin, and when the runner read the change to that state.
+    /// The time is `None` if the state didn't change since the pin was put into [`mode::Watch`].
+    /// Both are read together, so the time always belongs to the state.
+    /// This does not do any I2C transactions.
+    ///
+    /// The time is when the runner read `GPIO`, so it is late by the interrupt latency.
+    /// The difference between two times is still good for measuring pulse widths that are much
+    /// longer than that.
+    #[cfg(feature = "embassy-time")]
+    pub fn state_with_time(&self) -> (PinState, Option<embassy_time::Instant>) {
+        // The runner updates the stats while holding the request's lock
+        self.s()
+            .request
+            .lock(|_| (self.state(), self.stats().last_change))
+    }
+
+    /// Makes the runner read `GPIO` for this pin and waits until the last known state is updated.
+    /// Use this to resync after a suspected missed interrupt, for example after waking up from a
+    /// long sleep where edges on the interrupt line may have been lost.
+    pub async fn refresh(&mut self) {
+        self.s()
+            .request
+            .lock(|request| request.state = RequestState::Requested);
+        self.notify_runner();
+        self.wait_until_done().await;
+    }
+
+    /// Statistics which can help with debugging intermittent issues.
+    /// This does not do any I2C transactions.
+    pub fn stats(&self) -> PinStats {
+        self.s().stats.get()
+    }
+
     /// Wait until the watched value changes.
     /// After this, call [`Self::state`].
     /// It's possible that the watched value is the same as before even after this function returns.
-    pub async fn watch(&mut self) {
-        self.s.response_signal.wait().await;
+    pub fn watch(&mut self) -> WatchFuture<'_, 'a> {
+        WatchFuture { pin: self }
+    }
+
+    /// Clears the change notification, returning whether there was one
+    fn take_change(&self) -> bool {
+        let mask = 1 << self.index;
+        self.immutable.watch_changes.lock(|watch_changes| {
+            let changed = *watch_changes & mask != 0;
+            *watch_changes &= !mask;
+            changed
+        })
+    }
+
+    /// Returns immediately if the last known state is already `state`
+    async fn wait_for_state(&mut self, state: PinState) {
+        while self.state() != state {
+            self.watch().await;
+        }
+    }
+
+    /// `None` means any edge
+    async fn wait_for_edge(&mut self, after_state: Option<PinState>) {
+        // Changes from before this function was called don't count
+        self.take_change();
+        let mut previous_state = self.state();
+        loop {
+            self.watch().await;
+            let state = self.state();
+            if state != previous_state && after_state.is_none_or(|after_state| after_state == state)
+            {
+                break;
+            }
+            previous_state = state;
+        }
+    }
+}
+
+/// Returned by [`Pin::watch`].
+/// Unlike an `async fn`, this can be named, so it can be stored in a struct or a `static`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct WatchFuture<'p, 'a> {
+    pin: &'p Pin<'a, mode::Watch>,
+}
+
+impl Future for WatchFuture<'_, '_> {
+    type Output = ();
+
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.pin.s().waker.register(cx.waker());
+        if self.pin.take_change() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// This uses the last known state and doesn't do any extra I2C transactions,
+/// so a watched pin can be used as the interrupt pin of another [`Mcp23017`].
+///
+/// Edges are detected by comparing the last known states, so a pulse that is over before the
+/// runner reads `GPIO` is missed.
+///
+/// [`Wait::wait_for_high`] and [`Wait::wait_for_low`] return immediately if the last known state
+/// is already the level, without any I2C transactions. The last known state can be stale if the
+/// pin changed and the runner didn't service the interrupt yet, or if an interrupt was missed.
+/// Call [`Pin::refresh`] first if that matters.
+impl Wait for Pin<'_, mode::Watch> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_state(PinState::High).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_state(PinState::Low).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Some(PinState::High)).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(Some(PinState::Low)).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(None).await;
+        Ok(())
     }
 }
@@ -0,0 +1,126 @@
+#![no_main]
+
+use core::future::pending;
+
+use libfuzzer_sys::fuzz_target;
+use mcp23017_common::{AB, InterruptMode, Register, RegisterType};
+use mcp23017_peripheral::*;
+
+const IOCON_A: usize = RegisterType::IOCON as usize * 2;
+const IOCON_BANK: u8 = 1 << 7;
+
+struct FuzzPin {
+    level: PinState,
+}
+
+impl GpioPin for FuzzPin {
+    fn configure(&mut self, _io_direction: IoDirection, _pull_up_enabled: bool, _level: PinState) {}
+
+    fn level(&self) -> PinState {
+        self.level
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, _level: PinState) {
+        pending().await
+    }
+}
+
+struct FuzzInterruptPin;
+
+impl InterruptPin for FuzzInterruptPin {
+    fn configure(&mut self, _mode: InterruptMode, _level: PinState) {}
+}
+
+/// Registers that read back what was written
+fn is_plain(register: Register) -> bool {
+    matches!(
+        register._type,
+        RegisterType::IODIR
+            | RegisterType::IPOL
+            | RegisterType::GPINTEN
+            | RegisterType::DEFVAL
+            | RegisterType::INTCON
+            | RegisterType::GPPU
+            | RegisterType::OLAT
+    )
+}
+
+fn check_invariants(registers: &[u8; N_REGISTERS]) {
+    // IOCONA and IOCONB are the same register
+    assert_eq!(registers[IOCON_A], registers[IOCON_A + 1]);
+    // Bit 0 of IOCON is unimplemented
+    assert_eq!(registers[IOCON_A] & 1, 0);
+}
+
+// The first 2 bytes are the levels of the pins. The rest are operations:
+// - `0, len, bytes...`: a write transaction
+// - `1, len, bytes_read`: a read transaction
+// - `2, value`: writes `IOCON`, which switches the bank and sequential mode
+// - `3`: a save and load of the state
+fuzz_target!(|data: &[u8]| {
+    let Some((levels, mut data)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let levels = u16::from_le_bytes(*levels);
+    let mut mcp23017 = Mcp23017::new_without_reset_pin(
+        core::array::from_fn(|i| FuzzPin {
+            level: PinState::from(levels & (1 << i) != 0),
+        }),
+        [FuzzInterruptPin, FuzzInterruptPin],
+    );
+    while let Some((&op, rest)) = data.split_first() {
+        data = rest;
+        let bank_mode = mcp23017.dump_registers()[IOCON_A] & IOCON_BANK != 0;
+        match op % 4 {
+            0 => {
+                let Some((&len, rest)) = data.split_first() else {
+                    return;
+                };
+                let (bytes, rest) = rest.split_at((len as usize).min(rest.len()));
+                data = rest;
+                mcp23017.process_write_transaction(bytes);
+                if let &[address, value] = bytes
+                    && let Some(register) = Register::from_address(address, bank_mode)
+                    && is_plain(register)
+                {
+                    let index = register._type as usize * 2 + register.ab.set_index();
+                    assert_eq!(mcp23017.dump_registers()[index], value);
+                }
+            }
+            1 => {
+                let Some((&[len, bytes_read], rest)) = data.split_first_chunk::<2>() else {
+                    return;
+                };
+                data = rest;
+                let mut buffer = [0; I2C_BUFFER_LEN];
+                let buffer = &mut buffer[..len as usize % (I2C_BUFFER_LEN + 1)];
+                mcp23017.prepare_read_buffer(buffer);
+                mcp23017.confirm_bytes_read((bytes_read as usize).min(buffer.len()));
+            }
+            2 => {
+                let Some((&value, rest)) = data.split_first() else {
+                    return;
+                };
+                data = rest;
+                let address = Register {
+                    _type: RegisterType::IOCON,
+                    ab: AB::A,
+                }
+                .address(bank_mode);
+                mcp23017.process_write_transaction(&[address, value]);
+            }
+            _ => {
+                let registers = mcp23017.dump_registers();
+                let state = mcp23017.save_state();
+                mcp23017.load_state(state);
+                assert_eq!(mcp23017.save_state(), state);
+                assert_eq!(mcp23017.dump_registers(), registers);
+            }
+        }
+        check_invariants(&mcp23017.dump_registers());
+    }
+});
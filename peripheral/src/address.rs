@@ -0,0 +1,119 @@
+use core::{convert::Infallible, mem};
+
+use embedded_hal::digital::InputPin;
+
+use crate::*;
+
+/// The address of an MCP23017 with `A0`, `A1`, and `A2` low
+pub const BASE_ADDRESS: u8 = 0x20;
+
+/// The hardware address pins `A0`, `A1`, and `A2`
+pub trait AddressPins {
+    /// Bit `0` is `A0`
+    fn lower_bits(&mut self) -> u8;
+
+    fn address(&mut self) -> u8 {
+        BASE_ADDRESS | (self.lower_bits() & 0b111)
+    }
+}
+
+/// A fixed value for the address pins, where bit `0` is `A0`
+impl AddressPins for u8 {
+    fn lower_bits(&mut self) -> u8 {
+        *self
+    }
+}
+
+/// Input pins for `A0`, `A1`, and `A2`. They are read for every transaction, like the real chip.
+/// Reading them can't fail, which is the case for the pins of most HALs.
+impl<T: InputPin<Error = Infallible>> AddressPins for [T; 3] {
+    fn lower_bits(&mut self) -> u8 {
+        let mut lower_bits = 0;
+        for (i, pin) in self.iter_mut().enumerate() {
+            let Ok(high) = pin.is_high();
+            if high {
+                lower_bits |= 1 << i;
+            }
+        }
+        lower_bits
+    }
+}
+
+/// Wraps an [`I2cTargetTransport`] and only passes on transactions for the address from
+/// [`AddressPins`], so that emulated chips with different addresses can share a bus, even if
+/// the I2C peripheral acknowledges more than one address.
+///
+/// The I2C peripheral already acknowledged the other addresses, so reads for them get `0xFF`,
+/// which is what the controller would read if nothing was driving the bus.
+/// General calls are passed on.
+pub struct AddressFilter<T, A> {
+    transport: T,
+    address_pins: A,
+    buffer: [u8; I2C_BUFFER_LEN],
+    /// Whether the read that was just responded to was for a different address
+    ignoring_read: bool,
+}
+
+impl<T: I2cTargetTransport, A: AddressPins> AddressFilter<T, A> {
+    pub fn new(transport: T, address_pins: A) -> Self {
+        Self {
+            transport,
+            address_pins,
+            buffer: [0; I2C_BUFFER_LEN],
+            ignoring_read: false,
+        }
+    }
+
+    pub fn into_inner(self) -> (T, A) {
+        (self.transport, self.address_pins)
+    }
+}
+
+impl<T: I2cTargetTransport, A: AddressPins> I2cTargetTransport for AddressFilter<T, A> {
+    type Error = T::Error;
+
+    async fn next_event(&mut self) -> Result<TransportEvent<'_>, Self::Error> {
+        loop {
+            let address = self.address_pins.address();
+            match self.transport.next_event().await? {
+                TransportEvent::Write {
+                    address: event_address,
+                    bytes,
+                } if event_address == address || event_address == GENERAL_CALL_ADDRESS => {
+                    // Copied, because returning the event of `transport` from inside the loop
+                    // would keep it borrowed
+                    let len = bytes.len().min(I2C_BUFFER_LEN);
+                    self.buffer[..len].copy_from_slice(&bytes[..len]);
+                    return Ok(TransportEvent::Write {
+                        address: event_address,
+                        bytes: &self.buffer[..len],
+                    });
+                }
+                TransportEvent::ReadRequested {
+                    address: event_address,
+                    len,
+                } if event_address == address => {
+                    return Ok(TransportEvent::ReadRequested { address, len });
+                }
+                TransportEvent::ReadRequested { address: _, len: _ } => {
+                    self.ignoring_read = true;
+                    self.transport.respond(&[0xFF]).await?;
+                }
+                TransportEvent::ReadCompleted(bytes_read) => {
+                    if !mem::take(&mut self.ignoring_read) {
+                        return Ok(TransportEvent::ReadCompleted(bytes_read));
+                    }
+                }
+                TransportEvent::Write {
+                    address: _,
+                    bytes: _,
+                } => {}
+            }
+            trace!("ignoring a transaction for a different address");
+        }
+    }
+
+    async fn respond(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.transport.respond(bytes).await
+    }
+}
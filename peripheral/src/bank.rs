@@ -0,0 +1,152 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_futures::select::{Either3, select_array, select3};
+use embassy_time::Duration;
+use embedded_hal_async::digital::Wait;
+use mcp23017_common::InterruptMode;
+
+use crate::*;
+
+/// Several emulated chips with different addresses on one I2C transport, so one board can replace
+/// a whole bank of expanders. Each chip has its own registers and pins.
+///
+/// The transport must acknowledge the address of every chip, for example with a second own
+/// address and an address mask. Transactions for other addresses are ignored, and reads for them
+/// get `0xFF`.
+pub struct Mcp23017Bank<P, I, R, A, S, const N: usize> {
+    chips: [(Mcp23017<P, I, R>, A); N],
+    /// Resets every chip
+    reset: ResetPin<S>,
+}
+
+impl<P: GpioPin, I: InterruptPin, R: Wait, A: AddressPins, S: Wait, const N: usize>
+    Mcp23017Bank<P, I, R, A, S, N>
+{
+    /// Each chip can still have its own reset pin, and `reset_pin` resets all of them.
+    /// Use [`NoResetPin`] for the pins that aren't used.
+    pub fn new(chips: [(Mcp23017<P, I, R>, A); N], reset_pin: S) -> Self {
+        Self {
+            chips,
+            reset: ResetPin::new(reset_pin),
+        }
+    }
+
+    pub fn chips(&mut self) -> &mut [(Mcp23017<P, I, R>, A); N] {
+        &mut self.chips
+    }
+
+    /// See [`ResetPin::set_minimum_low_duration`]. This is for the shared reset pin, and each
+    /// chip has its own setting.
+    pub fn set_reset_minimum_low_duration(&mut self, minimum_low_duration: Option<Duration>) {
+        self.reset.set_minimum_low_duration(minimum_low_duration);
+    }
+
+    fn find(&mut self, address: u8) -> Option<usize> {
+        self.chips
+            .iter_mut()
+            .position(|(_, address_pins)| address_pins.address() == address)
+    }
+
+    /// Like [`run_with_transport`], but for every chip.
+    /// This future never completes, and it is safe to cancel.
+    pub async fn run_with_transport<T: I2cTargetTransport>(&mut self, transport: &mut T) -> ! {
+        let mut read_buffer = [0; I2C_BUFFER_LEN];
+        // The chip that the current read is for
+        let mut reading = None;
+        loop {
+            let event = match select3(
+                self.reset.wait_until_reset(),
+                select_array(self.chips.each_mut().map(|(chip, _)| chip.run_inputs())),
+                transport.next_event(),
+            )
+            .await
+            {
+                Either3::First(()) => {
+                    info!("Received reset input. Resetting every emulated MCP23017.");
+                    for (chip, _) in &mut self.chips {
+                        chip.reset();
+                    }
+                    continue;
+                }
+                Either3::Second(((), _)) => unreachable!(),
+                Either3::Third(event) => event,
+            };
+            match event {
+                Ok(TransportEvent::Write { address, bytes }) => {
+                    if is_general_call_reset(address, bytes) {
+                        info!("Received general call reset. Resetting every emulated MCP23017.");
+                        for (chip, _) in &mut self.chips {
+                            chip.reset();
+                        }
+                    } else if let Some(i) = self.find(address) {
+                        self.chips[i].0.process_write_transaction(bytes);
+                    }
+                }
+                Ok(TransportEvent::ReadRequested { address, len }) => {
+                    reading = self.find(address);
+                    let result = match reading {
+                        Some(i) => {
+                            let buffer = &mut read_buffer[..len.min(I2C_BUFFER_LEN)];
+                            self.chips[i].0.prepare_read_buffer(buffer);
+                            transport.respond(buffer).await
+                        }
+                        None => transport.respond(&[0xFF]).await,
+                    };
+                    if let Err(_error) = result {
+                        warn!("I2C read error: {}", Debug2Format(&_error));
+                    }
+                }
+                Ok(TransportEvent::ReadCompleted(bytes_read)) => {
+                    if let Some(i) = reading.take() {
+                        self.chips[i].0.confirm_bytes_read(bytes_read);
+                    }
+                }
+                Err(_error) => {
+                    warn!("I2C error: {}", Debug2Format(&_error));
+                }
+            }
+        }
+    }
+}
+
+/// One interrupt output shared by up to `N` chips, like the open-drain interrupt outputs of real
+/// chips wired together. The line is low if any chip drives it low.
+/// Configure the chips with `IOCON.ODR` and an active low interrupt, like with real chips.
+pub struct SharedInterruptLine<I, const N: usize> {
+    pin: RefCell<I>,
+    levels: Cell<[PinState; N]>,
+}
+
+impl<I: InterruptPin, const N: usize> SharedInterruptLine<I, N> {
+    pub fn new(pin: I) -> Self {
+        Self {
+            pin: RefCell::new(pin),
+            levels: Cell::new([PinState::High; N]),
+        }
+    }
+
+    /// The [`InterruptPin`] for chip `index`
+    pub fn pin(&self, index: usize) -> SharedInterruptPin<'_, I, N> {
+        SharedInterruptPin { line: self, index }
+    }
+}
+
+/// See [`SharedInterruptLine::pin`]
+pub struct SharedInterruptPin<'a, I, const N: usize> {
+    line: &'a SharedInterruptLine<I, N>,
+    index: usize,
+}
+
+impl<I: InterruptPin, const N: usize> InterruptPin for SharedInterruptPin<'_, I, N> {
+    fn configure(&mut self, mode: InterruptMode, level: PinState) {
+        let mut levels = self.line.levels.get();
+        levels[self.index] = level;
+        self.line.levels.set(levels);
+        let line_level = if levels.contains(&PinState::Low) {
+            PinState::Low
+        } else {
+            PinState::High
+        };
+        self.line.pin.borrow_mut().configure(mode, line_level);
+    }
+}
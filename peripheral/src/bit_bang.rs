@@ -0,0 +1,243 @@
+use embassy_futures::select::{Either, select};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+use crate::*;
+
+/// What [`BitBangI2cTransport`] received in the place of a byte
+enum Received {
+    Byte(u8),
+    /// A repeated start condition
+    Start,
+    Stop,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Waiting for a start condition
+    Idle,
+    /// A start condition was received, so the next byte is an address
+    Started,
+    /// [`I2cTargetTransport::respond`] finished. If the controller wants more bytes, `more` is
+    /// the address, and SCL is held low until the next response.
+    ReadCompleted { bytes_read: usize, more: Option<u8> },
+    /// The controller started reading, and SCL is held low until the response
+    ReadRequested { address: u8 },
+}
+
+/// [`I2cTargetTransport`] for micro controllers without an I2C peripheral that can be a target
+/// (slave). It bit-bangs the bus with two pins, which must be able to wait for edges, like the
+/// `Flex` pins of most HALs. The bus needs pull-ups, because the pins only drive low.
+///
+/// Every bit is handled by waking up on an edge, so this only works with slow buses, for example
+/// 10 kHz, depending on the micro controller. While a read is prepared, SCL is held low (clock
+/// stretching), so the controller must support clock stretching.
+///
+/// The address and general calls are acknowledged, and other addresses are ignored. Cancelling
+/// [`I2cTargetTransport::next_event`] in the middle of a transaction loses the transaction, so
+/// the controller sees a NACK and can retry.
+pub struct BitBangI2cTransport<P> {
+    sda: P,
+    scl: P,
+    address: u8,
+    buffer: [u8; I2C_BUFFER_LEN],
+    state: State,
+}
+
+impl<P: FlexPin + InputPin + Wait> BitBangI2cTransport<P> {
+    /// `address` is the 7-bit address to acknowledge
+    pub fn new(mut sda: P, mut scl: P, address: u8) -> Self {
+        sda.set_as_input();
+        scl.set_as_input();
+        Self {
+            sda,
+            scl,
+            address,
+            buffer: [0; _],
+            state: State::Idle,
+        }
+    }
+
+    pub fn into_pins(self) -> (P, P) {
+        (self.sda, self.scl)
+    }
+
+    /// A start condition is SDA falling while SCL is high
+    async fn wait_for_start(&mut self) -> Result<(), P::Error> {
+        loop {
+            self.sda.wait_for_falling_edge().await?;
+            if self.scl.is_high()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receives 8 bits, without acknowledging them. SCL must be low.
+    async fn receive_byte(&mut self) -> Result<Received, P::Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            self.scl.wait_for_high().await?;
+            let level = self.sda.is_high()?;
+            // SDA changing while SCL is high is a start or stop condition
+            match select(self.scl.wait_for_low(), self.sda.wait_for_any_edge()).await {
+                Either::First(result) => result?,
+                Either::Second(result) => {
+                    result?;
+                    return Ok(if self.sda.is_high()? {
+                        Received::Stop
+                    } else {
+                        Received::Start
+                    });
+                }
+            }
+            byte = byte << 1 | u8::from(level);
+        }
+        Ok(Received::Byte(byte))
+    }
+
+    /// Handles the 9th clock after a byte was received. With `ack`, SDA is held low for it.
+    async fn acknowledge(&mut self, ack: bool) -> Result<(), P::Error> {
+        if ack {
+            self.sda.set_as_output(PinState::Low);
+        }
+        self.scl.wait_for_high().await?;
+        self.scl.wait_for_low().await?;
+        // Stretch the clock while releasing SDA, so the controller can't see a stop condition
+        self.scl.set_as_output(PinState::Low);
+        self.sda.set_as_input();
+        Ok(())
+    }
+
+    /// Releases SCL after [`Self::acknowledge`] or clock stretching
+    fn release_scl(&mut self) {
+        self.scl.set_as_input();
+    }
+
+    /// Receives the address and the rest of the transaction if it's a write, and returns the
+    /// address and the length of the write. For reads, the state becomes
+    /// [`State::ReadRequested`].
+    async fn receive_transaction(&mut self) -> Result<Option<(u8, usize)>, P::Error> {
+        self.scl.wait_for_low().await?;
+        let byte = match self.receive_byte().await? {
+            Received::Byte(byte) => byte,
+            Received::Start => {
+                self.state = State::Started;
+                return Ok(None);
+            }
+            Received::Stop => return Ok(None),
+        };
+        let address = byte >> 1;
+        let read = byte & 1 != 0;
+        if !(address == self.address || address == GENERAL_CALL_ADDRESS && !read) {
+            trace!("ignoring I2C address {:#x}", address);
+            return Ok(None);
+        }
+        self.acknowledge(true).await?;
+        if read {
+            // SCL stays low until the response is ready
+            self.state = State::ReadRequested { address };
+            return Ok(None);
+        }
+        self.release_scl();
+        let mut len = 0;
+        loop {
+            match self.receive_byte().await? {
+                Received::Byte(byte) => {
+                    // Bytes that don't fit are not acknowledged
+                    let ack = len < self.buffer.len();
+                    if ack {
+                        self.buffer[len] = byte;
+                        len += 1;
+                    }
+                    self.acknowledge(ack).await?;
+                    self.release_scl();
+                }
+                Received::Start => {
+                    self.state = State::Started;
+                    break;
+                }
+                Received::Stop => break,
+            }
+        }
+        Ok(Some((address, len)))
+    }
+
+    /// Sends a byte and returns whether the controller acknowledged it. SCL must be low, and is
+    /// released after the first bit is on SDA.
+    async fn send_byte(&mut self, byte: u8) -> Result<bool, P::Error> {
+        for i in (0..8).rev() {
+            if byte & 1 << i != 0 {
+                self.sda.set_as_input();
+            } else {
+                self.sda.set_as_output(PinState::Low);
+            }
+            self.release_scl();
+            self.scl.wait_for_high().await?;
+            self.scl.wait_for_low().await?;
+        }
+        self.sda.set_as_input();
+        self.scl.wait_for_high().await?;
+        let ack = self.sda.is_low()?;
+        self.scl.wait_for_low().await?;
+        Ok(ack)
+    }
+}
+
+impl<P: FlexPin + InputPin + Wait> I2cTargetTransport for BitBangI2cTransport<P> {
+    type Error = P::Error;
+
+    async fn next_event(&mut self) -> Result<TransportEvent<'_>, Self::Error> {
+        loop {
+            match self.state {
+                State::Idle => {
+                    self.wait_for_start().await?;
+                    self.state = State::Started;
+                }
+                State::Started => {
+                    self.state = State::Idle;
+                    if let Some((address, len)) = self.receive_transaction().await? {
+                        return Ok(TransportEvent::Write {
+                            address,
+                            bytes: &self.buffer[..len],
+                        });
+                    }
+                }
+                State::ReadCompleted { bytes_read, more } => {
+                    self.state = match more {
+                        Some(address) => State::ReadRequested { address },
+                        None => State::Idle,
+                    };
+                    return Ok(TransportEvent::ReadCompleted(bytes_read));
+                }
+                State::ReadRequested { address } => {
+                    self.state = State::Idle;
+                    return Ok(TransportEvent::ReadRequested {
+                        address,
+                        len: I2C_BUFFER_LEN,
+                    });
+                }
+            }
+        }
+    }
+
+    async fn respond(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        // Something has to be sent, and a floating bus reads `0xFF`
+        let bytes = if bytes.is_empty() { &[0xFF] } else { bytes };
+        for (i, &byte) in bytes.iter().enumerate() {
+            if !self.send_byte(byte).await? {
+                self.state = State::ReadCompleted {
+                    bytes_read: i + 1,
+                    more: None,
+                };
+                return Ok(());
+            }
+        }
+        // The controller wants more, so stretch the clock until the next response
+        self.scl.set_as_output(PinState::Low);
+        self.state = State::ReadCompleted {
+            bytes_read: bytes.len(),
+            more: Some(self.address),
+        };
+        Ok(())
+    }
+}
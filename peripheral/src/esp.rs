@@ -0,0 +1,93 @@
+use crate::*;
+use esp_hal::gpio::{DriveMode, Flex, InputConfig, Level, OutputConfig, Pull};
+use mcp23017_common::InterruptMode;
+
+/// Every ESP32 GPIO can wait for a level, so every emulated pin is a `Flex`
+pub struct EspGpioPin<'d> {
+    pin: Flex<'d>,
+}
+
+impl<'d> EspGpioPin<'d> {
+    pub fn new(pin: Flex<'d>) -> Self {
+        Self { pin }
+    }
+}
+
+impl GpioPin for EspGpioPin<'_> {
+    fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
+        match io_direction {
+            IoDirection::Output => {
+                self.pin.set_level(Level::from(bool::from(level)));
+                self.pin.set_output_enable(true);
+            }
+            IoDirection::Input => {
+                self.pin.set_output_enable(false);
+                self.pin
+                    .apply_input_config(&InputConfig::default().with_pull(if pull_up_enabled {
+                        Pull::Up
+                    } else {
+                        Pull::None
+                    }));
+                self.pin.set_input_enable(true);
+            }
+        }
+    }
+
+    fn level(&self) -> PinState {
+        bool::from(self.pin.level()).into()
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        match level {
+            PinState::High => self.pin.wait_for_high().await,
+            PinState::Low => self.pin.wait_for_low().await,
+        }
+    }
+}
+
+/// Uses the open-drain drive mode of the GPIO for [`InterruptMode::OpenDrain`]
+pub struct EspInterruptPin<'d> {
+    pin: Flex<'d>,
+}
+
+impl<'d> EspInterruptPin<'d> {
+    pub fn new(pin: Flex<'d>) -> Self {
+        Self { pin }
+    }
+}
+
+impl InterruptPin for EspInterruptPin<'_> {
+    fn configure(&mut self, mode: InterruptMode, level: PinState) {
+        info!("setting interrupt pin level to {}", Debug2Format(&level));
+        self.pin
+            .apply_output_config(&OutputConfig::default().with_drive_mode(match mode {
+                InterruptMode::OpenDrain => DriveMode::OpenDrain,
+                InterruptMode::ActiveDriver => DriveMode::PushPull,
+            }));
+        self.pin.set_level(Level::from(bool::from(level)));
+        self.pin.set_output_enable(true);
+    }
+}
+
+impl FlexPin for Flex<'_> {
+    fn set_as_input(&mut self) {
+        self.set_output_enable(false);
+        self.apply_input_config(&InputConfig::default().with_pull(Pull::None));
+        self.set_input_enable(true);
+    }
+
+    fn set_as_output(&mut self, level: PinState) {
+        self.set_level(Level::from(bool::from(level)));
+        // The input stays enabled, so the transport can still read the line
+        self.set_output_enable(true);
+    }
+}
+
+/// `esp-hal` doesn't have an I2C slave driver yet, so this bit-bangs the bus with two `Flex` pins.
+/// See [`BitBangI2cTransport`] for its limits. Once `esp-hal` has a driver, a transport for the
+/// I2C peripheral can replace this.
+pub type EspI2cTransport<'d> = BitBangI2cTransport<Flex<'d>>;
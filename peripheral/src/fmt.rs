@@ -0,0 +1,49 @@
+//! Logging macros that log with `defmt`, `log`, both, or neither, depending on the features.
+//! Use `{:?}` for anything that isn't a number or a string, because it works with both.
+
+use core::fmt;
+
+macro_rules! log_with {
+    ($level:ident, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::$level!($fmt $(, $arg)*);
+        #[cfg(feature = "log")]
+        log::$level!($fmt $(, $arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$arg,)*);
+    }};
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        log_with!(trace, $($arg)*)
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        log_with!(info, $($arg)*)
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        log_with!(warn, $($arg)*)
+    };
+}
+
+/// Logs a value that only implements [`Debug`](fmt::Debug) with `{}`, with both `defmt` and `log`
+pub(crate) struct Debug2Format<'a, T: ?Sized>(pub &'a T);
+
+impl<T: fmt::Debug + ?Sized> fmt::Display for Debug2Format<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: fmt::Debug + ?Sized> defmt::Format for Debug2Format<'_, T> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Debug2Format(self.0))
+    }
+}
@@ -0,0 +1,80 @@
+use core::future::pending;
+
+use crate::*;
+
+/// An input pin whose level comes from `read`, for example to show a sensor threshold or any
+/// other state of the micro controller to the controller as an input pin.
+/// Configuration is ignored. The emulator polls `read`, so it should be cheap.
+pub struct FnGpioPin<F> {
+    read: F,
+}
+
+impl<F: Fn() -> PinState> FnGpioPin<F> {
+    pub const fn new(read: F) -> Self {
+        Self { read }
+    }
+}
+
+impl<F: Fn() -> PinState> GpioPin for FnGpioPin<F> {
+    fn configure(&mut self, _io_direction: IoDirection, _pull_up_enabled: bool, _level: PinState) {}
+
+    fn level(&self) -> PinState {
+        (self.read)()
+    }
+
+    fn can_wait(&mut self) -> bool {
+        false
+    }
+
+    async fn wait_for_level(&mut self, _level: PinState) {
+        // Not called, because the pin can't wait
+        pending().await
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        PinCapabilities::INPUT
+    }
+}
+
+/// An input pin whose level comes from the async `read`, for sources that need I/O or have to
+/// wait for something. It is a slow pin (see [`GpioPin::is_slow`]), so register reads use the
+/// last level that was read.
+/// Configuration is ignored. The emulator polls `read`.
+pub struct AsyncFnGpioPin<F> {
+    read: F,
+}
+
+impl<F: AsyncFnMut() -> PinState> AsyncFnGpioPin<F> {
+    pub const fn new(read: F) -> Self {
+        Self { read }
+    }
+}
+
+impl<F: AsyncFnMut() -> PinState> GpioPin for AsyncFnGpioPin<F> {
+    fn configure(&mut self, _io_direction: IoDirection, _pull_up_enabled: bool, _level: PinState) {}
+
+    fn level(&self) -> PinState {
+        unreachable!("slow pins are read with read_level")
+    }
+
+    fn can_wait(&mut self) -> bool {
+        false
+    }
+
+    async fn wait_for_level(&mut self, _level: PinState) {
+        // Not called, because the pin can't wait
+        pending().await
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        PinCapabilities::INPUT
+    }
+
+    fn is_slow(&self) -> bool {
+        true
+    }
+
+    async fn read_level(&mut self) -> PinState {
+        (self.read)().await
+    }
+}
@@ -1,18 +1,246 @@
+use core::future::pending;
+
+use embassy_time::Duration;
 pub use embedded_hal::digital::PinState;
 use mcp23017_common::InterruptMode;
 pub use mcp23017_common::IoDirection;
 
+/// How often the emulators read pins that can't wait (see [`GpioPin::can_wait`]), unless it is
+/// changed for the pin
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// What a [`GpioPin`] can be configured as. See [`GpioPin::capabilities`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinCapabilities {
+    pub input: bool,
+    pub output: bool,
+    /// `None` if the pull-up can be changed, or whether the pin is always pulled up
+    pub fixed_pull_up: Option<bool>,
+}
+
+impl PinCapabilities {
+    /// A pin that can be configured in every way
+    pub const ALL: Self = Self {
+        input: true,
+        output: true,
+        fixed_pull_up: None,
+    };
+    /// A pin that can only be an input
+    pub const INPUT: Self = Self {
+        input: true,
+        output: false,
+        fixed_pull_up: None,
+    };
+    /// A pin that can only be an output
+    pub const OUTPUT: Self = Self {
+        input: false,
+        output: true,
+        fixed_pull_up: None,
+    };
+
+    /// Whether the pin can honor the configuration. A disabled pull-up on a pin that is always
+    /// pulled up is fine, because a floating input can read anything.
+    pub fn supports(&self, io_direction: IoDirection, pull_up_enabled: bool) -> bool {
+        match io_direction {
+            IoDirection::Output => self.output,
+            IoDirection::Input => {
+                self.input && !(pull_up_enabled && self.fixed_pull_up == Some(false))
+            }
+        }
+    }
+}
+
 pub trait GpioPin {
     fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState);
-    /// This function will not be called if this pin is configured to be in output mode.
+    /// This function will not be called if this pin is configured to be in output mode, unless
+    /// [`crate::Mcp23017::set_read_output_levels`] is enabled.
     fn level(&self) -> PinState;
     /// Returns if the pin is capable of receiving interrupts (in input mode).
+    /// If not, the emulator reads the pin every [`DEFAULT_POLL_INTERVAL`], or the interval that
+    /// was set for the pin.
     fn can_wait(&mut self) -> bool;
     /// Returns when the pin's level becomes the specified level.
     /// This function will never be called if `can_wait` returns `false`.
     fn wait_for_level(&mut self, level: PinState) -> impl Future<Output = ()>;
+    /// Return `true` if reading the pin needs I/O, for example a pin forwarded over another bus in
+    /// a hardware-in-the-loop rig. Then [`Self::level`] is never called, and register reads use
+    /// the last level from [`Self::read_level`], because the I2C response can't wait for I/O.
+    /// See [`crate::Mcp23017::sample_pins`].
+    fn is_slow(&self) -> bool {
+        false
+    }
+    /// Reads the level, which can do I/O. Only called if [`Self::is_slow`] returns `true`.
+    fn read_level(&mut self) -> impl Future<Output = PinState> {
+        async { self.level() }
+    }
+    /// What the pin can be configured as. The emulator still calls [`Self::configure`] with
+    /// configurations that aren't supported, and reports them. See
+    /// [`crate::Mcp23017Event::UnsupportedConfig`].
+    fn capabilities(&self) -> PinCapabilities {
+        PinCapabilities::ALL
+    }
 }
 
 pub trait InterruptPin {
     fn configure(&mut self, mode: InterruptMode, level: PinState);
 }
+
+/// A [`GpioPin`] for emulated pins that aren't wired to anything.
+/// It ignores configuration and always reads `level`.
+/// It can wait, so the emulator doesn't poll it, but its level never changes.
+pub struct NoopGpioPin {
+    pub level: PinState,
+}
+
+impl NoopGpioPin {
+    pub const fn new(level: PinState) -> Self {
+        Self { level }
+    }
+}
+
+impl GpioPin for NoopGpioPin {
+    fn configure(&mut self, _io_direction: IoDirection, _pull_up_enabled: bool, _level: PinState) {}
+
+    fn level(&self) -> PinState {
+        self.level
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        if level != self.level {
+            pending().await
+        }
+    }
+}
+
+/// An [`InterruptPin`] that isn't wired to anything, for example for
+/// [`crate::Mcp23017::passive`]
+pub struct NoopInterruptPin;
+
+impl InterruptPin for NoopInterruptPin {
+    fn configure(&mut self, _mode: InterruptMode, _level: PinState) {}
+}
+
+/// The emulator needs 16 pins of the same type, so use this to mix real pins with
+/// [`NoopGpioPin`]s
+pub enum MaybeGpioPin<P> {
+    Pin(P),
+    Noop(NoopGpioPin),
+}
+
+impl<P: GpioPin> GpioPin for MaybeGpioPin<P> {
+    fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
+        match self {
+            Self::Pin(pin) => pin.configure(io_direction, pull_up_enabled, level),
+            Self::Noop(pin) => pin.configure(io_direction, pull_up_enabled, level),
+        }
+    }
+
+    fn level(&self) -> PinState {
+        match self {
+            Self::Pin(pin) => pin.level(),
+            Self::Noop(pin) => pin.level(),
+        }
+    }
+
+    fn can_wait(&mut self) -> bool {
+        match self {
+            Self::Pin(pin) => pin.can_wait(),
+            Self::Noop(pin) => pin.can_wait(),
+        }
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        match self {
+            Self::Pin(pin) => pin.wait_for_level(level).await,
+            Self::Noop(pin) => pin.wait_for_level(level).await,
+        }
+    }
+
+    fn is_slow(&self) -> bool {
+        match self {
+            Self::Pin(pin) => pin.is_slow(),
+            Self::Noop(pin) => pin.is_slow(),
+        }
+    }
+
+    async fn read_level(&mut self) -> PinState {
+        match self {
+            Self::Pin(pin) => pin.read_level().await,
+            Self::Noop(pin) => pin.read_level().await,
+        }
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        match self {
+            Self::Pin(pin) => pin.capabilities(),
+            Self::Noop(pin) => pin.capabilities(),
+        }
+    }
+}
+
+/// A [`GpioPin`] whose levels can be inverted, for boards with inverting transistor drivers or
+/// active-low wiring. The controller sees the logical level, and `pin` gets the physical level.
+/// This is independent of the emulated `IPOL` register.
+pub struct InvertiblePin<P> {
+    pub pin: P,
+    pub inverted: bool,
+}
+
+impl<P> InvertiblePin<P> {
+    pub const fn new(pin: P, inverted: bool) -> Self {
+        Self { pin, inverted }
+    }
+
+    /// Converts between the logical and the physical level, which is the same both ways
+    fn convert(&self, level: PinState) -> PinState {
+        if self.inverted { !level } else { level }
+    }
+}
+
+impl<P: GpioPin> GpioPin for InvertiblePin<P> {
+    fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
+        let level = self.convert(level);
+        self.pin.configure(io_direction, pull_up_enabled, level);
+    }
+
+    fn level(&self) -> PinState {
+        self.convert(self.pin.level())
+    }
+
+    fn can_wait(&mut self) -> bool {
+        self.pin.can_wait()
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        let level = self.convert(level);
+        self.pin.wait_for_level(level).await
+    }
+
+    fn is_slow(&self) -> bool {
+        self.pin.is_slow()
+    }
+
+    async fn read_level(&mut self) -> PinState {
+        let level = self.pin.read_level().await;
+        self.convert(level)
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        self.pin.capabilities()
+    }
+}
+
+/// Orders pins for the emulator with a table, because PCB routing rarely matches the order of
+/// the expander's pins. `map[i]` is the index in `pins` of the pin to use for emulated pin `i`
+/// (`A0`..`A7` then `B0`..`B7` for [`crate::Mcp23017`]).
+///
+/// Panics if a pin is used more than once.
+pub fn remap_pins<P, const N: usize>(pins: [P; N], map: [usize; N]) -> [P; N] {
+    let mut pins = pins.map(Some);
+    map.map(|i| pins[i].take().expect("a pin was used more than once"))
+}
@@ -0,0 +1,135 @@
+use core::{cell::RefCell, convert::Infallible, future};
+
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+
+use crate::*;
+
+/// A [`GpioPin`] for any embedded-hal pin that can be read and written, but not switched between
+/// input and output, like an open-drain pin with an external pull-up.
+/// In input mode, the pin is set high so that something else can drive it.
+/// The pull-up can't be changed. The pin can't fail, which is the case for the pins of most HALs.
+pub struct HalGpioPin<T> {
+    pin: RefCell<T>,
+}
+
+impl<T> HalGpioPin<T> {
+    pub fn new(pin: T) -> Self {
+        Self {
+            pin: RefCell::new(pin),
+        }
+    }
+
+    pub fn into_pin(self) -> T {
+        self.pin.into_inner()
+    }
+}
+
+impl<T: InputPin<Error = Infallible> + OutputPin + Wait> GpioPin for HalGpioPin<T> {
+    fn configure(&mut self, io_direction: IoDirection, _pull_up_enabled: bool, level: PinState) {
+        let pin = self.pin.get_mut();
+        let Ok(()) = match io_direction {
+            IoDirection::Output => OutputPin::set_state(pin, level),
+            IoDirection::Input => OutputPin::set_high(pin),
+        };
+    }
+
+    fn level(&self) -> PinState {
+        let Ok(high) = InputPin::is_high(&mut *self.pin.borrow_mut());
+        high.into()
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        let pin = self.pin.get_mut();
+        let Ok(()) = match level {
+            PinState::High => pin.wait_for_high().await,
+            PinState::Low => pin.wait_for_low().await,
+        };
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        PinCapabilities {
+            fixed_pull_up: Some(true),
+            ..PinCapabilities::ALL
+        }
+    }
+}
+
+/// A [`GpioPin`] for an embedded-hal pin that is either an input or an output, and stays that way.
+/// Configuring it in the other direction is ignored, like with the input-only STM32 pins.
+/// The pins can't fail, which is the case for the pins of most HALs.
+pub enum HalFixedDirectionPin<In, Out> {
+    Input(RefCell<In>),
+    Output(Out),
+}
+
+impl<In, Out> HalFixedDirectionPin<In, Out> {
+    pub fn new_input(pin: In) -> Self {
+        Self::Input(RefCell::new(pin))
+    }
+
+    pub fn new_output(pin: Out) -> Self {
+        Self::Output(pin)
+    }
+}
+
+impl<In: InputPin<Error = Infallible> + Wait, Out: OutputPin<Error = Infallible>> GpioPin
+    for HalFixedDirectionPin<In, Out>
+{
+    fn configure(&mut self, io_direction: IoDirection, _pull_up_enabled: bool, level: PinState) {
+        match (self, io_direction) {
+            (Self::Output(pin), IoDirection::Output) => {
+                let Ok(()) = pin.set_state(level);
+            }
+            (Self::Input(_), IoDirection::Input) => {}
+            _ => {
+                warn!("Tried to change the direction of a fixed direction pin")
+            }
+        }
+    }
+
+    fn level(&self) -> PinState {
+        match self {
+            Self::Input(pin) => {
+                let Ok(high) = InputPin::is_high(&mut *pin.borrow_mut());
+                high.into()
+            }
+            Self::Output(_) => {
+                warn!("Tried to read an output-only pin");
+                PinState::Low
+            }
+        }
+    }
+
+    fn can_wait(&mut self) -> bool {
+        matches!(self, Self::Input(_))
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        match self {
+            Self::Input(pin) => {
+                let pin = pin.get_mut();
+                let Ok(()) = match level {
+                    PinState::High => pin.wait_for_high().await,
+                    PinState::Low => pin.wait_for_low().await,
+                };
+            }
+            Self::Output(_) => {
+                warn!("Tried to wait for the level of an output-only pin");
+                // The level of an output only changes when it's configured
+                future::pending().await
+            }
+        }
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        match self {
+            Self::Input(_) => PinCapabilities::INPUT,
+            Self::Output(_) => PinCapabilities::OUTPUT,
+        }
+    }
+}
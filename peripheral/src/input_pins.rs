@@ -0,0 +1,85 @@
+use core::{future::pending, iter::zip};
+
+use collect_array_ext_trait::CollectArray;
+use embassy_futures::select::select_array;
+use embassy_time::{Duration, Timer};
+
+use crate::*;
+
+/// The pins of [`Pcf8574`] and [`Pca9555`], which interrupt when an input changes from the level
+/// that was last read, without `DEFVAL` or debouncing.
+pub(crate) struct InputPins<P, const N: usize> {
+    pub(crate) pins: [P; N],
+    /// The last levels of slow pins (see [`GpioPin::is_slow`])
+    samples: [PinState; N],
+    /// Not reset with the chip
+    poll_intervals: [Duration; N],
+}
+
+impl<P: GpioPin, const N: usize> InputPins<P, N> {
+    pub(crate) fn new(pins: [P; N]) -> Self {
+        Self {
+            pins,
+            samples: [PinState::High; N],
+            poll_intervals: [DEFAULT_POLL_INTERVAL; N],
+        }
+    }
+
+    /// The level of the pin, or the last sample if the pin is slow
+    pub(crate) fn level(&self, pin_index: usize) -> PinState {
+        let pin = &self.pins[pin_index];
+        if pin.is_slow() {
+            self.samples[pin_index]
+        } else {
+            pin.level()
+        }
+    }
+
+    pub(crate) fn set_poll_interval(&mut self, pin_index: usize, poll_interval: Duration) {
+        self.poll_intervals[pin_index] = poll_interval;
+    }
+
+    /// Reads the level of every slow pin
+    pub(crate) async fn sample(&mut self) {
+        for (pin, sample) in zip(&mut self.pins, &mut self.samples) {
+            if pin.is_slow() {
+                *sample = pin.read_level().await;
+            }
+        }
+    }
+
+    /// Waits until one of the pins that `watched` returns `true` for changes from its level in
+    /// `known_levels`, and returns its new level and index. The sample of the pin is updated.
+    pub(crate) async fn wait_for_change(
+        &mut self,
+        known_levels: &[PinState; N],
+        watched: impl Fn(usize) -> bool,
+    ) -> (PinState, usize) {
+        let poll_intervals = &self.poll_intervals;
+        let (level, index) = select_array(
+            self.pins
+                .iter_mut()
+                .enumerate()
+                .map(async |(i, pin)| {
+                    if watched(i) {
+                        let known_level = known_levels[i];
+                        if pin.can_wait() {
+                            pin.wait_for_level(!known_level).await;
+                        } else {
+                            while read_level(pin).await == known_level {
+                                Timer::after(poll_intervals[i]).await;
+                            }
+                        }
+                        !known_level
+                    } else {
+                        pending().await
+                    }
+                })
+                .collect_array::<N>()
+                .unwrap(),
+        )
+        .await;
+        self.samples[index] = level;
+        (level, index)
+    }
+}
@@ -1,10 +1,46 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+#[macro_use]
+mod fmt;
+mod address;
+mod bank;
+mod bit_bang;
+#[cfg(feature = "esp")]
+pub mod esp;
+mod fn_pin;
 mod gpio_pin;
+mod hal_pin;
+mod input_pins;
 mod mcp23017;
+#[cfg(feature = "nrf")]
+pub mod nrf;
+mod open_drain;
+mod pca9555;
+mod pcf8574;
+mod record;
 mod reset_pin;
+#[cfg(feature = "rp")]
+pub mod rp;
+mod spi;
 #[cfg(feature = "stm32")]
 pub mod stm32;
+mod transport;
+mod virtual_pin;
 
+pub use address::*;
+pub use bank::*;
+pub use bit_bang::*;
+pub use fn_pin::*;
 pub use gpio_pin::*;
+pub use hal_pin::*;
 pub use mcp23017::*;
+pub use open_drain::*;
+pub use pca9555::*;
+pub use pcf8574::*;
+pub use record::*;
 pub use reset_pin::*;
+pub use spi::*;
+pub use transport::*;
+pub use virtual_pin::*;
+
+use fmt::Debug2Format;
+use input_pins::InputPins;
@@ -1,10 +1,9 @@
-use core::{future::pending, iter::zip, mem};
+use core::{array, future::pending, mem};
 
 use collect_array_ext_trait::CollectArray;
-use embassy_futures::{
-    select::{select, select_array},
-    yield_now,
-};
+use embassy_futures::select::{select, select_array};
+use embassy_sync::{blocking_mutex::raw::RawMutex, channel::DynamicSender, signal::Signal};
+use embassy_time::{Duration, Timer};
 use embedded_hal::digital::PinState;
 use embedded_hal_async::digital::Wait;
 use mcp23017_common::{
@@ -13,11 +12,14 @@ use mcp23017_common::{
 use strum::{AsRefStr, Display, EnumCount, VariantArray, VariantNames};
 
 use crate::{
-    InterruptPin,
-    gpio_pin::{GpioPin, IoDirection},
-    reset_pin::ResetPin,
+    Debug2Format, EmulatedChip, GENERAL_CALL_RESET, InterruptPin,
+    gpio_pin::{DEFAULT_POLL_INTERVAL, GpioPin, IoDirection, PinCapabilities},
+    reset_pin::{NoResetPin, ResetPin},
 };
 
+/// The number of registers, which is the length of [`Mcp23017::dump_registers`]
+pub const N_REGISTERS: usize = RegisterType::COUNT * AB::COUNT;
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Display, VariantNames, AsRefStr)]
 #[strum(serialize_all = "snake_case")]
@@ -31,7 +33,127 @@ enum PinProperty {
     CompareValue,
 }
 
-pub struct Mcp23017<P, I, R> {
+/// Whether a [`RegisterAccess`] was a read or a write
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A register read or write by the controller. See [`Mcp23017Event::Accessed`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterAccess {
+    pub kind: AccessKind,
+    pub register: Register,
+    /// The value that was read or written
+    pub value: u8,
+    /// The address pointer that selected the register, which depends on `IOCON.BANK`
+    pub address: u8,
+}
+
+/// The new configuration of a pin whose `IODIR` or `OLAT` bit the controller changed. See
+/// [`Mcp23017Event::PinChanged`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinChange {
+    pub pin_index: usize,
+    pub io_direction: IoDirection,
+    pub output_latch: bool,
+}
+
+/// A configuration that the controller asked for, but the pin can't do. See
+/// [`Mcp23017Event::UnsupportedConfig`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedConfig {
+    pub pin_index: usize,
+    pub io_direction: IoDirection,
+    pub pull_up_enabled: bool,
+    pub capabilities: PinCapabilities,
+}
+
+/// Something that the emulator did. See [`Mcp23017::set_event_sender`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp23017Event {
+    /// The controller wrote a register. The values are what the register reads, so for `GPIO`
+    /// they are the levels of the pins.
+    RegisterWritten {
+        register: Register,
+        old: u8,
+        new: u8,
+    },
+    /// `INTF` was set for the pin, which changed to `level`
+    InterruptRaised { pin: usize, level: bool },
+    /// The controller wrote or read a register, for example to record what the controller did in
+    /// a test. Reads are sent when they are confirmed with [`Mcp23017::confirm_bytes_read`], so
+    /// only bytes that the controller actually read are sent.
+    Accessed(RegisterAccess),
+    /// The controller changed the `IODIR` or `OLAT` bit of a pin, for example when the emulated
+    /// pins control peripherals of the micro controller, which should react to commands instead
+    /// of polling [`Mcp23017::save_state`]
+    PinChanged(PinChange),
+    /// A pin was configured in a way that it doesn't support (see [`GpioPin::capabilities`]),
+    /// for example when the controller makes an input-only pin an output. Then the application
+    /// can surface the problem instead of the controller silently reading the wrong levels.
+    UnsupportedConfig(UnsupportedConfig),
+}
+
+/// How often the controller accessed each register. See [`Mcp23017::metrics`].
+/// Counters saturate instead of overflowing.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccessMetrics {
+    /// Ordered like [`Mcp23017::dump_registers`]. Only bytes that the controller actually read are
+    /// counted.
+    pub reads: [u32; N_REGISTERS],
+    /// Ordered like [`Mcp23017::dump_registers`]
+    pub writes: [u32; N_REGISTERS],
+    /// Reads of addresses that aren't a register
+    pub invalid_reads: u32,
+    /// Writes to addresses that aren't a register
+    pub invalid_writes: u32,
+}
+
+impl AccessMetrics {
+    pub fn reads(&self, register: Register) -> u32 {
+        self.reads[register.address(false) as usize]
+    }
+
+    pub fn writes(&self, register: Register) -> u32 {
+        self.writes[register.address(false) as usize]
+    }
+}
+
+/// Everything the emulator remembers, without the pins. See [`Mcp23017::save_state`].
+/// Compare two of these to diff emulators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mcp23017State {
+    bank_mode: bool,
+    mirror_interrupts: bool,
+    sequential_disabled: bool,
+    slew_rate_disabled: bool,
+    hardware_address_enabled: bool,
+    int_mode: InterruptMode,
+    int_active_state: PinState,
+    selected_address: u8,
+    io_directions: u16,
+    pull_up_enabled: u16,
+    output_latches: u16,
+    gpio_inverted: u16,
+    int_enabled: u16,
+    int_compare: u16,
+    interrupt_control: u16,
+    int_flags: u16,
+    int_captured_value: u16,
+    known_input_states: u16,
+}
+
+/// The per-pin state is kept in `u16`s with a bit for every pin, where bit `i` is pin `i`, so
+/// port `A` is the low byte. Register accesses are mask operations on these.
+pub struct Mcp23017<P, I, R = NoResetPin> {
     gpio_pins: [P; N_TOTAL_GPIO_PINS],
     interrupt_pins: [I; AB::COUNT],
     /// If you can, directly use your micro controller's RESET pin.
@@ -44,27 +166,74 @@ pub struct Mcp23017<P, I, R> {
     /// If set, an interrupt from any GPIO will trigger both interrupt pins
     mirror_interrupts: bool,
     /// IOCON.SEQOP
-    sequential_mode: bool,
+    sequential_disabled: bool,
+    /// IOCON.DISSLW
+    /// Only stored so that it can be read back
+    slew_rate_disabled: bool,
+    /// IOCON.HAEN
+    /// Only used by the SPI front-end, because I2C always uses the address pins
+    hardware_address_enabled: bool,
     /// IOCON.ODR
     int_mode: InterruptMode,
     /// IOCON.INTPOL
     int_active_state: PinState,
 
     selected_address: u8,
-    /// Corresponds to the `IODIR` bit
-    io_directions: [IoDirection; N_TOTAL_GPIO_PINS],
-    pull_up_enabled: [bool; N_TOTAL_GPIO_PINS],
-    output_latches: [PinState; N_TOTAL_GPIO_PINS],
-    gpio_inverted: [bool; N_TOTAL_GPIO_PINS],
-    int_enabled: [bool; N_TOTAL_GPIO_PINS],
-    int_compare: [PinState; N_TOTAL_GPIO_PINS],
-    interrupt_control: [InterruptControl; N_TOTAL_GPIO_PINS],
-    /// Can only be cleared by reading the GPIO or captured pin state
-    int_flags: [bool; N_TOTAL_GPIO_PINS],
-    int_captured_value: [PinState; N_TOTAL_GPIO_PINS],
+    /// `IODIR`, where `1` is an input
+    io_directions: u16,
+    /// `GPPU`
+    pull_up_enabled: u16,
+    /// `OLAT`
+    output_latches: u16,
+    /// `IPOL`
+    gpio_inverted: u16,
+    /// `GPINTEN`
+    int_enabled: u16,
+    /// `DEFVAL`
+    int_compare: u16,
+    /// `INTCON`, where `1` compares with `DEFVAL`
+    interrupt_control: u16,
+    /// `INTF`. Can only be cleared by reading the GPIO or captured pin state
+    int_flags: u16,
+    /// `INTCAP`
+    int_captured_value: u16,
     /// This is not a register but I think the chip needs to keep track of this in order to
     /// interrupt-on-change. It needs to know what the last known state is.
-    known_input_states: [PinState; N_TOTAL_GPIO_PINS],
+    known_input_states: u16,
+    /// Not reset by [`Self::reset`]
+    event_sender: Option<DynamicSender<'static, Mcp23017Event>>,
+    /// Not reset by [`Self::reset`]
+    read_output_levels: bool,
+    /// The last levels of slow pins (see [`GpioPin::is_slow`]), or of every pin in passive mode,
+    /// where they are the last levels read on the bus
+    samples: u16,
+    /// What `GPIO` reads, taken when a read is prepared so that the side effects of the read use
+    /// the same levels that were sent
+    gpio_snapshot: u16,
+    /// Not reset by [`Self::reset`]
+    debounce: [Option<Duration>; N_TOTAL_GPIO_PINS],
+    /// Not reset by [`Self::reset`]
+    propagation_delays: [Option<Duration>; N_TOTAL_GPIO_PINS],
+    /// Not reset by [`Self::reset`]
+    poll_intervals: [Duration; N_TOTAL_GPIO_PINS],
+    /// Levels that are read instead of the pins. Not reset by [`Self::reset`]
+    forced_levels: [Option<PinState>; N_TOTAL_GPIO_PINS],
+    /// Not reset by [`Self::reset`]
+    metrics: AccessMetrics,
+    /// See [`Self::strict`]
+    strict: bool,
+    /// See [`Self::passive`]
+    passive: bool,
+}
+
+impl<P: GpioPin, I: InterruptPin> Mcp23017<P, I> {
+    /// For when RESET isn't wired. [`Self::reset`] can still be called to reset the chip.
+    pub fn new_without_reset_pin(
+        gpio_pins: [P; N_TOTAL_GPIO_PINS],
+        interrupt_pins: [I; AB::COUNT],
+    ) -> Self {
+        Self::new(gpio_pins, interrupt_pins, NoResetPin)
+    }
 }
 
 impl<P: GpioPin, I: InterruptPin, R: Wait> Mcp23017<P, I, R> {
@@ -79,44 +248,106 @@ impl<P: GpioPin, I: InterruptPin, R: Wait> Mcp23017<P, I, R> {
             reset: ResetPin::new(reset_pin),
             bank_mode: false,
             mirror_interrupts: false,
-            sequential_mode: false,
+            sequential_disabled: false,
+            slew_rate_disabled: false,
+            hardware_address_enabled: false,
             int_mode: InterruptMode::ActiveDriver,
             int_active_state: PinState::Low,
             selected_address: 0,
-            io_directions: [IoDirection::Input; _],
-            pull_up_enabled: [false; _],
-            output_latches: [PinState::Low; _],
-            gpio_inverted: [false; _],
-            int_enabled: [false; _],
-            int_compare: [PinState::Low; _],
-            interrupt_control: [InterruptControl::CompareWithPreviousValue; _],
-            int_flags: [false; _],
-            int_captured_value: [PinState::Low; _],
-            known_input_states: [PinState::Low; _],
+            io_directions: ALL_PINS,
+            pull_up_enabled: 0,
+            output_latches: 0,
+            gpio_inverted: 0,
+            int_enabled: 0,
+            int_compare: 0,
+            interrupt_control: 0,
+            int_flags: 0,
+            int_captured_value: 0,
+            known_input_states: 0,
+            event_sender: None,
+            read_output_levels: false,
+            samples: 0,
+            gpio_snapshot: 0,
+            debounce: [None; _],
+            propagation_delays: [None; _],
+            poll_intervals: [DEFAULT_POLL_INTERVAL; _],
+            forced_levels: [None; _],
+            metrics: AccessMetrics::default(),
+            strict: false,
+            passive: false,
         };
         s.update_all_pins();
         s.update_interrupts();
         s
     }
 
+    /// Makes the emulator reproduce documented quirks of the real chip, instead of the idealized
+    /// behavior, for validating a controller against real silicon. Call it right after
+    /// constructing the emulator, for example `Mcp23017::new(..).strict()`.
+    ///
+    /// In strict mode:
+    /// - `GPA7` and `GPB7` are output only, like the current datasheet says. `IODIR` still reads
+    ///   back what was written, but the pins stay outputs, `GPIO` reads `OLAT` for them, and they
+    ///   never interrupt.
+    /// - `IOCON.ODR` overrides `IOCON.INTPOL`, so an open-drain `INT` is always active low.
+    ///
+    /// The unimplemented bit 0 of `IOCON` reads `0`, and a burst that changes `IOCON.BANK`
+    /// continues at the address after `IOCON` in the new mapping, in every mode, because that's
+    /// what the real chip does. Effects of the order in which `INTCAP` and `GPIO` are read aren't
+    /// reproduced: both clear the interrupt, like the datasheet says.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self.update_all_pins();
+        self.update_interrupts();
+        self
+    }
+
+    /// Makes the emulator a passive bus monitor, which decodes the transactions between a
+    /// controller and a real chip, for debugging third-party controllers. It tracks the address
+    /// pointer and the registers like the real chip, but never configures the pins or the
+    /// interrupt pins, so use [`NoopGpioPin`]s and [`NoopInterruptPin`]s. Call it right after
+    /// constructing the emulator.
+    ///
+    /// Pass writes to [`Self::process_write_transaction`] and the bytes that the real chip sent
+    /// to [`Self::observe_read`]. Observe the decoded accesses with [`Self::set_event_sender`]. [`Self::run`] isn't needed.
+    ///
+    /// [`NoopGpioPin`]: crate::NoopGpioPin
+    /// [`NoopInterruptPin`]: crate::NoopInterruptPin
+    pub fn passive(mut self) -> Self {
+        self.passive = true;
+        self
+    }
+
+    /// The pins that can't be inputs, see [`Self::strict`]
+    fn output_only_pins(&self) -> u16 {
+        if self.strict { OUTPUT_ONLY_PINS } else { 0 }
+    }
+
+    /// The pins that are inputs. This is `IODIR`, without the output only pins.
+    fn inputs(&self) -> u16 {
+        self.io_directions & !self.output_only_pins()
+    }
+
     /// Init / reset everything to initial values
     pub fn reset(&mut self) {
         self.bank_mode = false;
         self.mirror_interrupts = false;
-        self.sequential_mode = false;
+        self.sequential_disabled = false;
+        self.slew_rate_disabled = false;
+        self.hardware_address_enabled = false;
         self.int_mode = InterruptMode::ActiveDriver;
         self.int_active_state = PinState::Low;
         self.selected_address = 0;
-        self.io_directions = [IoDirection::Input; _];
-        self.pull_up_enabled = [false; _];
-        self.output_latches = [PinState::Low; _];
-        self.gpio_inverted = [false; _];
-        self.int_enabled = [false; _];
-        self.int_compare = [PinState::Low; _];
-        self.interrupt_control = [InterruptControl::CompareWithPreviousValue; _];
-        self.int_flags = [false; _];
-        self.int_captured_value = [PinState::Low; _];
-        self.known_input_states = [PinState::Low; _];
+        self.io_directions = ALL_PINS;
+        self.pull_up_enabled = 0;
+        self.output_latches = 0;
+        self.gpio_inverted = 0;
+        self.int_enabled = 0;
+        self.int_compare = 0;
+        self.interrupt_control = 0;
+        self.int_flags = 0;
+        self.int_captured_value = 0;
+        self.known_input_states = 0;
         self.update_all_pins();
         self.update_interrupts();
     }
@@ -128,8 +359,10 @@ impl<P: GpioPin, I: InterruptPin, R: Wait> Mcp23017<P, I, R> {
     }
 
     fn advance_address_mode(&self) -> AdvanceAddressMode {
-        if self.sequential_mode {
-            AdvanceAddressMode::Cycle
+        if !self.sequential_disabled {
+            AdvanceAddressMode::Cycle {
+                bank_mode: self.bank_mode,
+            }
         } else if !self.bank_mode {
             AdvanceAddressMode::Toggle
         } else {
@@ -145,30 +378,169 @@ impl<P: GpioPin, I: InterruptPin, R: Wait> Mcp23017<P, I, R> {
         if let Some(&address) = bytes.first() {
             self.selected_address = address;
             for &byte in &bytes[1..] {
-                if let Some(register) =
-                    Register::from_address(self.selected_address, self.bank_mode)
-                {
-                    self.write_register(register, byte);
-                } else {
-                    #[cfg(feature = "defmt")]
-                    defmt::warn!(
-                        "Attempted to write to invalid register address: {}. Not doing anything.",
-                        self.selected_address
-                    );
-                }
-                self.advance_address();
+                self.write_selected_register(byte);
             }
         }
     }
 
+    /// Writes the selected register and advances the address
+    pub(crate) fn write_selected_register(&mut self, value: u8) {
+        if let Some(register) = Register::from_address(self.selected_address, self.bank_mode) {
+            self.trace(AccessKind::Write, register, value);
+            let writes = &mut self.metrics.writes[register.address(false) as usize];
+            *writes = writes.saturating_add(1);
+            if self.event_sender.is_some() {
+                let old = self.register_value(register);
+                self.write_register(register, value);
+                let new = self.register_value(register);
+                self.send_event(Mcp23017Event::RegisterWritten { register, old, new });
+            } else {
+                self.write_register(register, value);
+            }
+        } else {
+            self.metrics.invalid_writes = self.metrics.invalid_writes.saturating_add(1);
+            warn!(
+                "Attempted to write to invalid register address: {}. Not doing anything.",
+                self.selected_address
+            );
+        }
+        self.advance_address();
+    }
+
+    /// Sends [`Mcp23017Event`]s to `sender`, so firmware running next to the emulator can observe
+    /// what the controller does. For example, create a `static` [`Channel`] and pass its
+    /// [`Channel::dyn_sender`]. `None` stops sending events.
+    ///
+    /// The emulator can't wait for the channel, so events are dropped while it is full. Every
+    /// register access is an event, so the channel has to be emptied quickly.
+    ///
+    /// [`Channel`]: embassy_sync::channel::Channel
+    /// [`Channel::dyn_sender`]: embassy_sync::channel::Channel::dyn_sender
+    pub fn set_event_sender(&mut self, sender: Option<DynamicSender<'static, Mcp23017Event>>) {
+        self.event_sender = sender;
+    }
+
+    fn send_event(&self, event: Mcp23017Event) {
+        if let Some(sender) = &self.event_sender
+            && sender.try_send(event).is_err()
+        {
+            warn!("event channel is full. dropping {:?}", event);
+        }
+    }
+
+    fn notify_pin_change(&self, pin_index: usize) {
+        self.send_event(Mcp23017Event::PinChanged(PinChange {
+            pin_index,
+            io_direction: bit(self.inputs(), pin_index).into(),
+            output_latch: bit(self.output_latches, pin_index),
+        }));
+    }
+
+    /// By default, `GPIO` reads `OLAT` for outputs. If enabled, [`GpioPin::level`] is read for
+    /// outputs too, like the real chip reads the pad. Then a test can simulate an output that is
+    /// shorted or overloaded, and check that the controller detects it.
+    pub fn set_read_output_levels(&mut self, read_output_levels: bool) {
+        self.read_output_levels = read_output_levels;
+    }
+
+    /// Makes [`Self::run`] only raise an interrupt for the pin if it is still at the new level
+    /// after `debounce`, so that a bouncing switch doesn't cause a storm of interrupts. `None`
+    /// disables debouncing, which is the default.
+    /// The debounce time adds to the interrupt latency.
+    pub fn set_debounce(&mut self, pin_index: usize, debounce: Option<Duration>) {
+        self.debounce[pin_index] = debounce;
+    }
+
+    /// Makes [`Self::run`] wait for `propagation_delay` after the pin changes before raising the
+    /// interrupt, so a test can exercise the controller's handling of interrupt timing, which
+    /// simulated pins otherwise make instantaneous. `None`, the default, means no delay.
+    /// `GPIO` reads during the delay already read the new level, like a real chip whose
+    /// interrupt logic is slower than its input buffer.
+    pub fn set_propagation_delay(&mut self, pin_index: usize, propagation_delay: Option<Duration>) {
+        self.propagation_delays[pin_index] = propagation_delay;
+    }
+
+    /// How often [`Self::run`] reads the pin if it can't wait (see [`GpioPin::can_wait`]). The
+    /// default is [`DEFAULT_POLL_INTERVAL`]. A longer interval saves CPU time and power, but adds
+    /// to the interrupt latency.
+    pub fn set_poll_interval(&mut self, pin_index: usize, poll_interval: Duration) {
+        self.poll_intervals[pin_index] = poll_interval;
+    }
+
+    /// Makes the emulator read `level` for the pin instead of the [`GpioPin`], so a test can
+    /// inject input stimuli without a signal generator. `None` goes back to reading the pin.
+    /// If the forced level is an interrupt condition, the interrupt is raised right away.
+    pub fn force_input_level(&mut self, pin_index: usize, level: Option<PinState>) {
+        self.forced_levels[pin_index] = level;
+        if let Some(level) = level
+            && bit(
+                self.int_enabled & !self.int_flags & !self.output_only_pins(),
+                pin_index,
+            )
+            && level != PinState::from(bit(self.compare_values(), pin_index))
+        {
+            info!(
+                "interrupt cuz pin {} was forced to {}",
+                pin_index,
+                Debug2Format(&level)
+            );
+            self.raise_interrupt(pin_index, level);
+            self.update_interrupts();
+        }
+    }
+
+    /// Replaces the backend of a pin, for example when a plug-in module is detected, and returns
+    /// the old one. The new pin is configured like the registers say.
+    /// If the new pin has a different level, it is an input change like any other, so it can
+    /// interrupt. If the new pin is slow, call [`Self::sample_pins`] to read its level.
+    pub fn replace_pin(&mut self, pin_index: usize, pin: P) -> P {
+        let old_pin = mem::replace(&mut self.gpio_pins[pin_index], pin);
+        self.update_pin(pin_index);
+        old_pin
+    }
+
+    /// See [`ResetPin::set_minimum_low_duration`]
+    pub fn set_reset_minimum_low_duration(&mut self, minimum_low_duration: Option<Duration>) {
+        self.reset.set_minimum_low_duration(minimum_low_duration);
+    }
+
+    /// How often the controller accessed each register since the emulator was created or
+    /// [`Self::reset_metrics`] was called. Useful when developing a controller driver, to find
+    /// inefficient access patterns like rewriting `IODIR` in every loop.
+    pub fn metrics(&self) -> &AccessMetrics {
+        &self.metrics
+    }
+
+    pub fn reset_metrics(&mut self) {
+        self.metrics = AccessMetrics::default();
+    }
+
+    fn trace(&self, kind: AccessKind, register: Register, value: u8) {
+        self.send_event(Mcp23017Event::Accessed(RegisterAccess {
+            kind,
+            register,
+            value,
+            address: self.selected_address,
+        }));
+    }
+
+    pub(crate) fn select_address(&mut self, address: u8) {
+        self.selected_address = address;
+    }
+
+    /// IOCON.HAEN. Only affects the SPI front-end.
+    pub(crate) fn hardware_address_enabled(&self) -> bool {
+        self.hardware_address_enabled
+    }
+
     pub fn prepare_read_buffer(&mut self, buffer: &mut [u8]) {
+        self.gpio_snapshot = self.pin_levels(ALL_PINS);
         let mut address = self.selected_address;
         for byte in buffer {
             if let Some(register) = Register::from_address(address, self.bank_mode) {
                 *byte = self.read_register(register);
             } else {
-                #[cfg(feature = "defmt")]
-                defmt::warn!(
+                warn!(
                     "Attempted to read to invalid register address: {}. Not doing anything.",
                     address
                 );
@@ -177,46 +549,202 @@ impl<P: GpioPin, I: InterruptPin, R: Wait> Mcp23017<P, I, R> {
         }
     }
 
+    /// For [`Self::passive`] mode, processes the bytes that the real chip sent for a read, instead
+    /// of [`Self::prepare_read_buffer`] and [`Self::confirm_bytes_read`].
+    /// `GPIO`, `INTF` and `INTCAP` change without the controller, so they take the values that
+    /// were read. Other registers are compared with what the emulator expects, and a difference
+    /// is logged, because it means that a transaction was missed.
+    pub fn observe_read(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if let Some(register) = Register::from_address(self.selected_address, self.bank_mode) {
+                let ab = register.ab;
+                match register._type {
+                    RegisterType::GPIO => {
+                        let inputs = port_mask(ab) & self.inputs();
+                        self.samples = self.samples & !inputs
+                            | u16::from(byte) << ab.starting_index() & inputs;
+                        write_port(&mut self.gpio_snapshot, ab, byte);
+                    }
+                    RegisterType::INTF => {
+                        write_port(&mut self.int_flags, ab, byte);
+                    }
+                    RegisterType::INTCAP => {
+                        write_port(&mut self.int_captured_value, ab, byte);
+                    }
+                    _ => {
+                        let expected = self.read_register(register);
+                        if byte != expected {
+                            warn!(
+                                "{:?} read {:#x}, but the emulator has {:#x}",
+                                register, byte, expected
+                            );
+                        }
+                    }
+                }
+            }
+            self.confirm_bytes_read(1);
+        }
+    }
+
     /// After transmitting bytes to the controller, call this function with the actual number of
     /// bytes read by the controller.
     pub fn confirm_bytes_read(&mut self, bytes_read: usize) {
         for _ in 0..bytes_read {
             if let Some(register) = Register::from_address(self.selected_address, self.bank_mode) {
+                if self.event_sender.is_some() {
+                    self.trace(AccessKind::Read, register, self.read_register(register));
+                }
+                let reads = &mut self.metrics.reads[register.address(false) as usize];
+                *reads = reads.saturating_add(1);
                 self.read_side_effects(register);
+            } else {
+                self.metrics.invalid_reads = self.metrics.invalid_reads.saturating_add(1);
             }
             self.advance_address();
         }
     }
 
+    /// A snapshot of the registers and everything else the emulator remembers, which can be
+    /// restored with [`Self::load_state`]. For example, a test can restore the state after a
+    /// scenario, or firmware can keep the state in memory that isn't cleared on reset, so that the
+    /// controller's configuration survives a restart.
+    pub fn save_state(&self) -> Mcp23017State {
+        Mcp23017State {
+            bank_mode: self.bank_mode,
+            mirror_interrupts: self.mirror_interrupts,
+            sequential_disabled: self.sequential_disabled,
+            slew_rate_disabled: self.slew_rate_disabled,
+            hardware_address_enabled: self.hardware_address_enabled,
+            int_mode: self.int_mode,
+            int_active_state: self.int_active_state,
+            selected_address: self.selected_address,
+            io_directions: self.io_directions,
+            pull_up_enabled: self.pull_up_enabled,
+            output_latches: self.output_latches,
+            gpio_inverted: self.gpio_inverted,
+            int_enabled: self.int_enabled,
+            int_compare: self.int_compare,
+            interrupt_control: self.interrupt_control,
+            int_flags: self.int_flags,
+            int_captured_value: self.int_captured_value,
+            known_input_states: self.known_input_states,
+        }
+    }
+
+    /// Restores a state from [`Self::save_state`], and updates the pins to match it
+    pub fn load_state(&mut self, state: Mcp23017State) {
+        let Mcp23017State {
+            bank_mode,
+            mirror_interrupts,
+            sequential_disabled,
+            slew_rate_disabled,
+            hardware_address_enabled,
+            int_mode,
+            int_active_state,
+            selected_address,
+            io_directions,
+            pull_up_enabled,
+            output_latches,
+            gpio_inverted,
+            int_enabled,
+            int_compare,
+            interrupt_control,
+            int_flags,
+            int_captured_value,
+            known_input_states,
+        } = state;
+        self.bank_mode = bank_mode;
+        self.mirror_interrupts = mirror_interrupts;
+        self.sequential_disabled = sequential_disabled;
+        self.slew_rate_disabled = slew_rate_disabled;
+        self.hardware_address_enabled = hardware_address_enabled;
+        self.int_mode = int_mode;
+        self.int_active_state = int_active_state;
+        self.selected_address = selected_address;
+        self.io_directions = io_directions;
+        self.pull_up_enabled = pull_up_enabled;
+        self.output_latches = output_latches;
+        self.gpio_inverted = gpio_inverted;
+        self.int_enabled = int_enabled;
+        self.int_compare = int_compare;
+        self.interrupt_control = interrupt_control;
+        self.int_flags = int_flags;
+        self.int_captured_value = int_captured_value;
+        self.known_input_states = known_input_states;
+        self.update_all_pins();
+        self.update_interrupts();
+    }
+
+    /// Every register, ordered by their addresses with `IOCON.BANK` cleared (`IODIRA`, `IODIRB`,
+    /// `IPOLA`, ...), no matter which bank mode is used. Useful for debug logs and for checking
+    /// the state in tests.
+    /// This has none of the side effects of reading the registers, so interrupts are not cleared.
+    pub fn dump_registers(&self) -> [u8; N_REGISTERS] {
+        array::from_fn(|address| {
+            self.register_value(Register::from_address(address as u8, false).unwrap())
+        })
+    }
+
+    /// What the register reads, without the side effects of reading it
+    fn register_value(&self, register: Register) -> u8 {
+        match register._type {
+            // The current levels, not the snapshot of the last read
+            RegisterType::GPIO => port_byte(self.pin_levels(port_mask(register.ab)), register.ab),
+            _ => self.read_register(register),
+        }
+    }
+
     fn update_pin(&mut self, pin_index: usize) {
-        self.gpio_pins[pin_index].configure(
-            self.io_directions[pin_index],
-            self.pull_up_enabled[pin_index],
-            self.output_latches[pin_index],
+        if self.passive {
+            return;
+        }
+        let io_direction = bit(self.inputs(), pin_index).into();
+        let pin = &mut self.gpio_pins[pin_index];
+        let pull_up_enabled = bit(self.pull_up_enabled, pin_index);
+        pin.configure(
+            io_direction,
+            pull_up_enabled,
+            bit(self.output_latches, pin_index).into(),
         );
+        let capabilities = pin.capabilities();
+        if !capabilities.supports(io_direction, pull_up_enabled) {
+            warn!(
+                "pin {} can't be configured as {:?} with pull-up {}",
+                pin_index, io_direction, pull_up_enabled
+            );
+            self.send_event(Mcp23017Event::UnsupportedConfig(UnsupportedConfig {
+                pin_index,
+                io_direction,
+                pull_up_enabled,
+                capabilities,
+            }));
+        }
     }
 
     fn update_interrupts(&mut self) {
-        let mut enable_interrupts = [false; AB::COUNT];
-        for (i, set) in AB::VARIANTS.iter().enumerate() {
-            if self.int_flags[set.range()].contains(&true) {
-                enable_interrupts[i] = true;
-            }
+        if self.passive {
+            return;
         }
+        let mut enable_interrupts: [bool; AB::COUNT] =
+            array::from_fn(|i| self.int_flags & port_mask(AB::VARIANTS[i]) != 0);
         if self.mirror_interrupts && enable_interrupts.contains(&true) {
             enable_interrupts.fill(true);
         }
+        let active_state = if self.strict && self.int_mode == InterruptMode::OpenDrain {
+            PinState::Low
+        } else {
+            self.int_active_state
+        };
         for (i, interrupt_pin) in self.interrupt_pins.iter_mut().enumerate() {
             if enable_interrupts[i] {
-                #[cfg(feature = "defmt")]
-                defmt::trace!("enabling interrupt pin {}", i);
+                trace!("enabling interrupt pin {}", i);
             }
             interrupt_pin.configure(
                 self.int_mode,
                 if enable_interrupts[i] {
-                    self.int_active_state
+                    active_state
                 } else {
-                    !self.int_active_state
+                    !active_state
                 },
             );
         }
@@ -226,443 +754,736 @@ impl<P: GpioPin, I: InterruptPin, R: Wait> Mcp23017<P, I, R> {
     /// and updates the address pointer
     fn write_register(&mut self, register: Register, value: u8) {
         // info!("write {} to register {}", value, register);
+        let ab = register.ab;
         match register._type {
             RegisterType::IODIR => {
-                let new_io_directions = {
-                    let mut new_io_directions = self.io_directions;
-                    for (index, io_direction) in new_io_directions[register.ab.range()]
-                        .iter_mut()
-                        .enumerate()
-                    {
-                        *io_direction = ((value & (1 << index)) != 0).into();
-                    }
-                    new_io_directions
-                };
-                let previous_io_directions =
-                    mem::replace(&mut self.io_directions, new_io_directions);
-                zip(previous_io_directions, self.io_directions)
-                    .enumerate()
-                    .filter_map(|(index, (io_direction, new_io_direction))| {
-                        if new_io_direction != io_direction {
-                            Some((index, new_io_direction))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, new_io_direction)| {
-                        let property = PinProperty::IoDirection.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!(
-                            "{}.{:017} = {}",
-                            FormatPinIndex(index),
-                            property,
-                            new_io_direction
-                        );
-                        self.update_pin(index);
-                    });
+                let changed = write_port(&mut self.io_directions, ab, value);
+                if self.io_directions & changed & self.output_only_pins() != 0 {
+                    warn!("GPA7 and GPB7 are output only. They stay outputs.");
+                }
+                for index in pin_indices(changed) {
+                    let property = PinProperty::IoDirection.as_ref();
+                    info!(
+                        "{}.{:017} = {:?}",
+                        FormatPinIndex(index),
+                        property,
+                        IoDirection::from(bit(self.io_directions, index))
+                    );
+                    self.update_pin(index);
+                    self.notify_pin_change(index);
+                }
             }
             RegisterType::GPPU => {
-                let new_pull_up_enabled = {
-                    let mut new_pull_up_enabled = self.pull_up_enabled;
-                    for (index, pull_up_enabled) in new_pull_up_enabled[register.ab.range()]
-                        .iter_mut()
-                        .enumerate()
-                    {
-                        *pull_up_enabled = (value & (1 << index)) != 0;
-                    }
-                    new_pull_up_enabled
-                };
-                let previous_pull_up_enabled =
-                    mem::replace(&mut self.pull_up_enabled, new_pull_up_enabled);
-                zip(previous_pull_up_enabled, self.pull_up_enabled)
-                    .enumerate()
-                    .filter_map(|(index, (previous_pull_up_enabled, pull_up_enabled))| {
-                        if pull_up_enabled != previous_pull_up_enabled {
-                            Some((index, pull_up_enabled))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, new_pull_up_enabled)| {
-                        let property = PinProperty::PullUpEnabled.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!(
-                            "{}.{:017} = {}",
-                            FormatPinIndex(index),
-                            property,
-                            new_pull_up_enabled
-                        );
-                        self.update_pin(index);
-                    });
+                let changed = write_port(&mut self.pull_up_enabled, ab, value);
+                for index in pin_indices(changed) {
+                    let property = PinProperty::PullUpEnabled.as_ref();
+                    info!(
+                        "{}.{:017} = {}",
+                        FormatPinIndex(index),
+                        property,
+                        bit(self.pull_up_enabled, index)
+                    );
+                    self.update_pin(index);
+                }
             }
             RegisterType::OLAT | RegisterType::GPIO => {
-                let new_io_directions = {
-                    let mut new_output_latches = self.output_latches;
-                    for (index, output_latch) in new_output_latches[register.ab.range()]
-                        .iter_mut()
-                        .enumerate()
-                    {
-                        *output_latch = ((value & (1 << index)) != 0).into();
-                    }
-                    new_output_latches
-                };
-                let previous_output_latches =
-                    mem::replace(&mut self.output_latches, new_io_directions);
-                zip(previous_output_latches, self.output_latches)
-                    .enumerate()
-                    .filter_map(|(index, (pin_state, new_pin_state))| {
-                        if new_pin_state != pin_state {
-                            Some((index, new_pin_state))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, pin_state)| {
-                        let property = PinProperty::IoLatch.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!(
-                            "{}.{:017} = {}",
-                            FormatPinIndex(index),
-                            property,
-                            defmt::Debug2Format(&pin_state)
-                        );
-                        self.update_pin(index);
-                    });
+                let changed = write_port(&mut self.output_latches, ab, value);
+                for index in pin_indices(changed) {
+                    let property = PinProperty::IoLatch.as_ref();
+                    info!(
+                        "{}.{:017} = {}",
+                        FormatPinIndex(index),
+                        property,
+                        bit(self.output_latches, index)
+                    );
+                    self.update_pin(index);
+                    self.notify_pin_change(index);
+                }
             }
             RegisterType::IPOL => {
-                let new_gpio_inverted = {
-                    let mut new_gpio_inverted = self.gpio_inverted;
-                    for (index, gpio_inverted) in new_gpio_inverted[register.ab.range()]
-                        .iter_mut()
-                        .enumerate()
-                    {
-                        *gpio_inverted = (value & (1 << index)) != 0;
-                    }
-                    new_gpio_inverted
-                };
-                let previous_gpio_inverted =
-                    mem::replace(&mut self.gpio_inverted, new_gpio_inverted);
-                zip(previous_gpio_inverted, self.gpio_inverted)
-                    .enumerate()
-                    .filter_map(|(index, (previous_value, current_value))| {
-                        if current_value != previous_value {
-                            Some((index, current_value))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, new_value)| {
-                        let property = PinProperty::InputInverted.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!("{}.{:017} = {}", FormatPinIndex(index), property, new_value);
-                    });
+                let changed = write_port(&mut self.gpio_inverted, ab, value);
+                for index in pin_indices(changed) {
+                    let property = PinProperty::InputInverted.as_ref();
+                    info!(
+                        "{}.{:017} = {}",
+                        FormatPinIndex(index),
+                        property,
+                        bit(self.gpio_inverted, index)
+                    );
+                }
             }
             RegisterType::GPINTEN => {
-                let new_int_enabled = {
-                    let mut new_int_enabled = self.int_enabled;
-                    for (index, int_enabled) in
-                        new_int_enabled[register.ab.range()].iter_mut().enumerate()
-                    {
-                        *int_enabled = (value & (1 << index)) != 0;
-                    }
-                    new_int_enabled
-                };
-                let previous_int_enabled = mem::replace(&mut self.int_enabled, new_int_enabled);
-                zip(previous_int_enabled, self.int_enabled)
-                    .enumerate()
-                    .filter_map(|(index, (previous_value, current_value))| {
-                        if current_value != previous_value {
-                            Some((index, current_value))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, new_value)| {
-                        let property = PinProperty::InterruptEnabled.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!("{}.{:017} = {}", FormatPinIndex(index), property, new_value);
-                        self.update_pin(index);
-                    });
+                let changed = write_port(&mut self.int_enabled, ab, value);
+                for index in pin_indices(changed) {
+                    let property = PinProperty::InterruptEnabled.as_ref();
+                    info!(
+                        "{}.{:017} = {}",
+                        FormatPinIndex(index),
+                        property,
+                        bit(self.int_enabled, index)
+                    );
+                    self.update_pin(index);
+                }
             }
             RegisterType::DEFVAL => {
-                let new_compare_register = {
-                    let mut new_compare_register = self.int_compare;
-                    for (index, compare_value) in new_compare_register[register.ab.range()]
-                        .iter_mut()
-                        .enumerate()
-                    {
-                        *compare_value = ((value & (1 << index)) != 0).into();
-                    }
-                    new_compare_register
-                };
-                let previous_compare_register =
-                    mem::replace(&mut self.int_compare, new_compare_register);
-                zip(previous_compare_register, self.int_compare)
-                    .enumerate()
-                    .filter_map(|(index, (previous_value, current_value))| {
-                        if current_value != previous_value {
-                            Some((index, current_value))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, pin_state)| {
-                        let property = PinProperty::CompareValue.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!(
-                            "{}.{:017} = {}",
-                            FormatPinIndex(index),
-                            property,
-                            defmt::Debug2Format(&pin_state)
-                        );
-                    });
+                let changed = write_port(&mut self.int_compare, ab, value);
+                for index in pin_indices(changed) {
+                    let property = PinProperty::CompareValue.as_ref();
+                    info!(
+                        "{}.{:017} = {}",
+                        FormatPinIndex(index),
+                        property,
+                        bit(self.int_compare, index)
+                    );
+                }
             }
             RegisterType::INTCON => {
-                let new_interrupt_control = {
-                    let mut new_interrupt_control = self.interrupt_control;
-                    for (index, interrupt_control) in new_interrupt_control[register.ab.range()]
-                        .iter_mut()
-                        .enumerate()
-                    {
-                        *interrupt_control = ((value & (1 << index)) != 0).into();
-                    }
-                    new_interrupt_control
-                };
-                let previous_interrupt_control =
-                    mem::replace(&mut self.interrupt_control, new_interrupt_control);
-                zip(previous_interrupt_control, self.interrupt_control)
-                    .enumerate()
-                    .filter_map(|(index, (previous_value, current_value))| {
-                        if current_value != previous_value {
-                            Some((index, current_value))
-                        } else {
-                            None
-                        }
-                    })
-                    .for_each(|(index, new_value)| {
-                        let property = PinProperty::InterruptControl.as_ref();
-                        #[cfg(feature = "defmt")]
-                        defmt::info!("{}.{:017} = {}", FormatPinIndex(index), property, new_value);
-                    });
+                let changed = write_port(&mut self.interrupt_control, ab, value);
+                for index in pin_indices(changed) {
+                    let property = PinProperty::InterruptControl.as_ref();
+                    info!(
+                        "{}.{:017} = {:?}",
+                        FormatPinIndex(index),
+                        property,
+                        InterruptControl::from(bit(self.interrupt_control, index))
+                    );
+                }
             }
             RegisterType::IOCON => {
+                if value & IOCON_UNIMPLEMENTED != 0 {
+                    trace!("ignoring the unimplemented bit 0 of IOCON");
+                }
                 self.bank_mode = (value & 1 << 7) != 0;
                 self.mirror_interrupts = (value & 1 << 6) != 0;
-                #[cfg(feature = "defmt")]
-                defmt::info!("mirror interrupts: {}", self.mirror_interrupts);
-                self.sequential_mode = (value & 1 << 5) != 0;
-                // what is slew rate idk
+                info!("mirror interrupts: {}", self.mirror_interrupts);
+                self.sequential_disabled = (value & 1 << 5) != 0;
+                self.slew_rate_disabled = (value & 1 << 4) != 0;
+                self.hardware_address_enabled = (value & 1 << 3) != 0;
                 self.int_mode = ((value & 1 << 2) != 0).into();
                 self.int_active_state = ((value & 1 << 1) != 0).into();
                 self.update_interrupts();
             }
-            register_type => todo!("write {register_type:?}"),
+            RegisterType::INTF | RegisterType::INTCAP => {
+                // Read-only. Controllers can write these during sequential writes.
+                warn!(
+                    "Attempted to write to read-only register {:?}. Not doing anything.",
+                    register
+                );
+            }
         }
     }
 
     /// Reads the register based on the saved address.
     /// Does not update the address pointer
     fn read_register(&self, register: Register) -> u8 {
+        let ab = register.ab;
         match register._type {
-            RegisterType::IODIR => {
-                let mut value = Default::default();
-                for (i, io_direction) in self.io_directions[register.ab.range()]
-                    .into_iter()
-                    .cloned()
-                    .enumerate()
-                {
-                    value |= u8::from(bool::from(io_direction)) << i;
-                }
-                value
-            }
-            RegisterType::GPPU => {
-                let mut value = Default::default();
-                for (i, io_direction) in self.pull_up_enabled[register.ab.range()]
-                    .into_iter()
-                    .cloned()
-                    .enumerate()
-                {
-                    value |= u8::from(io_direction) << i;
-                }
-                value
-            }
-            RegisterType::GPIO => {
-                let mut value = Default::default();
-                for (i, pin) in (&self.gpio_pins[register.ab.range()])
-                    .into_iter()
-                    .enumerate()
-                {
-                    value |= u8::from(bool::from(
-                        match self.io_directions[i + register.ab.starting_index()] {
-                            IoDirection::Output => {
-                                self.output_latches[i + register.ab.starting_index()].into()
-                            }
-                            IoDirection::Input => pin.level(),
-                        },
-                    )) << i;
-                }
-                value
-            }
-            RegisterType::INTCAP => {
-                let mut value = Default::default();
-                for (i, pin_state) in self.int_captured_value[register.ab.range()]
-                    .iter()
-                    .copied()
-                    .enumerate()
-                {
-                    value |= u8::from(bool::from(pin_state)) << i;
-                }
-                value
-            }
-            RegisterType::INTF => {
-                let mut value = Default::default();
-                for (i, flag) in self.int_flags[register.ab.range()]
-                    .iter()
-                    .copied()
-                    .enumerate()
-                {
-                    value |= u8::from(flag) << i;
-                }
-                value
+            RegisterType::IODIR => port_byte(self.io_directions, ab),
+            RegisterType::GPPU => port_byte(self.pull_up_enabled, ab),
+            RegisterType::GPIO => port_byte(self.gpio_snapshot, ab),
+            RegisterType::INTCAP => port_byte(self.int_captured_value, ab),
+            RegisterType::INTF => port_byte(self.int_flags, ab),
+            RegisterType::IPOL => port_byte(self.gpio_inverted, ab),
+            RegisterType::GPINTEN => port_byte(self.int_enabled, ab),
+            RegisterType::DEFVAL => port_byte(self.int_compare, ab),
+            RegisterType::INTCON => port_byte(self.interrupt_control, ab),
+            RegisterType::OLAT => port_byte(self.output_latches, ab),
+            RegisterType::IOCON => to_byte([
+                // IOCON_UNIMPLEMENTED
+                false,
+                self.int_active_state.into(),
+                self.int_mode.into(),
+                self.hardware_address_enabled,
+                self.slew_rate_disabled,
+                self.sequential_disabled,
+                self.mirror_interrupts,
+                self.bank_mode,
+            ]),
+        }
+    }
+
+    /// The level that `GPIO` reads for the pin
+    fn pin_level(&self, pin_index: usize) -> PinState {
+        if !bit(self.inputs(), pin_index) && !self.read_output_levels {
+            bit(self.output_latches, pin_index).into()
+        } else {
+            self.input_level(pin_index)
+        }
+    }
+
+    /// The levels that `GPIO` reads for the pins in `mask`. The other bits are `0`.
+    fn pin_levels(&self, mask: u16) -> u16 {
+        bits_from_fn(|i| bit(mask, i) && self.pin_level(i).into())
+    }
+
+    /// The levels that pins are compared with to interrupt, which is `DEFVAL` or the last known
+    /// level, depending on `INTCON`
+    fn compare_values(&self) -> u16 {
+        self.interrupt_control & self.int_compare
+            | !self.interrupt_control & self.known_input_states
+    }
+
+    /// The level of the pin, or the last sample if the pin is slow, unless the level is forced
+    fn input_level(&self, pin_index: usize) -> PinState {
+        let pin = &self.gpio_pins[pin_index];
+        if let Some(level) = self.forced_levels[pin_index] {
+            level
+        } else if self.passive || pin.is_slow() {
+            bit(self.samples, pin_index).into()
+        } else {
+            pin.level()
+        }
+    }
+
+    /// Reads the level of every slow pin (see [`GpioPin::is_slow`]), which register reads use.
+    /// [`Self::run`] also updates the samples when it notices a change, but only for pins with
+    /// interrupts enabled, so call this before the controller reads other slow pins, or
+    /// periodically.
+    pub async fn sample_pins(&mut self) {
+        for (i, pin) in self.gpio_pins.iter_mut().enumerate() {
+            if pin.is_slow() {
+                set_bit(&mut self.samples, i, pin.read_level().await.into());
             }
-            register_type => todo!("read {register_type:?}"),
         }
     }
 
     fn read_side_effects(&mut self, register: Register) {
         match register._type {
             RegisterType::GPIO => {
-                // Update the last known input state
-                // FIXME: If the state changes between the read and read side effects, the last known value will be in an unexpected state
-                for (i, state) in self.known_input_states[register.ab.range()]
-                    .iter_mut()
-                    .enumerate()
-                {
-                    match self.io_directions[i + register.ab.starting_index()] {
-                        IoDirection::Output => {}
-                        IoDirection::Input => {
-                            *state = self.gpio_pins[i + register.ab.starting_index()].level()
-                        }
-                    };
-                }
+                // Update the last known input state to what was read
+                let inputs = port_mask(register.ab) & self.inputs();
+                self.known_input_states =
+                    self.known_input_states & !inputs | self.gpio_snapshot & inputs;
                 // The interrupt is cleared
-                self.int_flags[register.ab.range()].fill(false);
-                #[cfg(feature = "defmt")]
-                defmt::info!("cleared interrupts: {}", register.ab);
+                self.int_flags &= !port_mask(register.ab);
+                info!("cleared interrupts: {:?}", register.ab);
+                self.reassert_level_interrupts(register.ab);
                 self.update_interrupts();
             }
             RegisterType::INTCAP => {
                 // The interrupt is cleared
-                self.int_flags[register.ab.range()].fill(false);
+                self.int_flags &= !port_mask(register.ab);
+                self.reassert_level_interrupts(register.ab);
                 self.update_interrupts();
             }
             _ => {}
         }
     }
 
-    /// Process any interrupts (and raise an interrupt accordingly).
+    /// When comparing with `DEFVAL`, the interrupt condition is a level, so the chip sets `INTF`
+    /// again right after it is cleared if the pin still doesn't match `DEFVAL`
+    fn reassert_level_interrupts(&mut self, ab: AB) {
+        let level_interrupts = self.int_enabled & self.interrupt_control & !self.output_only_pins();
+        for i in pin_indices(port_mask(ab) & level_interrupts) {
+            let level = self.pin_level(i);
+            if level != PinState::from(bit(self.int_compare, i)) {
+                trace!("pin {} still doesn't match DEFVAL", i);
+                self.raise_interrupt(i, level);
+            }
+        }
+    }
+
+    /// Sets `INTF` for the pin, which changed to `level`.
+    /// Call [`Self::update_interrupts`] after this.
+    fn raise_interrupt(&mut self, index: usize, level: PinState) {
+        let mask = port_mask(AB::from_index(index));
+        // INTCAP captures the whole port at the first interrupt, and keeps that value
+        // until the interrupt is cleared
+        if self.int_flags & mask == 0 {
+            let others = mask & !(1 << index);
+            self.int_captured_value = self.int_captured_value & !mask | self.pin_levels(others);
+            set_bit(&mut self.int_captured_value, index, level.into());
+        }
+        set_bit(&mut self.samples, index, level.into());
+        set_bit(&mut self.int_flags, index, true);
+        set_bit(&mut self.known_input_states, index, level.into());
+        self.send_event(Mcp23017Event::InterruptRaised {
+            pin: index,
+            level: level.into(),
+        });
+    }
+
+    /// Process any interrupts (and raise an interrupt accordingly), and handle the reset pin.
     /// This future will never complete.
     /// The future is safe to cancel.
-    ///
-    /// Also handles the reset pin
     pub async fn run(&mut self) {
         loop {
             use embassy_futures::select::Either::*;
+            let input_watch = self.input_watch();
             match select(
                 self.reset.wait_until_reset(),
-                select_array({
-                    self.gpio_pins
-                        .iter_mut()
-                        .enumerate()
-                        .map(async |(i, pin)| {
-                            // Only send interrupts for pins that don't already have the interrupt flag on
-                            if self.int_enabled[i] && !self.int_flags[i] {
-                                let compare_value = match self.interrupt_control[i] {
-                                    InterruptControl::CompareWithConfiguredValue => {
-                                        #[cfg(feature = "defmt")]
-                                        defmt::warn!(
-                                            "pin {} comparing with {}",
-                                            i,
-                                            defmt::Debug2Format(&self.int_compare[i])
-                                        );
-                                        self.int_compare[i]
-                                    }
-                                    InterruptControl::CompareWithPreviousValue => {
-                                        // the docs are unclear about what the "previous value" is
-                                        self.known_input_states[i]
-                                    }
-                                };
-                                if pin.can_wait() {
-                                    let level = !compare_value;
-                                    pin.wait_for_level(level).await;
-                                } else {
-                                    #[cfg(feature = "defmt")]
-                                    defmt::warn!("pin {} can't wait. falling back to polling", i);
-                                    loop {
-                                        if pin.level() != compare_value {
-                                            break;
-                                        }
-                                        yield_now().await;
-                                    }
-                                }
-                                !compare_value
-                            } else {
-                                pending().await
-                            }
-                        })
-                        .collect_array::<N_TOTAL_GPIO_PINS>()
-                        .unwrap()
-                }),
+                input_watch.wait(&mut self.gpio_pins),
             )
             .await
             {
-                First(()) => {
-                    #[cfg(feature = "defmt")]
-                    defmt::info!("Received reset input. Resetting emulated MCP23017.");
-                    self.reset();
-                }
-                Second((level, index)) => {
-                    #[cfg(feature = "defmt")]
-                    defmt::info!(
-                        "interrupt cuz pin {} changed to {}",
-                        index,
-                        defmt::Debug2Format(&level)
-                    );
-                    self.int_flags[index] = true;
-                    self.int_captured_value[index] = level;
-                    self.known_input_states[index] = level;
-                    self.update_interrupts();
-                }
+                First(()) => self.reset_from_pin(),
+                Second((level, index)) => self.input_changed(index, level),
+            };
+        }
+    }
+
+    /// Process any interrupts (and raise an interrupt accordingly), without handling the reset
+    /// pin, for example if RESET isn't wired. To wait for the reset pin in a different task, use
+    /// [`Self::run_inputs_with_reset`].
+    /// This future will never complete.
+    /// The future is safe to cancel.
+    pub async fn run_inputs(&mut self) {
+        loop {
+            let (level, index) = self.input_watch().wait(&mut self.gpio_pins).await;
+            self.input_changed(index, level);
+        }
+    }
+
+    /// Like [`Self::run_inputs`], but also resets the emulator when `reset` is signalled by a
+    /// [`ResetTask`], which waits for the reset pin in a different task, for example to give
+    /// interrupts a higher priority. Create the emulator without the reset pin then.
+    /// This future will never complete.
+    /// The future is safe to cancel.
+    ///
+    /// [`ResetTask`]: crate::ResetTask
+    pub async fn run_inputs_with_reset<M: RawMutex>(&mut self, reset: &Signal<M, ()>) {
+        loop {
+            use embassy_futures::select::Either::*;
+            let input_watch = self.input_watch();
+            match select(reset.wait(), input_watch.wait(&mut self.gpio_pins)).await {
+                First(()) => self.reset_from_pin(),
+                Second((level, index)) => self.input_changed(index, level),
             };
         }
     }
+
+    fn reset_from_pin(&mut self) {
+        info!("Received reset input. Resetting emulated MCP23017.");
+        self.reset();
+    }
+
+    fn input_changed(&mut self, index: usize, level: PinState) {
+        info!(
+            "interrupt cuz pin {} changed to {}",
+            index,
+            Debug2Format(&level)
+        );
+        self.raise_interrupt(index, level);
+        self.update_interrupts();
+    }
+
+    fn input_watch(&self) -> InputWatch {
+        let forced = bits_from_fn(|i| self.forced_levels[i].is_some());
+        InputWatch {
+            // Only send interrupts for pins that don't already have the interrupt flag on.
+            // Forced levels are checked by `force_input_level`.
+            waiting: self.int_enabled & !self.int_flags & !forced & !self.output_only_pins(),
+            compare_values: self.compare_values(),
+            interrupt_control: self.interrupt_control,
+            debounce: self.debounce,
+            propagation_delays: self.propagation_delays,
+            poll_intervals: self.poll_intervals,
+        }
+    }
+}
+
+/// A general call with [`GENERAL_CALL_RESET`] calls [`Mcp23017::reset`], and other general calls
+/// are ignored.
+impl<P: GpioPin, I: InterruptPin, R: Wait> EmulatedChip for Mcp23017<P, I, R> {
+    fn process_write_transaction(&mut self, bytes: &[u8]) {
+        Self::process_write_transaction(self, bytes);
+    }
+
+    fn prepare_read_buffer(&mut self, buffer: &mut [u8]) {
+        Self::prepare_read_buffer(self, buffer);
+    }
+
+    fn confirm_bytes_read(&mut self, bytes_read: usize) {
+        Self::confirm_bytes_read(self, bytes_read);
+    }
+
+    fn process_general_call(&mut self, bytes: &[u8]) {
+        if bytes == [GENERAL_CALL_RESET] {
+            info!("Received general call reset. Resetting emulated MCP23017.");
+            self.reset();
+        }
+    }
+
+    async fn run(&mut self) {
+        Self::run(self).await;
+    }
 }
 
+/// What [`Mcp23017::run_inputs`] waits for. It is copied out of the emulator, so that the pins
+/// can be borrowed while waiting.
+struct InputWatch {
+    /// The pins that can interrupt
+    waiting: u16,
+    compare_values: u16,
+    interrupt_control: u16,
+    debounce: [Option<Duration>; N_TOTAL_GPIO_PINS],
+    propagation_delays: [Option<Duration>; N_TOTAL_GPIO_PINS],
+    poll_intervals: [Duration; N_TOTAL_GPIO_PINS],
+}
+
+impl InputWatch {
+    /// Waits until a pin changes to an interrupt condition, and returns its new level and index
+    async fn wait(&self, gpio_pins: &mut [impl GpioPin; N_TOTAL_GPIO_PINS]) -> (PinState, usize) {
+        select_array(
+            gpio_pins
+                .iter_mut()
+                .enumerate()
+                .map(async |(i, pin)| {
+                    if bit(self.waiting, i) {
+                        // For `INTCON` = 0, the docs are unclear about what the "previous
+                        // value" is
+                        let compare_value = PinState::from(bit(self.compare_values, i));
+                        if bit(self.interrupt_control, i) {
+                            warn!("pin {} comparing with {}", i, Debug2Format(&compare_value));
+                        }
+                        loop {
+                            if pin.can_wait() {
+                                let level = !compare_value;
+                                pin.wait_for_level(level).await;
+                            } else {
+                                warn!("pin {} can't wait. falling back to polling", i);
+                                while read_level(pin).await == compare_value {
+                                    Timer::after(self.poll_intervals[i]).await;
+                                }
+                            }
+                            let Some(debounce) = self.debounce[i] else {
+                                break;
+                            };
+                            Timer::after(debounce).await;
+                            if read_level(pin).await != compare_value {
+                                break;
+                            }
+                            trace!("pin {} bounced", i);
+                        }
+                        if let Some(propagation_delay) = self.propagation_delays[i] {
+                            Timer::after(propagation_delay).await;
+                        }
+                        !compare_value
+                    } else {
+                        pending().await
+                    }
+                })
+                .collect_array::<N_TOTAL_GPIO_PINS>()
+                .unwrap(),
+        )
+        .await
+    }
+}
+
+/// Like [`GpioPin::level`], but uses [`GpioPin::read_level`] for slow pins
+pub(crate) async fn read_level(pin: &mut impl GpioPin) -> PinState {
+    if pin.is_slow() {
+        pin.read_level().await
+    } else {
+        pin.level()
+    }
+}
+
+/// A bit for every pin
+const ALL_PINS: u16 = u16::MAX;
+/// Bit 0 of `IOCON` isn't implemented. Writes to it are ignored, and it reads `0`.
+const IOCON_UNIMPLEMENTED: u8 = 1 << 0;
+/// `GPA7` and `GPB7`
+const OUTPUT_ONLY_PINS: u16 = 1 << 7 | 1 << 15;
+
+/// Whether bit `i` is set
+fn bit(bits: u16, i: usize) -> bool {
+    bits & (1 << i) != 0
+}
+
+fn set_bit(bits: &mut u16, i: usize, value: bool) {
+    if value {
+        *bits |= 1 << i;
+    } else {
+        *bits &= !(1 << i);
+    }
+}
+
+/// Bit `i` is `f(i)`
+fn bits_from_fn(mut f: impl FnMut(usize) -> bool) -> u16 {
+    (0..N_TOTAL_GPIO_PINS).fold(0, |bits, i| bits | u16::from(f(i)) << i)
+}
+
+/// The indices of the bits that are set
+fn pin_indices(bits: u16) -> impl Iterator<Item = usize> {
+    (0..N_TOTAL_GPIO_PINS).filter(move |&i| bit(bits, i))
+}
+
+/// The bits of the pins of the port
+fn port_mask(ab: AB) -> u16 {
+    0xFF << ab.starting_index()
+}
+
+/// The register value of the port
+fn port_byte(bits: u16, ab: AB) -> u8 {
+    (bits >> ab.starting_index()) as u8
+}
+
+/// Replaces the bits of the port with a register value, and returns the bits that changed
+fn write_port(bits: &mut u16, ab: AB, value: u8) -> u16 {
+    let previous = *bits;
+    *bits = previous & !port_mask(ab) | u16::from(value) << ab.starting_index();
+    previous ^ *bits
+}
+
+/// Bit `i` is the `i`th item
+pub(crate) fn to_byte(bits: impl IntoIterator<Item = bool>) -> u8 {
+    bits.into_iter()
+        .enumerate()
+        .fold(0, |value, (i, bit)| value | u8::from(bit) << i)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum AdvanceAddressMode {
-    /// `IOCON.SEQOP = 0`, `IOCON.BANK = 1`
+    /// `IOCON.SEQOP = 1`, `IOCON.BANK = 1`
     Fixed,
-    /// `IOCON.SEQOP = 0`, `IOCON.BANK = 0`
+    /// `IOCON.SEQOP = 1`, `IOCON.BANK = 0`
     Toggle,
-    /// `IOCON.SEQOP = 1`
-    Cycle,
+    /// `IOCON.SEQOP = 0`
+    Cycle { bank_mode: bool },
 }
 
+/// The address after an access. The mode is taken after the access, so if a burst writes
+/// `IOCON.BANK`, the address pointer keeps its value and continues in the new register map.
 fn advance_address(current_address: u8, mode: AdvanceAddressMode) -> u8 {
     match mode {
         AdvanceAddressMode::Fixed => current_address,
-        AdvanceAddressMode::Toggle => {
-            if current_address.is_multiple_of(2) {
-                current_address + 1
-            } else {
-                current_address - 1
+        // Also for addresses that aren't registers, so the pointer never leaves its pair
+        AdvanceAddressMode::Toggle => current_address ^ 1,
+        AdvanceAddressMode::Cycle { bank_mode } => {
+            // Addresses that aren't registers, like the ones between the ports in bank mode, are
+            // skipped, and the pointer rolls over to `0x00` after the last register
+            let last_address = Register {
+                _type: RegisterType::OLAT,
+                ab: AB::B,
             }
+            .address(bank_mode);
+            (current_address.saturating_add(1)..=last_address)
+                .find(|&address| Register::from_address(address, bank_mode).is_some())
+                .unwrap_or(0)
         }
-        AdvanceAddressMode::Cycle => {
-            if current_address == (RegisterType::COUNT * 2 - 1) as u8 {
-                0
-            } else {
-                current_address + 1
-            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NoopGpioPin, NoopInterruptPin};
+
+    fn mcp23017() -> Mcp23017<NoopGpioPin, NoopInterruptPin> {
+        Mcp23017::new_without_reset_pin(
+            array::from_fn(|_| NoopGpioPin::new(PinState::Low)),
+            [NoopInterruptPin, NoopInterruptPin],
+        )
+    }
+
+    fn address(_type: RegisterType, ab: AB, bank_mode: bool) -> u8 {
+        Register { _type, ab }.address(bank_mode)
+    }
+
+    const IOCON_BANK: u8 = 1 << 7;
+    const IOCON_SEQOP: u8 = 1 << 5;
+
+    #[test]
+    fn fixed_keeps_the_address() {
+        for address in [0x00, 0x0A, 0x1A, 0xFF] {
+            assert_eq!(advance_address(address, AdvanceAddressMode::Fixed), address);
+        }
+    }
+
+    #[test]
+    fn toggle_stays_in_the_pair() {
+        assert_eq!(advance_address(0x12, AdvanceAddressMode::Toggle), 0x13);
+        assert_eq!(advance_address(0x13, AdvanceAddressMode::Toggle), 0x12);
+        assert_eq!(advance_address(0xFF, AdvanceAddressMode::Toggle), 0xFE);
+    }
+
+    #[test]
+    fn cycle_rolls_over_after_the_last_register() {
+        let mode = AdvanceAddressMode::Cycle { bank_mode: false };
+        assert_eq!(advance_address(0x14, mode), 0x15);
+        assert_eq!(advance_address(0x15, mode), 0x00);
+        assert_eq!(advance_address(0xFF, mode), 0x00);
+        let mode = AdvanceAddressMode::Cycle { bank_mode: true };
+        assert_eq!(advance_address(0x0A, mode), 0x10);
+        assert_eq!(advance_address(0x0C, mode), 0x10);
+        assert_eq!(advance_address(0x1A, mode), 0x00);
+        assert_eq!(advance_address(0xFF, mode), 0x00);
+    }
+
+    #[test]
+    fn sequential_write_wraps_around() {
+        let mut mcp23017 = mcp23017();
+        let olat_a = address(RegisterType::OLAT, AB::A, false);
+        mcp23017.process_write_transaction(&[olat_a, 0x11, 0x22, 0x33]);
+        let registers = mcp23017.dump_registers();
+        assert_eq!(registers[olat_a as usize], 0x11);
+        assert_eq!(registers[olat_a as usize + 1], 0x22);
+        assert_eq!(registers[0], 0x33);
+    }
+
+    #[test]
+    fn sequential_read_wraps_around_in_bank_mode() {
+        let mut mcp23017 = mcp23017();
+        let iocon = address(RegisterType::IOCON, AB::A, false);
+        mcp23017.process_write_transaction(&[iocon, IOCON_BANK]);
+        let olat_a = address(RegisterType::OLAT, AB::A, true);
+        let olat_b = address(RegisterType::OLAT, AB::B, true);
+        mcp23017.process_write_transaction(&[olat_a, 0x11]);
+        mcp23017.process_write_transaction(&[olat_b, 0x22]);
+        mcp23017.process_write_transaction(&[olat_a]);
+        let mut buffer = [0; 3];
+        mcp23017.prepare_read_buffer(&mut buffer);
+        // `OLATA`, then `IODIRB` and `IPOLB`, skipping the addresses between the ports
+        assert_eq!(buffer, [0x11, 0xFF, 0x00]);
+        mcp23017.confirm_bytes_read(buffer.len());
+        mcp23017.process_write_transaction(&[olat_b]);
+        mcp23017.prepare_read_buffer(&mut buffer[..2]);
+        // `OLATB`, then `IODIRA`
+        assert_eq!(buffer[..2], [0x22, 0xFF]);
+    }
+
+    #[test]
+    fn byte_mode_toggles_or_stays() {
+        let mut mcp23017 = mcp23017();
+        let iocon = address(RegisterType::IOCON, AB::A, false);
+        mcp23017.process_write_transaction(&[iocon, IOCON_SEQOP]);
+        let gppu_a = address(RegisterType::GPPU, AB::A, false);
+        mcp23017.process_write_transaction(&[gppu_a, 0x11, 0x22, 0x33]);
+        let registers = mcp23017.dump_registers();
+        assert_eq!(registers[gppu_a as usize], 0x33);
+        assert_eq!(registers[gppu_a as usize + 1], 0x22);
+
+        mcp23017.process_write_transaction(&[iocon, IOCON_SEQOP | IOCON_BANK]);
+        let gppu_b = address(RegisterType::GPPU, AB::B, true);
+        mcp23017.process_write_transaction(&[gppu_b, 0x44, 0x55]);
+        let registers = mcp23017.dump_registers();
+        assert_eq!(registers[gppu_a as usize], 0x33);
+        assert_eq!(registers[gppu_a as usize + 1], 0x55);
+    }
+
+    #[test]
+    fn iocon_read_modify_write() {
+        let mut mcp23017 = mcp23017();
+        let iocon_a = address(RegisterType::IOCON, AB::A, false);
+        // Every bit except `BANK`, so the address stays the same, and byte mode, so the read
+        // goes to `IOCONB`, which is the same register
+        mcp23017.process_write_transaction(&[iocon_a, !IOCON_BANK]);
+        let mut buffer = [0; 2];
+        mcp23017.prepare_read_buffer(&mut buffer);
+        mcp23017.confirm_bytes_read(buffer.len());
+        assert_eq!(buffer, [!IOCON_BANK & !IOCON_UNIMPLEMENTED; 2]);
+        mcp23017.process_write_transaction(&[iocon_a, buffer[0] & !IOCON_SEQOP | 1]);
+        mcp23017.process_write_transaction(&[iocon_a]);
+        mcp23017.prepare_read_buffer(&mut buffer[..1]);
+        assert_eq!(buffer[0], !IOCON_BANK & !IOCON_SEQOP & !IOCON_UNIMPLEMENTED);
+    }
+
+    #[test]
+    fn bank_change_mid_burst() {
+        let mut mcp23017 = mcp23017();
+        // Without bank mode `IOCON` is `0x0A`, which is `OLATA` in bank mode, so the burst
+        // continues at `IODIRB`
+        let iocon = address(RegisterType::IOCON, AB::A, false);
+        mcp23017.process_write_transaction(&[iocon, IOCON_BANK, 0x00, 0xAB]);
+        let registers = mcp23017.dump_registers();
+        assert_eq!(
+            registers[address(RegisterType::IODIR, AB::B, false) as usize],
+            0x00
+        );
+        assert_eq!(
+            registers[address(RegisterType::IPOL, AB::B, false) as usize],
+            0xAB
+        );
+
+        // And back: in bank mode `IOCON` is `0x05`, which is `GPINTENB` without bank mode, so the
+        // burst continues at `DEFVALA`
+        let iocon = address(RegisterType::IOCON, AB::A, true);
+        mcp23017.process_write_transaction(&[iocon, 0x00, 0xCD]);
+        let registers = mcp23017.dump_registers();
+        assert_eq!(
+            registers[address(RegisterType::DEFVAL, AB::A, false) as usize],
+            0xCD
+        );
+    }
+
+    /// Enables the interrupt of `GPA0`, comparing with `DEFVAL` = high
+    fn mcp23017_comparing_gpa0_with_high() -> Mcp23017<NoopGpioPin, NoopInterruptPin> {
+        let mut mcp23017 = mcp23017();
+        for (_type, value) in [
+            (RegisterType::DEFVAL, 0x01),
+            (RegisterType::INTCON, 0x01),
+            (RegisterType::GPINTEN, 0x01),
+        ] {
+            mcp23017.process_write_transaction(&[address(_type, AB::A, false), value]);
+        }
+        mcp23017
+    }
+
+    fn read(mcp23017: &mut Mcp23017<NoopGpioPin, NoopInterruptPin>, _type: RegisterType) -> u8 {
+        mcp23017.process_write_transaction(&[address(_type, AB::A, false)]);
+        let mut buffer = [0];
+        mcp23017.prepare_read_buffer(&mut buffer);
+        mcp23017.confirm_bytes_read(buffer.len());
+        buffer[0]
+    }
+
+    fn intf(mcp23017: &Mcp23017<NoopGpioPin, NoopInterruptPin>) -> u8 {
+        mcp23017.dump_registers()[address(RegisterType::INTF, AB::A, false) as usize]
+    }
+
+    #[test]
+    fn level_interrupt_is_reasserted_after_reads() {
+        let mut mcp23017 = mcp23017_comparing_gpa0_with_high();
+        // `GPA0` is low, so it doesn't match `DEFVAL`
+        read(&mut mcp23017, RegisterType::GPIO);
+        assert_eq!(intf(&mcp23017), 0x01);
+        read(&mut mcp23017, RegisterType::INTCAP);
+        assert_eq!(intf(&mcp23017), 0x01);
+        read(&mut mcp23017, RegisterType::GPIO);
+        assert_eq!(intf(&mcp23017), 0x01);
+    }
+
+    #[test]
+    fn level_interrupt_stays_clear_once_the_pin_matches() {
+        let mut mcp23017 = mcp23017_comparing_gpa0_with_high();
+        read(&mut mcp23017, RegisterType::GPIO);
+        assert_eq!(intf(&mcp23017), 0x01);
+        mcp23017.gpio_pins[0].level = PinState::High;
+        read(&mut mcp23017, RegisterType::INTCAP);
+        assert_eq!(intf(&mcp23017), 0x00);
+        read(&mut mcp23017, RegisterType::GPIO);
+        assert_eq!(intf(&mcp23017), 0x00);
+    }
+
+    #[test]
+    fn gpio_read_uses_the_snapshot() {
+        let mut mcp23017 = mcp23017();
+        mcp23017.gpio_pins[0].level = PinState::High;
+        mcp23017.process_write_transaction(&[address(RegisterType::GPIO, AB::A, false)]);
+        let mut buffer = [0];
+        mcp23017.prepare_read_buffer(&mut buffer);
+        // The pin changes while the controller reads
+        mcp23017.gpio_pins[0].level = PinState::Low;
+        mcp23017.confirm_bytes_read(buffer.len());
+        assert_eq!(buffer[0], 0x01);
+        assert!(bit(mcp23017.known_input_states, 0));
+    }
+
+    #[test]
+    fn output_only_pins_dont_reassert_level_interrupts() {
+        let mut mcp23017 = mcp23017().strict();
+        // `GPA7` compares with high, and its latch is low
+        for (_type, value) in [
+            (RegisterType::DEFVAL, 0x80),
+            (RegisterType::INTCON, 0x80),
+            (RegisterType::GPINTEN, 0x80),
+        ] {
+            mcp23017.process_write_transaction(&[address(_type, AB::A, false), value]);
         }
+        mcp23017.force_input_level(7, Some(PinState::Low));
+        assert_eq!(intf(&mcp23017), 0x00);
+        read(&mut mcp23017, RegisterType::GPIO);
+        assert_eq!(intf(&mcp23017), 0x00);
     }
 }
@@ -0,0 +1,205 @@
+use crate::*;
+use embassy_nrf::gpio::{Flex, Input, Level, OutputDrive, Pull};
+use embassy_nrf::twis::{Command, Error, Twis};
+use embedded_hal_async::digital::Wait;
+use mcp23017_common::InterruptMode;
+
+fn get_pull(pull_up_enabled: bool) -> Pull {
+    if pull_up_enabled {
+        Pull::Up
+    } else {
+        Pull::None
+    }
+}
+
+enum NrfGpioPinType<'a> {
+    Input { pin: Input<'a>, pull: Pull },
+    Flex { pin: Flex<'a>, drive: OutputDrive },
+}
+
+/// Waiting for a level uses GPIOTE port events, so every pin can wait.
+pub struct NrfGpioPin<'a> {
+    _type: NrfGpioPinType<'a>,
+}
+
+impl<'a> NrfGpioPin<'a> {
+    /// An input-only pin. Its pull can't be changed, because an `Input` is configured with its pull
+    /// when it is created.
+    pub fn new_input(pin: Input<'a>, pull: Pull) -> Self {
+        Self {
+            _type: NrfGpioPinType::Input { pin, pull },
+        }
+    }
+
+    /// A pin that can be an input or an output. Changing the pull reconfigures the whole pin,
+    /// because the pull is part of the pin's configuration and can't be changed on its own.
+    pub fn new_flex(pin: Flex<'a>, drive: OutputDrive) -> Self {
+        Self {
+            _type: NrfGpioPinType::Flex { pin, drive },
+        }
+    }
+}
+
+impl GpioPin for NrfGpioPin<'_> {
+    fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
+        match &mut self._type {
+            NrfGpioPinType::Input { pin: _, pull } => match io_direction {
+                IoDirection::Input => {
+                    if *pull != get_pull(pull_up_enabled) {
+                        warn!("Cannot set pull because Input's pull cannot be changed.");
+                    }
+                }
+                IoDirection::Output => {
+                    warn!("Tried to use input-only pin as output")
+                }
+            },
+            NrfGpioPinType::Flex { pin, drive } => match io_direction {
+                IoDirection::Output => {
+                    pin.set_level(Level::from(bool::from(level)));
+                    pin.set_as_output(*drive);
+                }
+                IoDirection::Input => {
+                    pin.set_as_input(get_pull(pull_up_enabled));
+                }
+            },
+        }
+    }
+
+    fn level(&self) -> PinState {
+        bool::from(match &self._type {
+            NrfGpioPinType::Input { pin, pull: _ } => pin.get_level(),
+            NrfGpioPinType::Flex { pin, drive: _ } => pin.get_level(),
+        })
+        .into()
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        match (&mut self._type, level) {
+            (NrfGpioPinType::Input { pin, pull: _ }, PinState::High) => pin.wait_for_high().await,
+            (NrfGpioPinType::Input { pin, pull: _ }, PinState::Low) => pin.wait_for_low().await,
+            (NrfGpioPinType::Flex { pin, drive: _ }, PinState::High) => pin.wait_for_high().await,
+            (NrfGpioPinType::Flex { pin, drive: _ }, PinState::Low) => pin.wait_for_low().await,
+        }
+    }
+
+    fn capabilities(&self) -> PinCapabilities {
+        match &self._type {
+            NrfGpioPinType::Input { pin: _, pull } => PinCapabilities {
+                fixed_pull_up: Some(*pull == Pull::Up),
+                ..PinCapabilities::INPUT
+            },
+            NrfGpioPinType::Flex { pin: _, drive: _ } => PinCapabilities::ALL,
+        }
+    }
+}
+
+/// Uses [`OutputDrive::Standard0Disconnect1`] for [`InterruptMode::OpenDrain`], which is a real
+/// open-drain output
+pub struct NrfInterruptPin<'a> {
+    pin: Flex<'a>,
+}
+
+impl<'a> NrfInterruptPin<'a> {
+    pub fn new(pin: Flex<'a>) -> Self {
+        Self { pin }
+    }
+}
+
+impl InterruptPin for NrfInterruptPin<'_> {
+    fn configure(&mut self, mode: InterruptMode, level: PinState) {
+        info!("setting interrupt pin level to {}", Debug2Format(&level));
+        self.pin.set_level(Level::from(bool::from(level)));
+        self.pin.set_as_output(match mode {
+            InterruptMode::OpenDrain => OutputDrive::Standard0Disconnect1,
+            InterruptMode::ActiveDriver => OutputDrive::Standard,
+        });
+    }
+}
+
+/// Events that were already received from the hardware
+#[derive(Clone, Copy)]
+enum Pending {
+    ReadRequested { address: u8 },
+    ReadCompleted(usize),
+}
+
+/// [`I2cTargetTransport`] for a TWIS peripheral, which must be configured with the address of the
+/// chip.
+///
+/// EasyDMA can only use buffers in RAM. Writes are received into a buffer in this struct, and
+/// reads are sent from the buffer of [`run_with_transport`], which is on the stack of its future.
+pub struct NrfI2cTransport<'a, 'd> {
+    twis: &'a mut Twis<'d>,
+    buffer: [u8; I2C_BUFFER_LEN],
+    pending: Option<Pending>,
+}
+
+impl<'a, 'd> NrfI2cTransport<'a, 'd> {
+    pub fn new(twis: &'a mut Twis<'d>) -> Self {
+        Self {
+            twis,
+            buffer: [0; I2C_BUFFER_LEN],
+            pending: None,
+        }
+    }
+}
+
+impl I2cTargetTransport for NrfI2cTransport<'_, '_> {
+    type Error = Error;
+
+    async fn next_event(&mut self) -> Result<TransportEvent<'_>, Self::Error> {
+        match self.pending.take() {
+            Some(Pending::ReadRequested { address }) => Ok(TransportEvent::ReadRequested {
+                address,
+                len: I2C_BUFFER_LEN,
+            }),
+            Some(Pending::ReadCompleted(bytes_read)) => {
+                Ok(TransportEvent::ReadCompleted(bytes_read))
+            }
+            None => {
+                let command = self.twis.listen(&mut self.buffer).await?;
+                let address = self.twis.address_match();
+                match command {
+                    Command::Write(len) => Ok(TransportEvent::Write {
+                        address,
+                        bytes: &self.buffer[..len],
+                    }),
+                    Command::WriteRead(len) => {
+                        self.pending = Some(Pending::ReadRequested { address });
+                        Ok(TransportEvent::Write {
+                            address,
+                            bytes: &self.buffer[..len],
+                        })
+                    }
+                    Command::Read => Ok(TransportEvent::ReadRequested {
+                        address,
+                        len: I2C_BUFFER_LEN,
+                    }),
+                }
+            }
+        }
+    }
+
+    async fn respond(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        let bytes_read = match self.twis.respond_to_read(bytes).await {
+            Ok(bytes_read) => bytes_read,
+            // The controller read the whole buffer and then some
+            Err(Error::Overread) => bytes.len(),
+            Err(error) => return Err(error),
+        };
+        self.pending = Some(Pending::ReadCompleted(bytes_read));
+        Ok(())
+    }
+}
+
+/// Emulates the MCP23017 on `twis` with [`NrfI2cTransport`]. See [`run_with_transport`].
+pub async fn run_i2c<P: GpioPin, I: InterruptPin, R: Wait>(
+    mcp23017: &mut Mcp23017<P, I, R>,
+    twis: &mut Twis<'_>,
+) -> ! {
+    run_with_transport(mcp23017, &mut NrfI2cTransport::new(twis)).await
+}
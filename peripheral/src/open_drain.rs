@@ -0,0 +1,41 @@
+use mcp23017_common::InterruptMode;
+
+use crate::*;
+
+/// A pin that can switch between input and output, like a `Flex` pin of most HALs.
+/// Implement this to use [`OpenDrainInterruptPin`].
+pub trait FlexPin {
+    /// Makes the pin an input without a pull, so it doesn't drive the line
+    fn set_as_input(&mut self);
+    /// Makes the pin an output that drives `level`
+    fn set_as_output(&mut self, level: PinState);
+}
+
+/// An [`InterruptPin`] for micro controllers that don't have open-drain outputs.
+/// In open-drain mode the pin is only an output while it is low, and an input without a pull
+/// while it is high.
+pub struct OpenDrainInterruptPin<P> {
+    pin: P,
+}
+
+impl<P: FlexPin> OpenDrainInterruptPin<P> {
+    pub fn new(pin: P) -> Self {
+        Self { pin }
+    }
+
+    pub fn into_pin(self) -> P {
+        self.pin
+    }
+}
+
+impl<P: FlexPin> InterruptPin for OpenDrainInterruptPin<P> {
+    fn configure(&mut self, mode: InterruptMode, level: PinState) {
+        info!("setting interrupt pin level to {}", Debug2Format(&level));
+        match (mode, level) {
+            (InterruptMode::OpenDrain, PinState::High) => self.pin.set_as_input(),
+            (InterruptMode::OpenDrain, PinState::Low) | (InterruptMode::ActiveDriver, _) => {
+                self.pin.set_as_output(level)
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use core::{array, iter::zip};
+
+use embassy_time::Duration;
+use mcp23017_common::{AB, InterruptMode, N_TOTAL_GPIO_PINS};
+use strum::{EnumCount, FromRepr, VariantArray};
+
+use crate::*;
+
+/// The address of a PCA9555 or TCA9555 with `A0`, `A1`, and `A2` low. This is the same as the
+/// MCP23017's [`BASE_ADDRESS`], so [`AddressFilter`] works with a `u8` or address pins.
+pub const PCA9555_BASE_ADDRESS: u8 = 0x20;
+
+/// The registers of the PCA9555. Each one has a register for port 0 and one for port 1, which
+/// is the command byte with bit 0 set.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumCount, FromRepr)]
+pub enum Pca9555Register {
+    /// The levels of the pins, read-only
+    Input,
+    Output,
+    PolarityInversion,
+    /// `1` is an input
+    Configuration,
+}
+
+impl Pca9555Register {
+    /// The register and port (`A` is port 0) of a command byte
+    pub fn from_command(command: u8) -> Option<(Self, AB)> {
+        Some((
+            Self::from_repr(command as usize / 2)?,
+            AB::VARIANTS[command as usize % 2],
+        ))
+    }
+}
+
+/// Emulates a PCA9555 or TCA9555, with the same pins as [`Mcp23017`], so multi-vendor
+/// expander drivers can be tested against one board.
+///
+/// The controller writes a command byte that selects a register, and then reads or writes data.
+/// After each byte, the command switches to the other port of the same register.
+/// Inputs have the internal pull-up enabled, like the real chip.
+///
+/// `INT` is active low and open drain. It is asserted when an input changes, and released when
+/// the `Input` register of the port that changed is read. Unlike the real chip, it isn't
+/// released when the input changes back.
+pub struct Pca9555<P, I> {
+    gpio_pins: InputPins<P, N_TOTAL_GPIO_PINS>,
+    interrupt_pin: I,
+    command: u8,
+    output_latches: [PinState; N_TOTAL_GPIO_PINS],
+    polarity_inverted: [bool; N_TOTAL_GPIO_PINS],
+    io_directions: [IoDirection; N_TOTAL_GPIO_PINS],
+    /// The levels when the `Input` register was last read, which inputs are compared with to
+    /// interrupt
+    known_levels: [PinState; N_TOTAL_GPIO_PINS],
+    /// Which port changed since its `Input` register was read
+    int_flags: [bool; AB::COUNT],
+    /// The levels that the `Input` registers read, taken when a read is prepared so that the
+    /// side effects of the read use the same levels that were sent
+    snapshot: [PinState; N_TOTAL_GPIO_PINS],
+}
+
+impl<P: GpioPin, I: InterruptPin> Pca9555<P, I> {
+    pub fn new(gpio_pins: [P; N_TOTAL_GPIO_PINS], interrupt_pin: I) -> Self {
+        let mut s = Self {
+            gpio_pins: InputPins::new(gpio_pins),
+            interrupt_pin,
+            command: 0,
+            output_latches: [PinState::High; _],
+            polarity_inverted: [false; _],
+            io_directions: [IoDirection::Input; _],
+            known_levels: [PinState::High; _],
+            int_flags: [false; _],
+            snapshot: [PinState::High; _],
+        };
+        s.reset();
+        s
+    }
+
+    /// Init / reset everything to the power on values
+    pub fn reset(&mut self) {
+        self.command = 0;
+        self.output_latches = [PinState::High; _];
+        self.polarity_inverted = [false; _];
+        self.io_directions = [IoDirection::Input; _];
+        self.update_pins();
+        self.known_levels = array::from_fn(|i| self.pin_level(i));
+        self.int_flags = [false; _];
+        self.update_interrupt();
+    }
+
+    fn update_pins(&mut self) {
+        for (i, pin) in self.gpio_pins.pins.iter_mut().enumerate() {
+            pin.configure(
+                self.io_directions[i],
+                self.io_directions[i] == IoDirection::Input,
+                self.output_latches[i],
+            );
+        }
+    }
+
+    fn update_interrupt(&mut self) {
+        self.interrupt_pin.configure(
+            InterruptMode::OpenDrain,
+            if self.int_flags.contains(&true) {
+                PinState::Low
+            } else {
+                PinState::High
+            },
+        );
+    }
+
+    /// The level of the pin, before polarity inversion
+    fn pin_level(&self, pin_index: usize) -> PinState {
+        match self.io_directions[pin_index] {
+            IoDirection::Output => self.output_latches[pin_index],
+            IoDirection::Input => self.gpio_pins.level(pin_index),
+        }
+    }
+
+    /// The first byte is the command, and the rest are written to the selected registers
+    pub fn process_write_transaction(&mut self, bytes: &[u8]) {
+        if let Some(&command) = bytes.first() {
+            self.command = command;
+            for &value in &bytes[1..] {
+                self.write_selected_register(value);
+            }
+        }
+    }
+
+    fn write_selected_register(&mut self, value: u8) {
+        if let Some((register, ab)) = Pca9555Register::from_command(self.command) {
+            let bits = array::from_fn::<_, 8, _>(|i| value & (1 << i) != 0);
+            match register {
+                Pca9555Register::Input => {
+                    warn!(
+                        "Attempted to write to the read-only Input register. Not doing anything."
+                    );
+                }
+                Pca9555Register::Output => {
+                    for (latch, bit) in zip(&mut self.output_latches[ab.range()], bits) {
+                        *latch = bit.into();
+                    }
+                    self.update_pins();
+                }
+                Pca9555Register::PolarityInversion => {
+                    self.polarity_inverted[ab.range()].copy_from_slice(&bits);
+                }
+                Pca9555Register::Configuration => {
+                    for (io_direction, bit) in zip(&mut self.io_directions[ab.range()], bits) {
+                        *io_direction = bit.into();
+                    }
+                    self.update_pins();
+                }
+            }
+            trace!("pca9555 {:?} {:?} = {:#x}", register, ab, value);
+        } else {
+            warn!(
+                "Attempted to write to invalid command: {}. Not doing anything.",
+                self.command
+            );
+        }
+        self.command ^= 1;
+    }
+
+    fn read_register(&self, register: Pca9555Register, ab: AB) -> u8 {
+        to_byte(ab.range().map(|i| match register {
+            Pca9555Register::Input => bool::from(self.snapshot[i]) != self.polarity_inverted[i],
+            Pca9555Register::Output => self.output_latches[i].into(),
+            Pca9555Register::PolarityInversion => self.polarity_inverted[i],
+            Pca9555Register::Configuration => self.io_directions[i].into(),
+        }))
+    }
+
+    pub fn prepare_read_buffer(&mut self, buffer: &mut [u8]) {
+        self.snapshot = array::from_fn(|i| self.pin_level(i));
+        let mut command = self.command;
+        for byte in buffer {
+            *byte = if let Some((register, ab)) = Pca9555Register::from_command(command) {
+                self.read_register(register, ab)
+            } else {
+                warn!(
+                    "Attempted to read invalid command: {}. Reading 0xFF.",
+                    command
+                );
+                // Nothing drives the bus
+                0xFF
+            };
+            command ^= 1;
+        }
+    }
+
+    /// After transmitting bytes to the controller, call this function with the actual number of
+    /// bytes read by the controller.
+    pub fn confirm_bytes_read(&mut self, bytes_read: usize) {
+        for _ in 0..bytes_read {
+            if let Some((Pca9555Register::Input, ab)) = Pca9555Register::from_command(self.command)
+            {
+                self.known_levels[ab.range()].copy_from_slice(&self.snapshot[ab.range()]);
+                self.int_flags[ab.set_index()] = false;
+                self.update_interrupt();
+            }
+            self.command ^= 1;
+        }
+    }
+
+    /// How often [`Self::run`] reads the pin if it can't wait (see [`GpioPin::can_wait`]). The
+    /// default is [`DEFAULT_POLL_INTERVAL`].
+    pub fn set_poll_interval(&mut self, pin_index: usize, poll_interval: Duration) {
+        self.gpio_pins.set_poll_interval(pin_index, poll_interval);
+    }
+
+    /// Reads the level of every slow pin (see [`GpioPin::is_slow`]), which reads use.
+    /// [`Self::run`] also updates the samples when it notices a change.
+    pub async fn sample_pins(&mut self) {
+        self.gpio_pins.sample().await;
+    }
+
+    /// Asserts `INT` when an input changes.
+    /// This future will never complete.
+    /// The future is safe to cancel.
+    pub async fn run(&mut self) {
+        loop {
+            // Only wait for inputs in ports that don't already interrupt
+            let (level, index) = self
+                .gpio_pins
+                .wait_for_change(&self.known_levels, |i| {
+                    self.io_directions[i] == IoDirection::Input
+                        && !self.int_flags[AB::from_index(i).set_index()]
+                })
+                .await;
+            info!(
+                "pca9555 interrupt cuz pin {} changed to {}",
+                index,
+                Debug2Format(&level)
+            );
+            self.int_flags[AB::from_index(index).set_index()] = true;
+            self.update_interrupt();
+        }
+    }
+}
+
+/// The PCA9555 doesn't support general calls, so they are ignored
+impl<P: GpioPin, I: InterruptPin> EmulatedChip for Pca9555<P, I> {
+    fn process_write_transaction(&mut self, bytes: &[u8]) {
+        Self::process_write_transaction(self, bytes);
+    }
+
+    fn prepare_read_buffer(&mut self, buffer: &mut [u8]) {
+        Self::prepare_read_buffer(self, buffer);
+    }
+
+    fn confirm_bytes_read(&mut self, bytes_read: usize) {
+        Self::confirm_bytes_read(self, bytes_read);
+    }
+
+    async fn run(&mut self) {
+        Self::run(self).await;
+    }
+}
@@ -0,0 +1,182 @@
+use core::{array, iter::zip};
+
+use embassy_time::Duration;
+use mcp23017_common::InterruptMode;
+
+use crate::*;
+
+/// The number of pins of the PCF8574
+pub const N_PCF8574_PINS: usize = 8;
+/// The address of a PCF8574 with `A0`, `A1`, and `A2` low. This is the same as the MCP23017's
+/// [`BASE_ADDRESS`], so [`AddressFilter`] works with a `u8` or address pins.
+pub const PCF8574_BASE_ADDRESS: u8 = 0x20;
+/// The address of a PCF8574A with `A0`, `A1`, and `A2` low. To use it with [`AddressFilter`],
+/// implement [`AddressPins::address`].
+pub const PCF8574A_BASE_ADDRESS: u8 = 0x38;
+
+/// Emulates a PCF8574 or PCF8574A, with the same pins as [`Mcp23017`], so the same hardware can
+/// stand in for either expander.
+///
+/// The PCF8574 has no registers. Every byte written sets the 8 pins, and every byte read returns
+/// their levels. The pins are quasi-bidirectional: a `1` weakly pulls the pin up, so it can be
+/// used as an input, and a `0` drives it low. The emulator configures a `1` as an input with the
+/// pull-up enabled, and a `0` as an output.
+///
+/// `INT` is active low and open drain. It is asserted when an input changes, and released when
+/// the port is read or written.
+pub struct Pcf8574<P, I> {
+    gpio_pins: InputPins<P, N_PCF8574_PINS>,
+    interrupt_pin: I,
+    /// The last byte written
+    latches: [PinState; N_PCF8574_PINS],
+    /// The levels when the port was last read or written, which inputs are compared with to
+    /// interrupt
+    known_levels: [PinState; N_PCF8574_PINS],
+    interrupt: bool,
+    /// What a read returns, taken when a read is prepared so that clearing the interrupt uses the
+    /// same levels that were sent
+    snapshot: [PinState; N_PCF8574_PINS],
+}
+
+impl<P: GpioPin, I: InterruptPin> Pcf8574<P, I> {
+    pub fn new(gpio_pins: [P; N_PCF8574_PINS], interrupt_pin: I) -> Self {
+        let mut s = Self {
+            gpio_pins: InputPins::new(gpio_pins),
+            interrupt_pin,
+            latches: [PinState::High; _],
+            known_levels: [PinState::High; _],
+            interrupt: false,
+            snapshot: [PinState::High; _],
+        };
+        s.reset();
+        s
+    }
+
+    /// Sets every pin high, like at power on
+    pub fn reset(&mut self) {
+        self.latches = [PinState::High; _];
+        self.update_pins();
+        self.known_levels = array::from_fn(|i| self.pin_level(i));
+        self.interrupt = false;
+        self.update_interrupt();
+    }
+
+    fn update_pins(&mut self) {
+        for (pin, &latch) in zip(&mut self.gpio_pins.pins, &self.latches) {
+            match latch {
+                PinState::High => pin.configure(IoDirection::Input, true, PinState::High),
+                PinState::Low => pin.configure(IoDirection::Output, false, PinState::Low),
+            }
+        }
+    }
+
+    fn update_interrupt(&mut self) {
+        self.interrupt_pin.configure(
+            InterruptMode::OpenDrain,
+            if self.interrupt {
+                PinState::Low
+            } else {
+                PinState::High
+            },
+        );
+    }
+
+    /// The level that a read returns for the pin
+    fn pin_level(&self, pin_index: usize) -> PinState {
+        match self.latches[pin_index] {
+            PinState::Low => PinState::Low,
+            PinState::High => self.gpio_pins.level(pin_index),
+        }
+    }
+
+    /// Every byte sets the pins, so only the last one stays
+    pub fn process_write_transaction(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.latches = array::from_fn(|i| PinState::from(byte & (1 << i) != 0));
+            self.update_pins();
+            trace!("pcf8574 port = {:#x}", byte);
+        }
+        if !bytes.is_empty() {
+            self.clear_interrupt(array::from_fn(|i| self.pin_level(i)));
+        }
+    }
+
+    /// Fills `buffer` with the levels of the pins. The real chip samples the pins for every byte,
+    /// but the bytes have to be prepared before the controller reads them, so every byte is the
+    /// same.
+    pub fn prepare_read_buffer(&mut self, buffer: &mut [u8]) {
+        self.snapshot = array::from_fn(|i| self.pin_level(i));
+        buffer.fill(to_byte(self.snapshot.map(bool::from)));
+    }
+
+    /// After transmitting bytes to the controller, call this function with the actual number of
+    /// bytes read by the controller.
+    pub fn confirm_bytes_read(&mut self, bytes_read: usize) {
+        if bytes_read > 0 {
+            self.clear_interrupt(self.snapshot);
+        }
+    }
+
+    fn clear_interrupt(&mut self, levels: [PinState; N_PCF8574_PINS]) {
+        self.known_levels = levels;
+        if self.interrupt {
+            info!("cleared pcf8574 interrupt");
+        }
+        self.interrupt = false;
+        self.update_interrupt();
+    }
+
+    /// How often [`Self::run`] reads the pin if it can't wait (see [`GpioPin::can_wait`]). The
+    /// default is [`DEFAULT_POLL_INTERVAL`].
+    pub fn set_poll_interval(&mut self, pin_index: usize, poll_interval: Duration) {
+        self.gpio_pins.set_poll_interval(pin_index, poll_interval);
+    }
+
+    /// Reads the level of every slow pin (see [`GpioPin::is_slow`]), which reads use.
+    /// [`Self::run`] also updates the samples when it notices a change.
+    pub async fn sample_pins(&mut self) {
+        self.gpio_pins.sample().await;
+    }
+
+    /// Asserts `INT` when an input changes.
+    /// This future will never complete.
+    /// The future is safe to cancel.
+    pub async fn run(&mut self) {
+        loop {
+            // Outputs can't change, and the interrupt is only released by a read or write, so
+            // there is nothing to wait for
+            let (level, index) = self
+                .gpio_pins
+                .wait_for_change(&self.known_levels, |i| {
+                    self.latches[i] == PinState::High && !self.interrupt
+                })
+                .await;
+            info!(
+                "pcf8574 interrupt cuz pin {} changed to {}",
+                index,
+                Debug2Format(&level)
+            );
+            self.interrupt = true;
+            self.update_interrupt();
+        }
+    }
+}
+
+/// The PCF8574 doesn't support general calls, so they are ignored
+impl<P: GpioPin, I: InterruptPin> EmulatedChip for Pcf8574<P, I> {
+    fn process_write_transaction(&mut self, bytes: &[u8]) {
+        Self::process_write_transaction(self, bytes);
+    }
+
+    fn prepare_read_buffer(&mut self, buffer: &mut [u8]) {
+        Self::prepare_read_buffer(self, buffer);
+    }
+
+    fn confirm_bytes_read(&mut self, bytes_read: usize) {
+        Self::confirm_bytes_read(self, bytes_read);
+    }
+
+    async fn run(&mut self) {
+        Self::run(self).await;
+    }
+}
@@ -0,0 +1,155 @@
+use embedded_hal_async::digital::Wait;
+
+use crate::*;
+
+/// A transaction that the controller did, recorded with [`Recorder`] and replayed with
+/// [`replay`]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEntry<'a> {
+    /// The controller wrote these bytes, starting with the register address
+    Write(&'a [u8]),
+    /// The controller read these bytes
+    Read(&'a [u8]),
+}
+
+/// Wraps an [`I2cTargetTransport`] and calls `record` for every transaction, for example to log
+/// the transactions of a real controller and replay them later with [`replay`].
+/// Wrap an [`AddressFilter`] to only record the transactions of one chip.
+pub struct Recorder<T, F> {
+    transport: T,
+    record: F,
+    /// The bytes of the last response, to record the ones that were read
+    response: [u8; I2C_BUFFER_LEN],
+}
+
+impl<T: I2cTargetTransport, F: FnMut(TraceEntry)> Recorder<T, F> {
+    pub fn new(transport: T, record: F) -> Self {
+        Self {
+            transport,
+            record,
+            response: [0; I2C_BUFFER_LEN],
+        }
+    }
+
+    pub fn into_inner(self) -> (T, F) {
+        (self.transport, self.record)
+    }
+}
+
+impl<T: I2cTargetTransport, F: FnMut(TraceEntry)> I2cTargetTransport for Recorder<T, F> {
+    type Error = T::Error;
+
+    async fn next_event(&mut self) -> Result<TransportEvent<'_>, Self::Error> {
+        let event = self.transport.next_event().await?;
+        match event {
+            TransportEvent::Write { address: _, bytes } => (self.record)(TraceEntry::Write(bytes)),
+            TransportEvent::ReadCompleted(bytes_read) => {
+                (self.record)(TraceEntry::Read(
+                    &self.response[..bytes_read.min(I2C_BUFFER_LEN)],
+                ));
+            }
+            TransportEvent::ReadRequested { address: _, len: _ } => {}
+        }
+        Ok(event)
+    }
+
+    async fn respond(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        let len = bytes.len().min(I2C_BUFFER_LEN);
+        self.response[..len].copy_from_slice(&bytes[..len]);
+        self.transport.respond(bytes).await
+    }
+}
+
+/// A read in [`replay`] that didn't match the recording
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// The index of the [`TraceEntry`]
+    pub entry: usize,
+    /// The index of the byte in the read
+    pub byte: usize,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// Feeds recorded transactions into `mcp23017`, and checks that every read returns the recorded
+/// bytes. Use this to catch regressions in the register semantics with traces captured from real
+/// controllers (or real chips).
+/// Reads of pins depend on the pins, so use pins that give the same levels as when recording.
+/// Stops at the first mismatch.
+pub fn replay<'a, P: GpioPin, I: InterruptPin, R: Wait>(
+    mcp23017: &mut Mcp23017<P, I, R>,
+    trace: impl IntoIterator<Item = TraceEntry<'a>>,
+) -> Result<(), ReplayMismatch> {
+    let mut buffer = [0; I2C_BUFFER_LEN];
+    for (entry, trace_entry) in trace.into_iter().enumerate() {
+        match trace_entry {
+            TraceEntry::Write(bytes) => mcp23017.process_write_transaction(bytes),
+            TraceEntry::Read(expected) => {
+                for (chunk_index, expected) in expected.chunks(I2C_BUFFER_LEN).enumerate() {
+                    let actual = &mut buffer[..expected.len()];
+                    mcp23017.prepare_read_buffer(actual);
+                    mcp23017.confirm_bytes_read(expected.len());
+                    if let Some((byte, (&expected, &actual))) = expected
+                        .iter()
+                        .zip(actual.iter())
+                        .enumerate()
+                        .find(|(_, (expected, actual))| expected != actual)
+                    {
+                        return Err(ReplayMismatch {
+                            entry,
+                            byte: chunk_index * I2C_BUFFER_LEN + byte,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::array;
+
+    use mcp23017_common::{AB, Register, RegisterType};
+
+    use super::*;
+
+    #[test]
+    fn replay_checks_reads() {
+        let mut mcp23017 = Mcp23017::new_without_reset_pin(
+            array::from_fn(|_| NoopGpioPin::new(PinState::Low)),
+            [NoopInterruptPin, NoopInterruptPin],
+        );
+        let iodir_a = Register {
+            _type: RegisterType::IODIR,
+            ab: AB::A,
+        }
+        .address(false);
+        let trace = [
+            TraceEntry::Write(&[iodir_a, 0x0F]),
+            TraceEntry::Write(&[iodir_a]),
+            // `IODIRA`, and then `IODIRB`, which is still all inputs
+            TraceEntry::Read(&[0x0F, 0xFF]),
+        ];
+        assert_eq!(replay(&mut mcp23017, trace), Ok(()));
+
+        let trace = [
+            TraceEntry::Write(&[iodir_a]),
+            TraceEntry::Read(&[0x0F, 0x00]),
+        ];
+        assert_eq!(
+            replay(&mut mcp23017, trace),
+            Err(ReplayMismatch {
+                entry: 1,
+                byte: 1,
+                expected: 0x00,
+                actual: 0xFF,
+            })
+        );
+    }
+}
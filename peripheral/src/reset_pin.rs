@@ -1,9 +1,17 @@
+use core::{convert::Infallible, future::pending};
+
+use embassy_sync::{blocking_mutex::raw::RawMutex, signal::Signal};
 use embassy_time::{Duration, Instant};
+use embedded_hal::digital::ErrorType;
 use embedded_hal_async::digital::Wait;
 
+/// The minimum time that RESET has to be low, from the data sheet
+pub const RESET_MINIMUM_LOW_DURATION: Duration = Duration::from_micros(1);
+
 pub struct ResetPin<T> {
     pin: T,
     low_since: Option<Instant>,
+    minimum_low_duration: Option<Duration>,
 }
 
 impl<T> ResetPin<T> {
@@ -17,27 +25,35 @@ impl<T: Wait> ResetPin<T> {
         Self {
             pin,
             low_since: None,
+            minimum_low_duration: Some(RESET_MINIMUM_LOW_DURATION),
         }
     }
+
+    /// How long the pin has to be low to reset, which is [`RESET_MINIMUM_LOW_DURATION`] by
+    /// default. Change it to emulate marginal reset timing, or for a slow RC reset circuit.
+    /// With `None`, any low pulse resets.
+    pub fn set_minimum_low_duration(&mut self, minimum_low_duration: Option<Duration>) {
+        self.minimum_low_duration = minimum_low_duration;
+    }
 }
 
 impl<T: Wait> ResetPin<T> {
     pub async fn wait_until_reset(&mut self) {
         loop {
             if let Some(low_since) = self.low_since {
-                // From the data sheet
-                let minimum_duration = Duration::from_micros(1);
                 self.pin.wait_for_high().await.unwrap();
                 self.low_since = None;
+                let Some(minimum_duration) = self.minimum_low_duration else {
+                    break;
+                };
                 let low_duration = low_since.elapsed();
                 if low_duration >= minimum_duration {
                     break;
                 } else {
-                    #[cfg(feature = "defmt")]
-                    defmt::warn!(
+                    warn!(
                         "reset pin went low for {} us, which is not long enough to trigger a reset ({} us)",
                         low_duration.as_micros(),
-                        minimum_duration
+                        minimum_duration.as_micros()
                     );
                 }
             } else {
@@ -47,3 +63,61 @@ impl<T: Wait> ResetPin<T> {
         }
     }
 }
+
+/// Waits for a [`ResetPin`] on its own, and signals the task that runs
+/// [`crate::Mcp23017::run_inputs_with_reset`], so that the two futures don't both borrow the
+/// emulator and can run in different tasks.
+pub struct ResetTask<'a, M: RawMutex, T> {
+    reset: ResetPin<T>,
+    signal: &'a Signal<M, ()>,
+}
+
+impl<'a, M: RawMutex, T: Wait> ResetTask<'a, M, T> {
+    pub fn new(reset: ResetPin<T>, signal: &'a Signal<M, ()>) -> Self {
+        Self { reset, signal }
+    }
+
+    pub fn into_reset_pin(self) -> ResetPin<T> {
+        self.reset
+    }
+
+    /// Signals every time that the reset pin resets the chip.
+    /// This future will never complete.
+    /// The future is safe to cancel.
+    pub async fn run(&mut self) {
+        loop {
+            self.reset.wait_until_reset().await;
+            self.signal.signal(());
+        }
+    }
+}
+
+/// A reset pin that is never pulled low, for when RESET isn't wired.
+/// See [`crate::Mcp23017::new_without_reset_pin`].
+pub struct NoResetPin;
+
+impl ErrorType for NoResetPin {
+    type Error = Infallible;
+}
+
+impl Wait for NoResetPin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        pending().await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        pending().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        pending().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        pending().await
+    }
+}
@@ -0,0 +1,165 @@
+use crate::*;
+use embassy_rp::gpio::{Flex, Level, Pull};
+use embassy_rp::i2c::Instance;
+use embassy_rp::i2c_slave::{Command, Error, I2cSlave, ReadStatus};
+use embedded_hal_async::digital::Wait;
+
+/// Every RP2040 GPIO can wait for a level, so every emulated pin is a `Flex`
+pub struct RpGpioPin<'d> {
+    pin: Flex<'d>,
+}
+
+impl<'d> RpGpioPin<'d> {
+    pub fn new(pin: Flex<'d>) -> Self {
+        Self { pin }
+    }
+}
+
+impl GpioPin for RpGpioPin<'_> {
+    fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
+        match io_direction {
+            IoDirection::Output => {
+                self.pin.set_level(Level::from(bool::from(level)));
+                self.pin.set_as_output();
+            }
+            IoDirection::Input => {
+                self.pin.set_pull(if pull_up_enabled {
+                    Pull::Up
+                } else {
+                    Pull::None
+                });
+                self.pin.set_as_input();
+            }
+        }
+    }
+
+    fn level(&self) -> PinState {
+        bool::from(self.pin.get_level()).into()
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        match level {
+            PinState::High => self.pin.wait_for_high().await,
+            PinState::Low => self.pin.wait_for_low().await,
+        }
+    }
+}
+
+impl FlexPin for Flex<'_> {
+    fn set_as_input(&mut self) {
+        self.set_pull(Pull::None);
+        Flex::set_as_input(self);
+    }
+
+    fn set_as_output(&mut self, level: PinState) {
+        self.set_level(Level::from(bool::from(level)));
+        Flex::set_as_output(self);
+    }
+}
+
+/// The RP2040 doesn't have open-drain outputs, so this uses [`OpenDrainInterruptPin`]
+pub type RpInterruptPin<'d> = OpenDrainInterruptPin<Flex<'d>>;
+
+/// Events that were already received from the hardware
+#[derive(Clone, Copy)]
+enum Pending {
+    ReadRequested,
+    ReadCompleted { bytes_read: usize, more: bool },
+}
+
+/// [`I2cTargetTransport`] for an `I2cSlave`. `address` must be the address that it is
+/// configured with, because the hardware doesn't report which address was used.
+///
+/// While the read buffer is prepared, the hardware stretches the clock, so the controller waits for
+/// it instead of reading stale bytes.
+pub struct RpI2cTransport<'a, 'd, T: Instance> {
+    i2c: &'a mut I2cSlave<'d, T>,
+    address: u8,
+    buffer: [u8; I2C_BUFFER_LEN],
+    pending: Option<Pending>,
+}
+
+impl<'a, 'd, T: Instance> RpI2cTransport<'a, 'd, T> {
+    pub fn new(i2c: &'a mut I2cSlave<'d, T>, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            buffer: [0; I2C_BUFFER_LEN],
+            pending: None,
+        }
+    }
+}
+
+impl<T: Instance> I2cTargetTransport for RpI2cTransport<'_, '_, T> {
+    type Error = Error;
+
+    async fn next_event(&mut self) -> Result<TransportEvent<'_>, Self::Error> {
+        match self.pending.take() {
+            Some(Pending::ReadRequested) => {
+                return Ok(TransportEvent::ReadRequested {
+                    address: self.address,
+                    len: I2C_BUFFER_LEN,
+                });
+            }
+            Some(Pending::ReadCompleted { bytes_read, more }) => {
+                if more {
+                    self.pending = Some(Pending::ReadRequested);
+                }
+                return Ok(TransportEvent::ReadCompleted(bytes_read));
+            }
+            None => {}
+        }
+        Ok(match self.i2c.listen(&mut self.buffer).await? {
+            Command::Write(len) => TransportEvent::Write {
+                address: self.address,
+                bytes: &self.buffer[..len],
+            },
+            Command::WriteRead(len) => {
+                self.pending = Some(Pending::ReadRequested);
+                TransportEvent::Write {
+                    address: self.address,
+                    bytes: &self.buffer[..len],
+                }
+            }
+            Command::Read => TransportEvent::ReadRequested {
+                address: self.address,
+                len: I2C_BUFFER_LEN,
+            },
+            Command::GeneralCall(len) => TransportEvent::Write {
+                address: GENERAL_CALL_ADDRESS,
+                bytes: &self.buffer[..len],
+            },
+        })
+    }
+
+    async fn respond(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.pending = Some(match self.i2c.respond_to_read(bytes).await? {
+            ReadStatus::Done => Pending::ReadCompleted {
+                bytes_read: bytes.len(),
+                more: false,
+            },
+            ReadStatus::NeedMoreBytes => Pending::ReadCompleted {
+                bytes_read: bytes.len(),
+                more: true,
+            },
+            ReadStatus::LeftoverBytes(leftover) => Pending::ReadCompleted {
+                bytes_read: bytes.len() - leftover as usize,
+                more: false,
+            },
+        });
+        Ok(())
+    }
+}
+
+/// Emulates the MCP23017 on `i2c` with [`RpI2cTransport`]. See [`run_with_transport`].
+pub async fn run_i2c<P: GpioPin, I: InterruptPin, R: Wait, T: Instance>(
+    mcp23017: &mut Mcp23017<P, I, R>,
+    i2c: &mut I2cSlave<'_, T>,
+    address: u8,
+) -> ! {
+    run_with_transport(mcp23017, &mut RpI2cTransport::new(i2c, address)).await
+}
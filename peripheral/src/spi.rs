@@ -0,0 +1,108 @@
+use embedded_hal_async::digital::Wait;
+
+use crate::*;
+
+/// The opcode of an MCP23S17 is `0100 A2 A1 A0 R/W`
+const OPCODE_MASK: u8 = 0b1111_0000;
+const OPCODE_BASE: u8 = 0b0100_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpiState {
+    /// Waiting for the opcode, the first byte after CS goes low
+    Opcode,
+    /// Waiting for the register address
+    Address { read: bool },
+    /// Every byte after the register address reads or writes a register
+    Data { read: bool },
+    /// The opcode was for a different device, so the rest of the transaction is ignored
+    Ignored,
+}
+
+/// The SPI target (slave) front-end for emulating an MCP23S17, the SPI variant of the MCP23017.
+/// It drives the same registers as the I2C front-end.
+///
+/// SPI has no clock stretching, so the byte to send has to be ready before the controller clocks
+/// the next byte. Call [`Self::select`] when CS goes low, [`Self::exchange`] for every byte
+/// received (usually from the SPI peripheral's interrupt), and load the returned byte for the
+/// next transfer. Call [`Self::deselect`] when CS goes high.
+///
+/// Like the real chip, the address pins are only compared with the opcode if `IOCON.HAEN` is set.
+/// Otherwise the device responds to every opcode, as if the address pins were all low.
+#[derive(Debug)]
+pub struct Mcp23S17Spi {
+    /// `A2 A1 A0`
+    hardware_address: u8,
+    state: SpiState,
+}
+
+impl Mcp23S17Spi {
+    /// `hardware_address` is the value of the `A2 A1 A0` pins, `0` to `7`
+    pub const fn new(hardware_address: u8) -> Self {
+        Self {
+            hardware_address: hardware_address & 0b111,
+            state: SpiState::Opcode,
+        }
+    }
+
+    pub fn select(&mut self) {
+        self.state = SpiState::Opcode;
+    }
+
+    pub fn deselect(&mut self) {
+        self.state = SpiState::Opcode;
+    }
+
+    /// Processes a byte received from the controller, and returns the byte to send during the next
+    /// transfer.
+    /// For reads, the register returned here is only counted as read (clearing interrupts and
+    /// advancing the address) once the controller clocks the next byte.
+    pub fn exchange<P: GpioPin, I: InterruptPin, R: Wait>(
+        &mut self,
+        mcp23017: &mut Mcp23017<P, I, R>,
+        byte: u8,
+    ) -> u8 {
+        match self.state {
+            SpiState::Opcode => {
+                let hardware_address = (byte >> 1) & 0b111;
+                let addressed = byte & OPCODE_MASK == OPCODE_BASE
+                    && (!mcp23017.hardware_address_enabled()
+                        || hardware_address == self.hardware_address);
+                self.state = if addressed {
+                    SpiState::Address {
+                        read: byte & 1 != 0,
+                    }
+                } else {
+                    trace!("ignoring SPI opcode {:#x}", byte);
+                    SpiState::Ignored
+                };
+                0
+            }
+            SpiState::Address { read } => {
+                mcp23017.select_address(byte);
+                self.state = SpiState::Data { read };
+                if read {
+                    Self::next_read_byte(mcp23017)
+                } else {
+                    0
+                }
+            }
+            SpiState::Data { read: true } => {
+                mcp23017.confirm_bytes_read(1);
+                Self::next_read_byte(mcp23017)
+            }
+            SpiState::Data { read: false } => {
+                mcp23017.write_selected_register(byte);
+                0
+            }
+            SpiState::Ignored => 0,
+        }
+    }
+
+    fn next_read_byte<P: GpioPin, I: InterruptPin, R: Wait>(
+        mcp23017: &mut Mcp23017<P, I, R>,
+    ) -> u8 {
+        let mut buffer = [0];
+        mcp23017.prepare_read_buffer(&mut buffer);
+        buffer[0]
+    }
+}
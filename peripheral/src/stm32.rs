@@ -3,9 +3,11 @@ use embassy_stm32::{
     Peri,
     exti::{Channel, ExtiInput, InterruptHandler},
     gpio::{ExtiPin, Flex, Level, Pull, Speed},
+    i2c::{Address, Error, I2c, MultiMaster, SendStatus, SlaveCommandKind},
     interrupt::typelevel::Binding,
     mode::Async,
 };
+use embedded_hal_async::digital::Wait;
 use mcp23017_common::*;
 
 fn get_pull(pull_up_enabled: bool) -> Pull {
@@ -59,18 +61,18 @@ impl<'a> Stm32GpioPin<'a> {
 impl GpioPin for Stm32GpioPin<'_> {
     fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
         match &mut self._type {
-            Stm32GpioPinType::ExtiInput { pin: _, pull } => {
-                if io_direction == IoDirection::Input {
+            Stm32GpioPinType::ExtiInput { pin: _, pull } => match io_direction {
+                IoDirection::Input => {
                     if *pull != get_pull(pull_up_enabled) {
-                        #[cfg(feature = "defmt")]
-                        defmt::warn!(
+                        warn!(
                             "Cannot set pull because ExtiInput's pull cannot be dynamically changed."
                         );
                     }
                 }
-                #[cfg(feature = "defmt")]
-                defmt::warn!("Tried to use input-only pin as output")
-            }
+                IoDirection::Output => {
+                    warn!("Tried to use input-only pin as output")
+                }
+            },
             Stm32GpioPinType::Flex { pin, speed } => match io_direction {
                 IoDirection::Output => {
                     pin.set_level(Level::from(bool::from(level)));
@@ -107,6 +109,16 @@ impl GpioPin for Stm32GpioPin<'_> {
             Stm32GpioPinType::Flex { pin: _, speed: _ } => unreachable!(),
         }
     }
+
+    fn capabilities(&self) -> PinCapabilities {
+        match &self._type {
+            Stm32GpioPinType::ExtiInput { pin: _, pull } => PinCapabilities {
+                fixed_pull_up: Some(*pull == Pull::Up),
+                ..PinCapabilities::INPUT
+            },
+            Stm32GpioPinType::Flex { pin: _, speed: _ } => PinCapabilities::ALL,
+        }
+    }
 }
 
 pub struct Stm32InterruptPin<'a> {
@@ -123,8 +135,7 @@ impl<'a> Stm32InterruptPin<'a> {
 impl InterruptPin for Stm32InterruptPin<'_> {
     fn configure(&mut self, mode: InterruptMode, level: PinState) {
         let level = bool::from(level).into();
-        #[cfg(feature = "defmt")]
-        defmt::info!("setting interrupt pin level to {}", level);
+        info!("setting interrupt pin level to {}", Debug2Format(&level));
         self.pin.set_level(level);
         match mode {
             InterruptMode::OpenDrain => {
@@ -136,3 +147,68 @@ impl InterruptPin for Stm32InterruptPin<'_> {
         }
     }
 }
+
+/// [`I2cTargetTransport`] for an I2C peripheral in slave mode with the address of the chip, for
+/// example `I2c::new(...).into_slave_multimaster(SlaveAddrConfig::basic(0x20))`.
+pub struct Stm32I2cTransport<'a, 'd> {
+    i2c: &'a mut I2c<'d, Async, MultiMaster>,
+    buffer: [u8; I2C_BUFFER_LEN],
+    /// The number of bytes read by the last read, which wasn't returned as an event yet
+    read_completed: Option<usize>,
+}
+
+impl<'a, 'd> Stm32I2cTransport<'a, 'd> {
+    pub fn new(i2c: &'a mut I2c<'d, Async, MultiMaster>) -> Self {
+        Self {
+            i2c,
+            buffer: [0; I2C_BUFFER_LEN],
+            read_completed: None,
+        }
+    }
+}
+
+impl I2cTargetTransport for Stm32I2cTransport<'_, '_> {
+    type Error = Error;
+
+    async fn next_event(&mut self) -> Result<TransportEvent<'_>, Self::Error> {
+        if let Some(bytes_read) = self.read_completed.take() {
+            return Ok(TransportEvent::ReadCompleted(bytes_read));
+        }
+        loop {
+            let command = self.i2c.listen().await?;
+            let Address::SevenBit(address) = command.address else {
+                // The MCP23017 only has a 7-bit address
+                continue;
+            };
+            return match command.kind {
+                SlaveCommandKind::Write => {
+                    let len = self.i2c.respond_to_write(&mut self.buffer).await?;
+                    Ok(TransportEvent::Write {
+                        address,
+                        bytes: &self.buffer[..len],
+                    })
+                }
+                SlaveCommandKind::Read => Ok(TransportEvent::ReadRequested {
+                    address,
+                    len: I2C_BUFFER_LEN,
+                }),
+            };
+        }
+    }
+
+    async fn respond(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.read_completed = Some(match self.i2c.respond_to_read(bytes).await? {
+            SendStatus::Done => bytes.len(),
+            SendStatus::LeftoverBytes(leftover) => bytes.len() - leftover,
+        });
+        Ok(())
+    }
+}
+
+/// Emulates the MCP23017 on `i2c` with [`Stm32I2cTransport`]. See [`run_with_transport`].
+pub async fn run_i2c<P: GpioPin, I: InterruptPin, R: Wait>(
+    mcp23017: &mut Mcp23017<P, I, R>,
+    i2c: &mut I2c<'_, Async, MultiMaster>,
+) -> ! {
+    run_with_transport(mcp23017, &mut Stm32I2cTransport::new(i2c)).await
+}
@@ -0,0 +1,114 @@
+use core::fmt::Debug;
+
+use embassy_futures::select::{Either, select};
+use mcp23017_common::RegisterType;
+use strum::EnumCount;
+
+use crate::*;
+
+/// Enough for the register address and a write to every register
+pub const I2C_BUFFER_LEN: usize = 1 + RegisterType::COUNT * 2;
+
+/// The I2C general call address. Transports that receive general calls report them as
+/// [`TransportEvent::Write`]s to this address.
+pub const GENERAL_CALL_ADDRESS: u8 = 0x00;
+/// The general call command that resets every device that supports it
+pub const GENERAL_CALL_RESET: u8 = 0x06;
+
+/// Whether a write is the general call reset command
+pub(crate) fn is_general_call_reset(address: u8, bytes: &[u8]) -> bool {
+    address == GENERAL_CALL_ADDRESS && bytes == [GENERAL_CALL_RESET]
+}
+
+/// Something that happened on the I2C bus. See [`I2cTargetTransport`].
+/// `address` is the 7-bit address that the controller used.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransportEvent<'a> {
+    /// The controller wrote these bytes, starting with the register address
+    Write { address: u8, bytes: &'a [u8] },
+    /// The controller started reading. Give it up to `len` bytes with
+    /// [`I2cTargetTransport::respond`].
+    ReadRequested { address: u8, len: usize },
+    /// The controller read this many of the bytes that were given to it with
+    /// [`I2cTargetTransport::respond`]
+    ReadCompleted(usize),
+}
+
+/// The I2C target (slave) front-end of the emulator. Implement this for an I2C peripheral and
+/// pass it to [`run_with_transport`], which does the rest.
+///
+/// A write followed by a repeated start and a read should be a [`TransportEvent::Write`] and then a
+/// [`TransportEvent::ReadRequested`], so that the read starts at the register that was just
+/// selected. If the controller keeps reading after the bytes given to
+/// [`I2cTargetTransport::respond`], return [`TransportEvent::ReadCompleted`] and then another
+/// [`TransportEvent::ReadRequested`].
+/// Addresses of other devices should be ignored. General calls should be reported with
+/// [`GENERAL_CALL_ADDRESS`] if the I2C peripheral receives them, so that the controller can reset
+/// the chip with [`GENERAL_CALL_RESET`].
+pub trait I2cTargetTransport {
+    type Error: Debug;
+    /// Waits for the next event. This must be safe to cancel.
+    fn next_event(&mut self) -> impl Future<Output = Result<TransportEvent<'_>, Self::Error>>;
+    /// Sends `bytes` for the read that was requested. The next event should be
+    /// [`TransportEvent::ReadCompleted`], unless there was an error.
+    fn respond(&mut self, bytes: &[u8]) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// A chip that [`run_with_transport`] can emulate: [`Mcp23017`], [`Pcf8574`], or [`Pca9555`]
+pub trait EmulatedChip {
+    /// Handles a write of the controller, which starts with the register address or command
+    fn process_write_transaction(&mut self, bytes: &[u8]);
+    /// Fills `buffer` with what the controller reads next
+    fn prepare_read_buffer(&mut self, buffer: &mut [u8]);
+    /// Called with the number of bytes that the controller actually read
+    fn confirm_bytes_read(&mut self, bytes_read: usize);
+    /// Handles a general call. By default they are ignored, because most chips don't support
+    /// them.
+    fn process_general_call(&mut self, _bytes: &[u8]) {}
+    /// Watches the pins. This future never completes, and it must be safe to cancel.
+    fn run(&mut self) -> impl Future<Output = ()>;
+}
+
+/// Emulates `chip` on `transport`.
+/// This also runs [`EmulatedChip::run`], so it's the only future that needs to be polled.
+/// This future never completes, and it is safe to cancel.
+///
+/// Writes are passed to [`EmulatedChip::process_write_transaction`], and general calls to
+/// [`EmulatedChip::process_general_call`]. For reads, a buffer is prepared with
+/// [`EmulatedChip::prepare_read_buffer`], and [`EmulatedChip::confirm_bytes_read`] is called
+/// with the number of bytes that the controller actually read.
+/// Errors are logged and otherwise ignored, because the controller retries.
+/// Every address that `transport` acknowledges is handled, so use [`AddressFilter`] if it
+/// acknowledges more than the address of this chip.
+pub async fn run_with_transport<C: EmulatedChip, T: I2cTargetTransport>(
+    chip: &mut C,
+    transport: &mut T,
+) -> ! {
+    let mut read_buffer = [0; I2C_BUFFER_LEN];
+    loop {
+        let event = match select(chip.run(), transport.next_event()).await {
+            Either::First(()) => unreachable!(),
+            Either::Second(event) => event,
+        };
+        match event {
+            Ok(TransportEvent::Write { address, bytes }) => {
+                if address == GENERAL_CALL_ADDRESS {
+                    chip.process_general_call(bytes);
+                } else {
+                    chip.process_write_transaction(bytes);
+                }
+            }
+            Ok(TransportEvent::ReadRequested { address: _, len }) => {
+                let buffer = &mut read_buffer[..len.min(I2C_BUFFER_LEN)];
+                chip.prepare_read_buffer(buffer);
+                if let Err(_error) = transport.respond(buffer).await {
+                    warn!("I2C read error: {}", Debug2Format(&_error));
+                }
+            }
+            Ok(TransportEvent::ReadCompleted(bytes_read)) => chip.confirm_bytes_read(bytes_read),
+            Err(_error) => {
+                warn!("I2C error: {}", Debug2Format(&_error));
+            }
+        }
+    }
+}
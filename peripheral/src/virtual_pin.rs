@@ -0,0 +1,141 @@
+use embassy_sync::{
+    blocking_mutex::raw::RawMutex,
+    watch::{Receiver, Watch},
+};
+
+use crate::*;
+
+/// The number of tasks that can wait on a [`VirtualPinCell`]. One is used by the [`VirtualPin`],
+/// and the rest can be taken with [`VirtualPinCell::receiver`].
+pub const VIRTUAL_PIN_RECEIVERS: usize = 2;
+
+/// What the emulator configured a [`VirtualPin`] to, and what the simulation drives it to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualPinState {
+    pub io_direction: IoDirection,
+    pub pull_up_enabled: bool,
+    /// The output latch
+    pub latch: PinState,
+    /// The level that the simulation drives the pin to, or `None` if nothing drives it
+    pub driven: Option<PinState>,
+}
+
+impl VirtualPinState {
+    /// The state after the emulator is reset, with nothing driving the pin
+    pub const fn new() -> Self {
+        Self {
+            io_direction: IoDirection::Input,
+            pull_up_enabled: false,
+            latch: PinState::Low,
+            driven: None,
+        }
+    }
+
+    /// An output reads its latch, unless the simulation drives it, for example to simulate a
+    /// short. An input reads the driven level, or high with the pull-up enabled. A floating input
+    /// reads low.
+    pub fn level(&self) -> PinState {
+        match self.io_direction {
+            IoDirection::Output => self.driven.unwrap_or(self.latch),
+            IoDirection::Input => self.driven.unwrap_or(PinState::from(self.pull_up_enabled)),
+        }
+    }
+}
+
+impl Default for VirtualPinState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The shared side of a [`VirtualPin`]. Put it in a `static` (or anything that outlives the
+/// emulator), give a [`VirtualPin`] to the emulator, and use this from the simulation or test,
+/// for example to press a button with [`Self::drive`].
+pub struct VirtualPinCell<M: RawMutex> {
+    watch: Watch<M, VirtualPinState, VIRTUAL_PIN_RECEIVERS>,
+}
+
+impl<M: RawMutex> VirtualPinCell<M> {
+    pub const fn new() -> Self {
+        Self {
+            watch: Watch::new_with(VirtualPinState::new()),
+        }
+    }
+
+    /// Drives the pin to `level`, or stops driving it with `None`
+    pub fn drive(&self, level: Option<PinState>) {
+        self.watch.sender().send_if_modified(|state| {
+            let state = state.as_mut().unwrap();
+            let modified = state.driven != level;
+            state.driven = level;
+            modified
+        });
+    }
+
+    pub fn state(&self) -> VirtualPinState {
+        self.watch.try_get().unwrap()
+    }
+
+    /// See [`VirtualPinState::level`]
+    pub fn level(&self) -> PinState {
+        self.state().level()
+    }
+
+    /// Lets a task wait for changes, for example for an output to be set.
+    /// Returns `None` if all [`VIRTUAL_PIN_RECEIVERS`] are taken.
+    pub fn receiver(&self) -> Option<Receiver<'_, M, VirtualPinState, VIRTUAL_PIN_RECEIVERS>> {
+        self.watch.receiver()
+    }
+}
+
+impl<M: RawMutex> Default for VirtualPinCell<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A [`GpioPin`] that isn't a real pin, for simulations and tests.
+/// Its level is set and read through a [`VirtualPinCell`].
+pub struct VirtualPin<'a, M: RawMutex> {
+    cell: &'a VirtualPinCell<M>,
+    receiver: Receiver<'a, M, VirtualPinState, VIRTUAL_PIN_RECEIVERS>,
+}
+
+impl<'a, M: RawMutex> VirtualPin<'a, M> {
+    /// Returns `None` if all of the cell's [`VIRTUAL_PIN_RECEIVERS`] are taken
+    pub fn new(cell: &'a VirtualPinCell<M>) -> Option<Self> {
+        Some(Self {
+            cell,
+            receiver: cell.receiver()?,
+        })
+    }
+}
+
+impl<M: RawMutex> GpioPin for VirtualPin<'_, M> {
+    fn configure(&mut self, io_direction: IoDirection, pull_up_enabled: bool, level: PinState) {
+        self.cell.watch.sender().send_if_modified(|state| {
+            let state = state.as_mut().unwrap();
+            let new_state = VirtualPinState {
+                io_direction,
+                pull_up_enabled,
+                latch: level,
+                driven: state.driven,
+            };
+            let modified = *state != new_state;
+            *state = new_state;
+            modified
+        });
+    }
+
+    fn level(&self) -> PinState {
+        self.cell.level()
+    }
+
+    fn can_wait(&mut self) -> bool {
+        true
+    }
+
+    async fn wait_for_level(&mut self, level: PinState) {
+        self.receiver.get_and(|state| state.level() == level).await;
+    }
+}